@@ -43,6 +43,12 @@ pub struct AuthManager {
     sessions: RwLock<HashMap<String, String>>, // token -> username
 }
 
+impl Default for AuthManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AuthManager {
     pub fn new() -> Self {
         Self {
@@ -0,0 +1,40 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use serde_json::Value;
+use anyhow::{Result, anyhow};
+
+/// Sends a query to the running server's `/query` endpoint and returns the
+/// `data` (or `message`) of a successful response.
+pub fn send_query(host: &str, port: u16, token: Option<&str>, query: &Value) -> Result<Value> {
+    // A server listening on every interface is reached through loopback
+    let host = if host == "0.0.0.0" { "127.0.0.1" } else { host };
+    let mut stream = TcpStream::connect((host, port))
+        .map_err(|e| anyhow!("Could not reach the server at {}:{}: {}", host, port, e))?;
+    stream.set_read_timeout(Some(Duration::from_secs(3600)))?;
+
+    let body = serde_json::to_vec(query)?;
+    let mut request = format!(
+        "POST /query HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        host, port, body.len()
+    );
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(&body)?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n")
+        .ok_or(anyhow!("Malformed response from the server"))?;
+    let reply: Value = serde_json::from_slice(&response[split + 4..])
+        .map_err(|e| anyhow!("Unexpected response from the server: {}", e))?;
+
+    match reply.get("status").and_then(|s| s.as_str()) {
+        Some("success") => Ok(reply.get("data").or(reply.get("message")).cloned().unwrap_or(Value::Null)),
+        _ => Err(anyhow!("Server refused the request: {}",
+            reply.get("message").and_then(|m| m.as_str()).unwrap_or("unknown error"))),
+    }
+}
@@ -1,5 +1,7 @@
 use clap::{Parser, Subcommand, Args};
 
+pub mod client;
+
 #[derive(Parser)]
 #[command(name = "rdb")]
 #[command(about = "RDB: A high-performance relational database engine", long_about = None)]
@@ -45,8 +47,61 @@ pub struct DbArgs {
 
 #[derive(Subcommand)]
 pub enum DbCommands {
-    Create { name: String },
+    Create {
+        name: String,
+        /// Page size in bytes (power of two, 1024-32768). Defaults to storage.page_size
+        #[arg(long)]
+        page_size: Option<usize>,
+        /// Encrypt the database with the key from RDB_ENCRYPTION_KEY or storage.encryption_key_file
+        #[arg(long)]
+        encrypt: bool,
+    },
     List,
+    /// Migrate a database written by an older RDB to the current file format
+    Upgrade {
+        name: String,
+        /// Write the upgraded database to this file and leave the original untouched
+        #[arg(long)]
+        copy: Option<String>,
+    },
+    /// Check a database for corruption: page chains, slots, tuples, indexes and leaked pages. The server must be stopped
+    Check {
+        name: String,
+    },
+    /// Back a database up into a directory. A running server takes the backup without stopping writes
+    Backup {
+        name: String,
+        /// Directory to create the backup in
+        dest: String,
+        /// Copy only the pages changed since the last backup
+        #[arg(long)]
+        incremental: bool,
+        /// Session token for the server; defaults to RDB_TOKEN
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Replace a database with a backup. The server must be stopped
+    Restore {
+        /// Backup directories: a full backup, then any incremental backups taken after it, oldest first
+        #[arg(required = true)]
+        backups: Vec<String>,
+        /// Database to restore into; defaults to the database the backup was taken of
+        #[arg(long)]
+        name: Option<String>,
+        /// Replace the database if it exists
+        #[arg(long)]
+        force: bool,
+        /// Replay the archived log up to this time (RFC 3339, or YYYY-MM-DD HH:MM:SS in UTC)
+        #[arg(long, value_parser = parse_timestamp)]
+        until: Option<i64>,
+        /// Archived log to replay; defaults to the database's directory under storage.wal_archive_dir
+        #[arg(long)]
+        archive: Option<String>,
+    },
+    /// Compact every table, rebuild their indexes and shrink the file. The server must be stopped
+    Vacuum {
+        name: String,
+    },
 }
 
 #[derive(Args)]
@@ -105,3 +160,13 @@ pub struct ShellArgs {
     #[arg(long)]
     pub database: Option<String>,
 }
+
+// Parses a point in time into ms since the epoch
+fn parse_timestamp(value: &str) -> Result<i64, String> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp_millis());
+    }
+    chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f")
+        .map(|time| time.and_utc().timestamp_millis())
+        .map_err(|_| format!("'{}' is not a time; use RFC 3339 or YYYY-MM-DD HH:MM:SS", value))
+}
@@ -20,12 +20,22 @@ pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub workers: usize,
+    // How long a shutdown waits for in-flight requests before dropping them
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DatabaseConfig {
     pub default_db: String,
     pub data_dir: String,
+    // Databases the server creates in memory at start; they are gone once it stops
+    #[serde(default)]
+    pub memory_databases: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,6 +43,73 @@ pub struct StorageConfig {
     pub page_size: usize,
     pub buffer_pool_size: usize,
     pub compression_threshold: usize,
+    // File holding the key for encrypted databases; RDB_ENCRYPTION_KEY takes precedence
+    #[serde(default)]
+    pub encryption_key_file: Option<String>,
+    // Read database files through a memory map instead of pread
+    #[serde(default)]
+    pub mmap_reads: bool,
+    // Which cached page the buffer pool evicts when it needs a frame
+    #[serde(default)]
+    pub replacement_policy: ReplacementPolicy,
+    // Background writer: dirty pages are written out every flush interval, at
+    // most `flush_batch_pages` at a time. 0 disables it.
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    #[serde(default = "default_flush_batch_pages")]
+    pub flush_batch_pages: usize,
+    // Full checkpoint of every database; 0 disables it
+    #[serde(default = "default_checkpoint_interval_secs")]
+    pub checkpoint_interval_secs: u64,
+    // Pages of a table read ahead during full scans; 0 disables it
+    #[serde(default = "default_read_ahead_pages")]
+    pub read_ahead_pages: usize,
+    // Each database's log is copied into <dir>/<name>/ before a checkpoint
+    // empties it, for point-in-time recovery
+    #[serde(default)]
+    pub wal_archive_dir: Option<String>,
+    // Backups the server takes (the backup query) go into directories under this one
+    #[serde(default = "default_backup_dir")]
+    pub backup_dir: String,
+}
+
+fn default_flush_interval_ms() -> u64 {
+    200
+}
+
+fn default_flush_batch_pages() -> usize {
+    64
+}
+
+fn default_checkpoint_interval_secs() -> u64 {
+    300
+}
+
+fn default_read_ahead_pages() -> usize {
+    16
+}
+
+fn default_backup_dir() -> String {
+    "./backups".to_string()
+}
+
+fn default_auto_vacuum_interval_secs() -> u64 {
+    600
+}
+
+/// Page replacement policy of the buffer pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplacementPolicy {
+    /// Evicts the least recently used page
+    #[default]
+    Lru,
+    /// Second-chance approximation of LRU with a reference bit per frame
+    Clock,
+    /// Evicts the page whose second most recent access is oldest
+    LruK,
+    /// Keeps pages seen once in a small FIFO, so scans don't flush hot pages
+    TwoQ,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -52,6 +129,9 @@ pub struct IndexingConfig {
 pub struct PerformanceConfig {
     pub auto_compact: bool,
     pub compact_threshold: u8,
+    // How often auto-vacuum looks for tables to compact; 0 disables it
+    #[serde(default = "default_auto_vacuum_interval_secs")]
+    pub auto_vacuum_interval_secs: u64,
     pub max_batch_size: usize,
 }
 
@@ -84,22 +164,35 @@ impl Config {
         let config: Config = toml::from_str(&content)?;
         Ok(config)
     }
+}
 
-    pub fn default() -> Self {
+impl Default for Config {
+    fn default() -> Self {
         Self {
             server: ServerConfig {
                 host: "127.0.0.1".to_string(),
                 port: 8080,
                 workers: 4,
+                shutdown_timeout_secs: default_shutdown_timeout_secs(),
             },
             database: DatabaseConfig {
                 default_db: "main".to_string(),
                 data_dir: "./data".to_string(),
+                memory_databases: Vec::new(),
             },
             storage: StorageConfig {
                 page_size: 4096,
                 buffer_pool_size: 500,
                 compression_threshold: 64,
+                encryption_key_file: None,
+                mmap_reads: false,
+                replacement_policy: ReplacementPolicy::Lru,
+                flush_interval_ms: default_flush_interval_ms(),
+                flush_batch_pages: default_flush_batch_pages(),
+                checkpoint_interval_secs: default_checkpoint_interval_secs(),
+                read_ahead_pages: default_read_ahead_pages(),
+                wal_archive_dir: None,
+                backup_dir: default_backup_dir(),
             },
             cache: CacheConfig {
                 enable_query_cache: true,
@@ -113,6 +206,7 @@ impl Config {
             performance: PerformanceConfig {
                 auto_compact: true,
                 compact_threshold: 30,
+                auto_vacuum_interval_secs: default_auto_vacuum_interval_secs(),
                 max_batch_size: 10000,
             },
             auth: AuthConfig {
@@ -195,6 +289,12 @@ impl ConfigManager {
         self.root_dir.join("databases").join(format!("{}.db", db_name))
     }
 
+    /// Directory the server writes backups into. A relative
+    /// `storage.backup_dir` is taken from the root directory, like databases.
+    pub fn backup_dir(&self, config: &Config) -> Result<std::path::PathBuf> {
+        Ok(std::path::absolute(self.root_dir.join(&config.storage.backup_dir))?)
+    }
+
     pub fn load_config(&self) -> Result<Config> {
         let path = self.config_path();
         if path.exists() {
@@ -5,4 +5,23 @@ use thiserror::Error;
 pub enum RdbError {
     #[error("Unknown Error")]
     Unknown,
+    #[error("Corruption detected in database '{database}': page {page_id} checksum mismatch (stored {stored:#010x}, computed {computed:#010x})")]
+    PageCorruption {
+        database: String,
+        page_id: u32,
+        stored: u32,
+        computed: u32,
+    },
+    #[error("Database '{database}' uses file format v{found}, but this engine only supports up to v{supported}; open it with a newer RDB")]
+    FormatTooNew {
+        database: String,
+        found: u32,
+        supported: u32,
+    },
+    #[error("Database '{database}' uses file format v{found}; run `rdb db upgrade {database}` to migrate it to v{current}")]
+    UpgradeRequired {
+        database: String,
+        found: u32,
+        current: u32,
+    },
 }
@@ -25,8 +25,10 @@ async fn run_app() -> anyhow::Result<()> {
 
     // Initialize Logly first
     let logger = std::sync::Arc::new(Logger::new());
-    let mut logger_config = LoggerConfig::default();
-    logger_config.color = true;
+    let logger_config = LoggerConfig {
+        color: true,
+        ..Default::default()
+    };
     logger.configure(logger_config);
 
     let mut config_manager = ConfigManager::new()?;
@@ -64,7 +66,7 @@ async fn run_app() -> anyhow::Result<()> {
              print_status(&config_manager)?;
         }
         Some(Commands::Db(args)) => {
-             handle_db_command(args, &config_manager, &logger)?;
+             handle_db_command(args, &config, &config_manager, &logger)?;
         }
         Some(Commands::User(args)) => {
              handle_user_command(args, &config_manager)?;
@@ -117,24 +119,7 @@ fn run_init(manager: &mut ConfigManager, logger: &Logger) -> anyhow::Result<()>
     let main_db_path = manager.get_database_path("main");
     if !main_db_path.exists() {
         logger.info("Creating 'main' database...".to_string())?;
-        let pager = storage::pager::Pager::open(&main_db_path)?;
-        
-        // Allocate Page 0 for Header
-        let header_page_id = pager.allocate_page()?;
-        assert_eq!(header_page_id, 0);
-        
-        let header = storage::header::DatabaseHeader::new("main".to_string());
-        pager.write_header(&header)?;
-        
-        // Create Catalog Page (Page 1)
-        let page_id = pager.allocate_page()?;
-        assert_eq!(page_id, 1);
-        let catalog = storage::catalog::Catalog::new();
-        let bytes = catalog.to_bytes()?;
-        let mut page = storage::page::Page::new(page_id);
-        page.data[..bytes.len()].copy_from_slice(&bytes);
-        pager.write_page(&page)?;
-        
+        storage::pager::Pager::create_database(&main_db_path, "main", config.storage.page_size, None)?;
         logger.success("Created database: main".to_string())?;
     }
 
@@ -226,6 +211,10 @@ fn print_status(manager: &ConfigManager) -> anyhow::Result<()> {
                 
                 println!("  • {} ({} KB)", name, size_kb);
                 println!("    Path: {:?}", path);
+                if let Ok(header) = storage::header::DatabaseHeader::read_from_file(&path) {
+                    println!("    Page Size: {} bytes{}", header.page_size,
+                        if header.encryption { ", encrypted" } else { "" });
+                }
             }
             
             println!("\n  Total: {} database(s)", databases.len());
@@ -240,38 +229,207 @@ fn print_status(manager: &ConfigManager) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn handle_db_command(args: &cli::DbArgs, manager: &ConfigManager, logger: &Logger) -> anyhow::Result<()> {
+fn handle_db_command(args: &cli::DbArgs, config: &core::config::Config, manager: &ConfigManager, logger: &std::sync::Arc<Logger>) -> anyhow::Result<()> {
     match &args.command {
-        cli::DbCommands::Create { name } => {
+        cli::DbCommands::Create { name, page_size, encrypt } => {
             let path = manager.get_database_path(name);
             if path.exists() {
                 logger.error(format!("Database {} already exists", name))?;
                 return Ok(());
             }
             
-            let pager = storage::pager::Pager::open(&path)?;
-            
-            // Allocate Page 0 for Header
-            let header_page_id = pager.allocate_page()?;
-            assert_eq!(header_page_id, 0);
+            let key = if *encrypt {
+                let key = storage::crypto::load_key(config.storage.encryption_key_file.as_deref().map(std::path::Path::new))?;
+                Some(key.ok_or(anyhow::anyhow!(
+                    "--encrypt needs a key: set {} or storage.encryption_key_file", storage::crypto::KEY_ENV_VAR
+                ))?)
+            } else {
+                None
+            };
+            let page_size = page_size.unwrap_or(config.storage.page_size);
+            storage::pager::Pager::create_database(&path, name, page_size, key)?;
 
-            let header = storage::header::DatabaseHeader::new(name.clone());
-            pager.write_header(&header)?;
-            
-            // Create Catalog Page (Page 1)
-            let page_id = pager.allocate_page()?;
-            assert_eq!(page_id, 1);
-            let catalog = storage::catalog::Catalog::new();
-            let bytes = catalog.to_bytes()?;
-            let mut page = storage::page::Page::new(page_id);
-            page.data[..bytes.len()].copy_from_slice(&bytes);
-            pager.write_page(&page)?;
-
-            logger.success(format!("Created database: {}", name))?;
+            logger.success(format!("Created database: {} ({} byte pages{})", name, page_size,
+                if *encrypt { ", encrypted" } else { "" }))?;
         }
         cli::DbCommands::List => {
              print_status(manager)?;
         }
+        cli::DbCommands::Upgrade { name, copy } => {
+            let path = manager.get_database_path(name);
+            if !path.exists() {
+                logger.error(format!("Database {} not found", name))?;
+                return Ok(());
+            }
+
+            let key = storage::crypto::load_key(config.storage.encryption_key_file.as_deref().map(std::path::Path::new))?;
+            let current = storage::header::CURRENT_FILE_FORMAT_VERSION;
+            match copy {
+                Some(target) => {
+                    let report = storage::upgrade::upgrade(&path, std::path::Path::new(target), key)?;
+                    logger.success(format!("Upgraded {} from format v{} to v{} into {}: {} table(s), {} row(s)",
+                        name, report.from_version, current, target, report.tables, report.rows))?;
+                }
+                None => {
+                    let (report, backup) = storage::upgrade::upgrade_in_place(&path, key)?;
+                    logger.success(format!("Upgraded {} from format v{} to v{}: {} table(s), {} row(s)",
+                        name, report.from_version, current, report.tables, report.rows))?;
+                    logger.info(format!("The original file was kept as {:?}", backup))?;
+                    retire_wal_archive(config, name, logger)?;
+                }
+            }
+        }
+        cli::DbCommands::Check { name } => {
+            let path = manager.get_database_path(name);
+            if !path.exists() {
+                logger.error(format!("Database {} not found", name))?;
+                return Ok(());
+            }
+            if storage::header::DatabaseHeader::read_from_file(&path).is_ok_and(|header| header.in_use) {
+                logger.error(format!("Database {} is in use or was not shut down cleanly; stop the server first, \
+                    or start and stop it once to recover the database", name))?;
+                return Ok(());
+            }
+
+            let key = storage::crypto::load_key(config.storage.encryption_key_file.as_deref().map(std::path::Path::new))?;
+            let report = storage::check::check_database(&path, key, wal_archive(config))?;
+            for problem in &report.problems {
+                logger.error(problem.clone())?;
+            }
+            if !report.is_clean() {
+                logger.error(format!("Found {} problem(s) in {}", report.problems.len(), name))?;
+                std::process::exit(1);
+            }
+            logger.success(format!("Checked {}: {} table(s), {} row(s), {} page(s), no problems found",
+                name, report.tables, report.rows, report.pages))?;
+        }
+        cli::DbCommands::Backup { name, dest, incremental, token } => {
+            let path = manager.get_database_path(name);
+            if !path.exists() {
+                logger.error(format!("Database {} not found", name))?;
+                return Ok(());
+            }
+            let dest = std::path::absolute(dest)?;
+
+            let manifest: storage::backup::BackupManifest = if storage::header::DatabaseHeader::read_from_file(&path)?.in_use {
+                // The server only writes under its backup directory, and takes
+                // the destination relative to it
+                let backup_dir = manager.backup_dir(config)?;
+                let Ok(relative) = dest.strip_prefix(&backup_dir) else {
+                    logger.error(format!("The server writes backups under storage.backup_dir ({:?}); choose a destination there", backup_dir))?;
+                    return Ok(());
+                };
+                let token = token.clone().or_else(|| std::env::var("RDB_TOKEN").ok());
+                let query = serde_json::json!({"op": "backup", "database": name, "destination": relative, "incremental": incremental});
+                let reply = cli::client::send_query(&config.server.host, config.server.port, token.as_deref(), &query)?;
+                serde_json::from_value(reply)?
+            } else {
+                let key = storage::crypto::load_key(config.storage.encryption_key_file.as_deref().map(std::path::Path::new))?;
+                let pager = storage::pager::Pager::open_with_archive(&path, key, wal_archive(config))?;
+                storage::backup::backup(&pager, &dest, *incremental)?
+            };
+            match manifest.changed_pages {
+                Some(changed) => logger.success(format!("Backed up {} incrementally to {:?}: {} of {} page(s) changed, sha256 {}",
+                    name, dest, changed, manifest.pages, manifest.sha256))?,
+                None => logger.success(format!("Backed up {} to {:?}: {} page(s), sha256 {}", name, dest, manifest.pages, manifest.sha256))?,
+            }
+        }
+        cli::DbCommands::Restore { backups, name, force, until, archive } => {
+            let backups: Vec<std::path::PathBuf> = backups.iter().map(std::path::PathBuf::from).collect();
+            let manifest = storage::backup::read_manifest(&backups[0])?;
+            let name = name.clone().unwrap_or(manifest.database.clone());
+            let path = manager.get_database_path(&name);
+            if path.exists() {
+                if storage::header::DatabaseHeader::read_from_file(&path).is_ok_and(|header| header.in_use) {
+                    logger.error(format!("Database {} is in use or was not shut down cleanly; stop the server first", name))?;
+                    return Ok(());
+                }
+                if !*force {
+                    logger.error(format!("Database {} exists; pass --force to replace it", name))?;
+                    return Ok(());
+                }
+            }
+
+            let key = storage::crypto::load_key(config.storage.encryption_key_file.as_deref().map(std::path::Path::new))?;
+            let taken_at = |manifest: &storage::backup::BackupManifest| chrono::DateTime::from_timestamp(manifest.created_at, 0)
+                .map_or(manifest.created_at.to_string(), |t| t.to_rfc3339());
+            match until {
+                None => {
+                    let manifest = storage::backup::restore(&backups, &path, key)?;
+                    logger.success(format!("Restored {} from the backup of {} taken at {}", name, manifest.database, taken_at(&manifest)))?;
+                }
+                Some(until) => {
+                    let archive = match (archive, wal_archive(config)) {
+                        (Some(dir), _) => std::path::PathBuf::from(dir),
+                        (None, Some(root)) => storage::archive::segment_dir(root, &manifest.database),
+                        (None, None) => {
+                            logger.error("No log archive to replay: set storage.wal_archive_dir or pass --archive".to_string())?;
+                            return Ok(());
+                        }
+                    };
+                    let (manifest, report) = storage::backup::restore_until(&backups, &path, key, &archive, *until)?;
+                    let last_commit = report.last_commit_ms.and_then(chrono::DateTime::from_timestamp_millis)
+                        .map_or("none".to_string(), |t| t.to_rfc3339());
+                    logger.success(format!("Restored {} from the backup of {} taken at {} and replayed {} transaction(s) \
+                        from {} log segment(s); the last one committed at {}",
+                        name, manifest.database, taken_at(&manifest), report.transactions, report.segments, last_commit))?;
+                    if report.reached_end {
+                        logger.warning("The archived log ends before the requested time; anything later was not archived".to_string())?;
+                    }
+                }
+            }
+            retire_wal_archive(config, &name, logger)?;
+        }
+        cli::DbCommands::Vacuum { name } => {
+            let path = manager.get_database_path(name);
+            if !path.exists() {
+                logger.error(format!("Database {} not found", name))?;
+                return Ok(());
+            }
+            // A running server would keep writing pages this moves around
+            if storage::header::DatabaseHeader::read_from_file(&path)?.in_use {
+                logger.error(format!("Database {} is in use or was not shut down cleanly; stop the server first, \
+                    or vacuum it through the server with the vacuum query", name))?;
+                return Ok(());
+            }
+
+            let key = storage::crypto::load_key(config.storage.encryption_key_file.as_deref().map(std::path::Path::new))?;
+            let pager = std::sync::Arc::new(storage::pager::Pager::open_with_archive(&path, key, wal_archive(config))?);
+            let buffer_pool = std::sync::Arc::new(storage::buffer::BufferPool::new(config.storage.buffer_pool_size));
+            buffer_pool.register_pager(0, pager);
+            let error_logger = logger.clone();
+            let executor = query::executor::Executor::new(buffer_pool.clone())
+                .with_compression_threshold(config.storage.compression_threshold)
+                .with_error_handler(move |e| {
+                    let _ = error_logger.warning(format!("{:#}", e));
+                });
+            let result = executor.execute(query::Query::Vacuum(query::VacuumQuery {
+                database: "main".to_string(),
+                table: None,
+                min_free_percent: None,
+            }));
+            buffer_pool.shutdown()?;
+
+            if let query::executor::ExecutionResult::Json(stats) = result? {
+                logger.success(format!("Vacuumed {}: {} table(s), {} row(s) moved, {} page(s) freed, {} page(s) cut from the file",
+                    name, stats["tables"], stats["rows_moved"], stats["pages_freed"], stats["pages_truncated"]))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Where database logs are archived, if anywhere
+fn wal_archive(config: &core::config::Config) -> Option<&std::path::Path> {
+    config.storage.wal_archive_dir.as_deref().map(std::path::Path::new)
+}
+
+// A database replaced by a restore or upgrade starts a new log, which must
+// not be mixed up with the archive of the old one
+fn retire_wal_archive(config: &core::config::Config, name: &str, logger: &Logger) -> anyhow::Result<()> {
+    if let Some(root) = wal_archive(config)
+        && let Some(retired) = storage::archive::retire(root, name)? {
+            logger.info(format!("Moved the log archive of the replaced database to {:?}", retired))?;
     }
     Ok(())
 }
@@ -332,29 +490,40 @@ async fn start_server(args: &StartArgs, mut config: core::config::Config, config
     }
 
     // Initialize Storage Engine
-    let buffer_pool = std::sync::Arc::new(storage::buffer::BufferPool::new(config.storage.buffer_pool_size));
+    let buffer_pool = std::sync::Arc::new(storage::buffer::BufferPool::with_policy(config.storage.buffer_pool_size, config.storage.replacement_policy)
+        .with_read_ahead(config.storage.read_ahead_pages));
     
     // Open existing databases
+    let encryption_key = storage::crypto::load_key(config.storage.encryption_key_file.as_deref().map(std::path::Path::new))?;
     let db_dir = config_manager.root_dir.join("databases");
+    let mut database_names = Vec::new();
     if db_dir.exists() {
         for entry in std::fs::read_dir(db_dir)? {
             let entry = entry?;
             let path = entry.path();
-            if path.extension().map_or(false, |ext| ext == "rdb") {
+            // `rdb db create` names files `<name>.db`; older setups used `.rdb`
+            if path.extension().is_some_and(|ext| ext == "db" || ext == "rdb") {
                 let name = path.file_stem().unwrap().to_string_lossy();
-                let pager = std::sync::Arc::new(storage::pager::Pager::open(&path)?);
-                
-                // Register with ID. "main" -> 0. Others -> hash.
-                let db_id = if name == "main" {
-                    0
-                } else {
-                    let mut hasher = std::collections::hash_map::DefaultHasher::new();
-                    use std::hash::{Hash, Hasher};
-                    name.hash(&mut hasher);
-                    hasher.finish() as u32
-                };
+                let pager = std::sync::Arc::new(storage::pager::Pager::open_with_archive(&path, encryption_key, wal_archive(&config))?);
+                if config.storage.mmap_reads {
+                    pager.enable_mmap_reads()?;
+                }
+                let db_id = query::executor::database_id(&name);
                 
+                if !args.silent {
+                    if pager.unclean_shutdown() {
+                        logger.warning(format!("Database {} was not shut down cleanly", name))?;
+                    }
+                    let report = pager.recovery_report();
+                    if !report.is_clean() {
+                        logger.warning(format!(
+                            "Recovered database {} from WAL: {} transaction(s) replayed, {} rolled back",
+                            name, report.committed_txns, report.rolled_back_txns
+                        ))?;
+                    }
+                }
                 buffer_pool.register_pager(db_id, pager);
+                database_names.push(name.to_string());
                 if !args.silent {
                     logger.info(format!("Loaded database: {} (ID: {})", name, db_id))?;
                 }
@@ -362,7 +531,55 @@ async fn start_server(args: &StartArgs, mut config: core::config::Config, config
         }
     }
 
-    let executor = std::sync::Arc::new(query::executor::Executor::new(buffer_pool));
+    for name in &config.database.memory_databases {
+        if database_names.contains(name) {
+            return Err(anyhow::anyhow!("Database {} is configured in memory, but also exists as a file", name));
+        }
+        let store = || std::sync::Arc::new(storage::store::MemoryStore::new());
+        let pager = storage::pager::Pager::create_database_in(name, store(), store(), config.storage.page_size, None)?;
+        let db_id = query::executor::database_id(name);
+        buffer_pool.register_pager(db_id, std::sync::Arc::new(pager));
+        database_names.push(name.clone());
+        if !args.silent {
+            logger.info(format!("Created in-memory database: {} (ID: {})", name, db_id))?;
+        }
+    }
+
+    let writer_settings = storage::writer::WriterSettings {
+        flush_interval: (config.storage.flush_interval_ms > 0).then(|| std::time::Duration::from_millis(config.storage.flush_interval_ms)),
+        flush_batch: config.storage.flush_batch_pages,
+        checkpoint_interval: (config.storage.checkpoint_interval_secs > 0).then(|| std::time::Duration::from_secs(config.storage.checkpoint_interval_secs)),
+    };
+    let writer_logger = logger.clone();
+    let writer = storage::writer::BackgroundWriter::start(buffer_pool.clone(), writer_settings, move |e| {
+        let _ = writer_logger.error(format!("Background writer: {}", e));
+    });
+
+    let executor_logger = logger.clone();
+    let executor = std::sync::Arc::new(query::executor::Executor::new(buffer_pool.clone())
+        .with_compression_threshold(config.storage.compression_threshold)
+        .with_backup_dir(config_manager.backup_dir(&config)?)
+        .with_error_handler(move |e| {
+            let _ = executor_logger.warning(format!("{:#}", e));
+        }));
+
+    let auto_vacuum = (config.performance.auto_compact && config.performance.auto_vacuum_interval_secs > 0).then(|| {
+        let vacuum_logger = logger.clone();
+        let error_logger = logger.clone();
+        storage::vacuum::AutoVacuum::start(
+            executor.clone(),
+            database_names,
+            std::time::Duration::from_secs(config.performance.auto_vacuum_interval_secs),
+            config.performance.compact_threshold,
+            move |database, stats| {
+                let _ = vacuum_logger.info(format!("Auto-vacuumed {}: {} table(s), {} page(s) freed, {} page(s) cut from the file",
+                    database, stats["tables"], stats["pages_freed"], stats["pages_truncated"]));
+            },
+            move |database, e| {
+                let _ = error_logger.error(format!("Auto-vacuum of {}: {}", database, e));
+            },
+        )
+    });
     
     // Initialize Auth
     let auth_manager = std::sync::Arc::new(auth::AuthManager::new());
@@ -373,7 +590,14 @@ async fn start_server(args: &StartArgs, mut config: core::config::Config, config
         // Create default admin if not exists?
     }
     
-    server::run_server(config, executor, auth_manager, logger).await?;
+    server::run_server(config, executor, auth_manager, logger.clone()).await?;
+
+    // Requests have drained; write everything out and mark each database closed
+    logger.info("Shutting down: flushing databases".to_string())?;
+    drop(auto_vacuum);
+    drop(writer);
+    buffer_pool.shutdown()?;
+    logger.info("Shutdown complete".to_string())?;
     Ok(())
 }
 
@@ -1,30 +1,122 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use crate::query::{Query, CreateTableQuery, InsertQuery, SelectQuery, UpdateQuery, DeleteQuery, DropTableQuery};
+use crate::query::{Query, CreateTableQuery, InsertQuery, SelectQuery, UpdateQuery, DeleteQuery, DropTableQuery, VacuumQuery, BackupQuery};
+use crate::storage::backup;
 use crate::storage::buffer::{BufferPool, GlobalPageId};
 use crate::storage::catalog::{Catalog, TableInfo};
-use crate::storage::slotted::SlottedPage;
+use crate::storage::codec::{self, Codec, TupleCodec};
+use crate::storage::overflow;
+use crate::storage::fsm::FreeSpaceMap;
+use crate::storage::page::PAGE_CHECKSUM_SIZE;
+use crate::storage::slotted::{self, SlottedPage};
 use crate::storage::index::BTreeIndex;
+use crate::storage::vacuum::{self, VacuumStats};
 use anyhow::{Result, anyhow};
-use serde_json::Value;
+use serde_json::{Value, json};
 
 pub enum ExecutionResult {
     Message(String),
     Json(Value),
 }
 
+type ErrorHandler = Box<dyn Fn(anyhow::Error) + Send + Sync>;
+
+/// ID a database is registered under in the buffer pool: "main" is 0, others
+/// a hash of the name.
+pub fn database_id(name: &str) -> u32 {
+    if name == "main" {
+        return 0;
+    }
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    use std::hash::{Hash, Hasher};
+    name.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
 pub struct Executor {
     buffer_pool: Arc<BufferPool>,
+    compression_threshold: usize,
+    on_error: ErrorHandler,
+    // Where backup queries may write; None refuses them
+    backup_dir: Option<std::path::PathBuf>,
+    // Codecs of tables with a zstd dictionary, by database, table and first
+    // dictionary page, so queries don't read and prepare the dictionary again
+    codecs: parking_lot::Mutex<HashMap<(u32, String, u32), Arc<TupleCodec>>>,
 }
 
 impl Executor {
     pub fn new(buffer_pool: Arc<BufferPool>) -> Self {
-        Self { buffer_pool }
+        Self { buffer_pool, compression_threshold: 64, on_error: Box::new(|_| {}), backup_dir: None, codecs: Default::default() }
+    }
+
+    /// Sets the size above which tuples are compressed.
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// Lets backup queries write into directories under `dir`.
+    pub fn with_backup_dir(mut self, dir: impl Into<std::path::PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    /// Hands errors that don't fail the query they happen in to `on_error`,
+    /// e.g. when a vacuum committed but the file couldn't be shrunk.
+    pub fn with_error_handler<F>(mut self, on_error: F) -> Self
+    where
+        F: Fn(anyhow::Error) + Send + Sync + 'static,
+    {
+        self.on_error = Box::new(on_error);
+        self
     }
 
+    /// Executes a query. Anything that may write runs inside one transaction, so
+    /// its changes commit together or not at all. Each database commits on its
+    /// own, so a batch may write to one database only.
     pub fn execute(&self, query: Query) -> Result<ExecutionResult> {
+        let mut written = query.written_database_names();
+        written.sort_unstable();
+        written.dedup();
+        let db_id = match written.as_slice() {
+            [] => return self.run(query),
+            [name] => self.get_db_id(name)?,
+            names => return Err(anyhow!("A batch can only write to one database, not {}", names.join(", "))),
+        };
+
+        self.buffer_pool.begin(db_id)?;
+        let vacuums = query.vacuums();
+        match self.run(query) {
+            Ok(mut result) => {
+                // A transaction that fails to commit is rolled back
+                self.buffer_pool.commit(db_id)?;
+                // Pages a vacuum freed at the end of the file can only be cut
+                // off once committed. The vacuum stands if that fails; the
+                // next one cuts them.
+                if vacuums {
+                    let truncated = self.buffer_pool.truncate_free_pages(db_id).unwrap_or_else(|e| {
+                        (self.on_error)(e.context("Vacuum committed, but shrinking the file failed"));
+                        0
+                    });
+                    if let ExecutionResult::Json(Value::Object(stats)) = &mut result {
+                        stats.insert("pages_truncated".to_string(), truncated.into());
+                    }
+                }
+                Ok(result)
+            }
+            Err(e) => {
+                let _ = self.buffer_pool.rollback(db_id);
+                Err(e)
+            }
+        }
+    }
+
+    fn run(&self, query: Query) -> Result<ExecutionResult> {
         match query {
             Query::CreateTable(q) => self.handle_create_table(q),
             Query::DropTable(q) => self.handle_drop_table(q),
+            Query::Vacuum(q) => self.handle_vacuum(q),
+            Query::Backup(q) => self.handle_backup(q),
             Query::Insert(q) => self.handle_insert(q),
             Query::Select(q) => self.handle_select(q),
             Query::Update(q) => self.handle_update(q),
@@ -34,9 +126,13 @@ impl Executor {
     }
 
     fn handle_batch(&self, queries: Vec<Query>) -> Result<ExecutionResult> {
+        // The batch's own transaction would keep a backup waiting forever
+        if queries.iter().any(|q| matches!(q, Query::Backup(_))) {
+            return Err(anyhow!("Backups can't run inside a batch"));
+        }
         let mut results = Vec::new();
         for query in queries {
-            match self.execute(query)? {
+            match self.run(query)? {
                 ExecutionResult::Message(msg) => results.push(Value::String(msg)),
                 ExecutionResult::Json(val) => results.push(val),
             }
@@ -73,12 +169,12 @@ impl Executor {
                 "LIKE" => {
                     if let (Some(s), Some(pattern)) = (col_val.as_str(), where_clause.value.as_str()) {
                         // Simple wildcard support: % at start/end
-                        if pattern.starts_with('%') && pattern.ends_with('%') {
+                        if pattern.len() >= 2 && pattern.starts_with('%') && pattern.ends_with('%') {
                             s.contains(&pattern[1..pattern.len()-1])
-                        } else if pattern.starts_with('%') {
-                            s.ends_with(&pattern[1..])
-                        } else if pattern.ends_with('%') {
-                            s.starts_with(&pattern[..pattern.len()-1])
+                        } else if let Some(suffix) = pattern.strip_prefix('%') {
+                            s.ends_with(suffix)
+                        } else if let Some(prefix) = pattern.strip_suffix('%') {
+                            s.starts_with(prefix)
                         } else {
                             s == pattern
                         }
@@ -96,45 +192,114 @@ impl Executor {
         }
     }
 
-    fn get_db_id(&self, _db_name: &str) -> Result<u32> {
-        if _db_name == "main" {
-            Ok(0)
-        } else {
-            let mut hasher = std::collections::hash_map::DefaultHasher::new();
-            use std::hash::{Hash, Hasher};
-            _db_name.hash(&mut hasher);
-            Ok(hasher.finish() as u32)
+    fn get_db_id(&self, db_name: &str) -> Result<u32> {
+        Ok(database_id(db_name))
+    }
+
+    // Free space map of a table. Tables created before maps existed get one
+    // built from their heap chain on first use.
+    fn free_space_map(&self, catalog: &mut Catalog, table: &str, db_id: u32) -> Result<FreeSpaceMap> {
+        let table_info = catalog.tables.get_mut(table)
+            .ok_or(anyhow!("Table {} not found", table))?;
+        if table_info.fsm_root_page_id != 0 {
+            return FreeSpaceMap::load(&self.buffer_pool, db_id, table_info.fsm_root_page_id);
+        }
+        let fsm = FreeSpaceMap::build(&self.buffer_pool, db_id, table_info.root_page_id)?;
+        table_info.fsm_root_page_id = fsm.root_page_id();
+        catalog.save(&self.buffer_pool, db_id)?;
+        Ok(fsm)
+    }
+
+    fn codec(&self, table_info: &TableInfo, db_id: u32) -> Result<Arc<TupleCodec>> {
+        let Some((first_page_id, _)) = table_info.dictionary else {
+            return Ok(Arc::new(TupleCodec::for_table(&self.buffer_pool, db_id, table_info, self.compression_threshold)?));
+        };
+        let key = (db_id, table_info.name.clone(), first_page_id);
+        if let Some(codec) = self.codecs.lock().get(&key) {
+            return Ok(codec.clone());
         }
+        let codec = Arc::new(TupleCodec::for_table(&self.buffer_pool, db_id, table_info, self.compression_threshold)?);
+        self.codecs.lock().insert(key, codec.clone());
+        Ok(codec)
+    }
+
+    // Drops the cached codec of a table whose dictionary may be gone or replaced
+    fn forget_codec(&self, db_id: u32, table: &str) {
+        self.codecs.lock().retain(|(id, name, _), _| *id != db_id || name != table);
+    }
+
+    // Links a new, empty page to the end of a table's heap chain
+    fn append_heap_page(&self, db_id: u32, fsm: &mut FreeSpaceMap) -> Result<u32> {
+        let new_page = self.buffer_pool.new_page(db_id)?;
+        let new_page_id = new_page.read().id;
+        {
+            let mut new_guard = new_page.write();
+            let mut new_slotted = SlottedPage::new(&mut new_guard);
+            new_slotted.init();
+            fsm.record(new_page_id, new_slotted.available_space());
+        }
+
+        // Follow the chain in case the recorded tail is not the last page
+        let mut tail_page_id = fsm.tail_page_id();
+        loop {
+            let tail = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: tail_page_id })?;
+            let mut tail_guard = tail.write();
+            let mut tail_slotted = SlottedPage::new(&mut tail_guard);
+            match tail_slotted.next_page_id() {
+                0 => {
+                    tail_slotted.set_next_page_id(new_page_id);
+                    break;
+                }
+                next => tail_page_id = next,
+            }
+        }
+        fsm.set_tail_page_id(new_page_id);
+        Ok(new_page_id)
     }
 
     fn handle_create_table(&self, query: CreateTableQuery) -> Result<ExecutionResult> {
         let db_id = self.get_db_id(&query.database)?;
         
-        // 1. Load Catalog (Page 1)
-        let catalog_page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: 1 })?;
-        let mut catalog_guard = catalog_page.write();
-        
-        let mut catalog = Catalog::from_bytes(&catalog_guard.data)?;
+        // 1. Load Catalog
+        let mut catalog = Catalog::load(&self.buffer_pool, db_id)?;
         
         if catalog.tables.contains_key(&query.table) {
             return Err(anyhow!("Table {} already exists", query.table));
         }
 
+        let compression = query.compression.unwrap_or_default();
+        compression.codec.validate()?;
+        let dictionary = if compression.dictionary_samples.is_empty() {
+            None
+        } else {
+            if !matches!(compression.codec, Codec::Zstd { .. }) {
+                return Err(anyhow!("Dictionaries need the zstd codec"));
+            }
+            let samples = compression.dictionary_samples.iter()
+                .map(serde_json::to_vec)
+                .collect::<Result<Vec<_>, _>>()?;
+            let dictionary = codec::train_dictionary(&samples)?;
+            Some((overflow::write_chain(&self.buffer_pool, db_id, &dictionary)?, dictionary.len()))
+        };
+
         // 2. Allocate root page for table
         let root_page = self.buffer_pool.new_page(db_id)?;
         let root_page_id = root_page.read().id;
+        let mut fsm = FreeSpaceMap::create(&self.buffer_pool, db_id, root_page_id)?;
         {
             let mut root_guard = root_page.write();
             let mut slotted = SlottedPage::new(&mut root_guard);
             slotted.init();
+            fsm.record(root_page_id, slotted.available_space());
         }
+        fsm.save(&self.buffer_pool)?;
         
         // 3. Allocate Index Root Page
         let index_root_page = self.buffer_pool.new_page(db_id)?;
         let index_root_page_id = index_root_page.read().id;
         
         // Init Index
-        let index = BTreeIndex::new(self.buffer_pool.clone(), db_id, index_root_page_id);
+        let index = BTreeIndex::new(self.buffer_pool.clone(), db_id, index_root_page_id)?;
         index.init()?;
 
         // 4. Update Catalog
@@ -142,20 +307,14 @@ impl Executor {
             name: query.table.clone(),
             root_page_id,
             index_root_page_id,
+            fsm_root_page_id: fsm.root_page_id(),
             columns: query.columns,
+            codec: compression.codec,
+            dictionary,
         };
+        self.forget_codec(db_id, &query.table);
         catalog.add_table(table_info);
-        
-        let bytes = catalog.to_bytes()?;
-        if bytes.len() > crate::storage::page::PAGE_SIZE {
-            return Err(anyhow!("Catalog too large for single page"));
-        }
-        
-        catalog_guard.data[..bytes.len()].copy_from_slice(&bytes);
-        if bytes.len() < crate::storage::page::PAGE_SIZE {
-            catalog_guard.data[bytes.len()..].fill(0);
-        }
-        catalog_guard.dirty = true;
+        catalog.save(&self.buffer_pool, db_id)?;
 
         Ok(ExecutionResult::Message(format!("Table {} created", query.table)))
     }
@@ -164,25 +323,36 @@ impl Executor {
         let db_id = self.get_db_id(&query.database)?;
         
         // 1. Load Catalog
-        let catalog_page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: 1 })?;
-        let mut catalog_guard = catalog_page.write();
-        let mut catalog = Catalog::from_bytes(&catalog_guard.data)?;
-        
-        if !catalog.tables.contains_key(&query.table) {
-            return Err(anyhow!("Table {} not found", query.table));
-        }
+        let mut catalog = Catalog::load(&self.buffer_pool, db_id)?;
         
         // 2. Remove from Catalog
-        // Note: In a real DB, we would also free the pages (root_page_id, index_root_page_id, and all data pages)
-        // For this MVP, we just remove the metadata entry. The pages leak but are inaccessible.
-        catalog.tables.remove(&query.table);
+        let table_info = catalog.tables.remove(&query.table)
+            .ok_or(anyhow!("Table {} not found", query.table))?;
+        catalog.save(&self.buffer_pool, db_id)?;
+        self.forget_codec(db_id, &query.table);
         
-        let bytes = catalog.to_bytes()?;
-        catalog_guard.data[..bytes.len()].copy_from_slice(&bytes);
-        if bytes.len() < crate::storage::page::PAGE_SIZE {
-            catalog_guard.data[bytes.len()..].fill(0);
+        // 3. Return the data pages and the index to the free list
+        let mut current_page_id = table_info.root_page_id;
+        while current_page_id != 0 {
+            let page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: current_page_id })?;
+            let mut page_guard = page.write();
+            let mut slotted = SlottedPage::with_overflow(&mut page_guard, &self.buffer_pool, db_id);
+            // Deleting the rows frees their overflow pages
+            for i in 0..slotted.num_slots() {
+                slotted.mark_deleted(i)?;
+            }
+            let next = slotted.next_page_id();
+            drop(page_guard);
+            self.buffer_pool.free_page(db_id, current_page_id)?;
+            current_page_id = next;
+        }
+        BTreeIndex::new(self.buffer_pool.clone(), db_id, table_info.index_root_page_id)?.destroy()?;
+        if let Some((first_page_id, len)) = table_info.dictionary {
+            overflow::free_chain(&self.buffer_pool, db_id, first_page_id, len)?;
+        }
+        if table_info.fsm_root_page_id != 0 {
+            FreeSpaceMap::load(&self.buffer_pool, db_id, table_info.fsm_root_page_id)?.destroy(&self.buffer_pool)?;
         }
-        catalog_guard.dirty = true;
         
         Ok(ExecutionResult::Message(format!("Table {} dropped", query.table)))
     }
@@ -191,84 +361,63 @@ impl Executor {
         let db_id = self.get_db_id(&query.database)?;
         
         // 1. Load Catalog
-        let catalog_page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: 1 })?;
-        let catalog_guard = catalog_page.read();
-        let catalog = Catalog::from_bytes(&catalog_guard.data)?;
+        let mut catalog = Catalog::load(&self.buffer_pool, db_id)?;
+        let mut fsm = self.free_space_map(&mut catalog, &query.table, db_id)?;
         
         let table_info = catalog.get_table(&query.table)
             .ok_or(anyhow!("Table {} not found", query.table))?;
             
         // Find PK column
         let pk_col = table_info.columns.iter().find(|c| c.primary_key);
+        let codec = self.codec(table_info, db_id)?;
             
         // 2. Insert values
-        let mut current_page_id = table_info.root_page_id;
-        
         for value in query.values {
             let tuple_data = serde_json::to_vec(&value)?;
-            
-            #[allow(unused_assignments)]
-            let mut inserted_page_id = None;
-            #[allow(unused_assignments)]
-            let mut inserted_slot_id = None;
-            
-            loop {
-                let page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: current_page_id })?;
-                let mut page_guard = page.write();
-                let mut slotted = SlottedPage::new(&mut page_guard);
-                
-                match slotted.insert_tuple(&tuple_data) {
-                    Ok(slot_id) => {
-                        inserted_page_id = Some(current_page_id);
-                        inserted_slot_id = Some(slot_id);
-                        break;
-                    },
-                    Err(_) => {
-                        // Page full
-                        let next = slotted.next_page_id();
-                        if next != 0 {
-                            current_page_id = next;
-                            continue;
-                        } else {
-                            // Allocate new page
-                            drop(slotted); // Release borrow
-                            
-                            let new_page = self.buffer_pool.new_page(db_id)?;
-                            let new_page_id = new_page.read().id;
-                            
-                            {
-                                let mut new_guard = new_page.write();
-                                let mut new_slotted = SlottedPage::new(&mut new_guard);
-                                new_slotted.init();
-                            }
-                            
-                            // Link
-                            let mut slotted = SlottedPage::new(&mut page_guard);
-                            slotted.set_next_page_id(new_page_id);
-                            
-                            current_page_id = new_page_id;
-                        }
-                    }
-                }
-            }
+            let (inserted_page_id, inserted_slot_id) = self.insert_tuple(db_id, &mut fsm, &codec, &tuple_data)?;
             
             // Insert into Index
-            if let Some(pk) = pk_col {
-                if let Some(val) = value.get(&pk.name) {
-                    if let Some(int_val) = val.as_u64() {
-                        if let (Some(pid), Some(sid)) = (inserted_page_id, inserted_slot_id) {
-                             let key = int_val as u32;
-                             let index = BTreeIndex::new(self.buffer_pool.clone(), db_id, table_info.index_root_page_id);
-                             index.insert(key, (pid, sid))?;
-                        }
-                    }
-                }
+            if let Some(pk) = pk_col
+                && let Some(val) = value.get(&pk.name)
+                && let Some(int_val) = val.as_u64() {
+                     let key = int_val as u32;
+                     let index = BTreeIndex::new(self.buffer_pool.clone(), db_id, table_info.index_root_page_id)?;
+                     index.insert(key, (inserted_page_id, inserted_slot_id))?;
             }
         }
+        fsm.save(&self.buffer_pool)?;
 
         Ok(ExecutionResult::Message("Inserted".to_string()))
     }
 
+    // Stores a tuple on a page the free space map says has room, or on a new
+    // page at the end of the heap chain. Returns where it went.
+    fn insert_tuple(&self, db_id: u32, fsm: &mut FreeSpaceMap, codec: &TupleCodec, tuple_data: &[u8]) -> Result<(u32, u16)> {
+        let usable = self.buffer_pool.page_size(db_id)? - PAGE_CHECKSUM_SIZE;
+        let needed = SlottedPage::space_needed(tuple_data.len(), usable);
+        loop {
+            // Go straight to a page with room, or add one at the end of the chain
+            let page_id = match fsm.find(needed) {
+                Some(page_id) => page_id,
+                None => self.append_heap_page(db_id, fsm)?,
+            };
+            let page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id })?;
+            let mut page_guard = page.write();
+            let mut slotted = SlottedPage::with_overflow(&mut page_guard, &self.buffer_pool, db_id).with_codec(codec);
+            
+            match slotted.insert_tuple(tuple_data) {
+                Ok(slot_id) => {
+                    fsm.record(page_id, slotted.available_space());
+                    return Ok((page_id, slot_id));
+                },
+                // Large rows go to overflow pages, so this only fails when a fresh page is too small
+                Err(e) if slotted.num_slots() == 0 => return Err(e),
+                // The map was out of date; correcting it keeps the page from being picked again
+                Err(_) => fsm.record(page_id, slotted.available_space()),
+            }
+        }
+    }
+
     fn handle_select(&self, query: SelectQuery) -> Result<ExecutionResult> {
         if query.join.is_some() {
             return Err(anyhow!("Joins are not yet implemented"));
@@ -276,57 +425,50 @@ impl Executor {
         let db_id = self.get_db_id(&query.database)?;
         
         // 1. Load Catalog
-        let catalog_page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: 1 })?;
-        let catalog_guard = catalog_page.read();
-        let catalog = Catalog::from_bytes(&catalog_guard.data)?;
+        let catalog = Catalog::load(&self.buffer_pool, db_id)?;
         
         let table_info = catalog.get_table(&query.from)
             .ok_or(anyhow!("Table {} not found", query.from))?;
             
+        let codec = self.codec(table_info, db_id)?;
         let mut results = Vec::new();
         
         // Check for Index Scan
         let pk_col = table_info.columns.iter().find(|c| c.primary_key);
         let mut index_scan = false;
         
-        if let Some(pk) = pk_col {
-            if let Some(where_clause) = &query.r#where {
-                if where_clause.column == pk.name && where_clause.cmp == "=" {
-                    if let Some(int_val) = where_clause.value.as_u64() {
-                        index_scan = true;
-                        let key = int_val as u32;
-                        let index = BTreeIndex::new(self.buffer_pool.clone(), db_id, table_info.index_root_page_id);
-                        if let Some((pid, sid)) = index.search(key)? {
-                            let page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: pid })?;
-                            let page_guard = page.read();
-                            #[allow(mutable_transmutes)]
-                            let page_mut_ref: &mut crate::storage::page::Page = unsafe { std::mem::transmute(&*page_guard) };
-                            let slotted = SlottedPage::new(page_mut_ref);
-                            
-                            if let Some(tuple_bytes) = slotted.get_tuple(sid) {
-                                if !tuple_bytes.is_empty() {
-                                    let val: Value = serde_json::from_slice(&tuple_bytes)?;
-                                // Filter (re-check just in case) and Project
-                                // ... (Simplified: assume match)
-                                
-                                // Project
-                                if query.columns.len() == 1 && query.columns[0] == "*" {
-                                    results.push(val);
-                                } else {
-                                    let mut projected = serde_json::Map::new();
-                                    for col in &query.columns {
-                                        if let Some(v) = val.get(col) {
-                                            projected.insert(col.clone(), v.clone());
-                                        }
-                                    }
-                                    results.push(Value::Object(projected));
-                                }
+        if let Some(pk) = pk_col
+            && let Some(where_clause) = &query.r#where
+            && where_clause.column == pk.name && where_clause.cmp == "="
+            && let Some(int_val) = where_clause.value.as_u64() {
+                index_scan = true;
+                let key = int_val as u32;
+                let index = BTreeIndex::new(self.buffer_pool.clone(), db_id, table_info.index_root_page_id)?;
+                if let Some((pid, sid)) = index.search(key)? {
+                    let page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: pid })?;
+                    let page_guard = page.read();
+                    let slotted = SlottedPage::read_only(&page_guard, &self.buffer_pool, db_id).with_codec(&codec);
+                    
+                    // Re-check the key, so an entry that went stale can never
+                    // return another row
+                    if let Some(tuple_bytes) = slotted.get_tuple(sid)?
+                        && !tuple_bytes.is_empty()
+                        && let val = serde_json::from_slice::<Value>(&tuple_bytes)?
+                        && Self::check_filter(&val, where_clause) {
+                        // Project
+                        if query.columns.len() == 1 && query.columns[0] == "*" {
+                            results.push(val);
+                        } else {
+                            let mut projected = serde_json::Map::new();
+                            for col in &query.columns {
+                                if let Some(v) = val.get(col) {
+                                    projected.insert(col.clone(), v.clone());
                                 }
                             }
+                            results.push(Value::Object(projected));
                         }
                     }
                 }
-            }
         }
         
             
@@ -335,15 +477,13 @@ impl Executor {
             let mut current_page_id = table_info.root_page_id;
             
             while current_page_id != 0 {
-                let page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: current_page_id })?;
+                let page = self.buffer_pool.fetch_chain_page(GlobalPageId { db_id, page_id: current_page_id }, slotted::next_page_id)?;
                 let page_guard = page.read();
-                #[allow(mutable_transmutes)]
-                let page_mut_ref: &mut crate::storage::page::Page = unsafe { std::mem::transmute(&*page_guard) };
-                let slotted = SlottedPage::new(page_mut_ref);
+                let slotted = SlottedPage::read_only(&page_guard, &self.buffer_pool, db_id).with_codec(&codec);
                 
                 let num_slots = slotted.num_slots();
                 for i in 0..num_slots {
-                    if let Some(tuple_bytes) = slotted.get_tuple(i) {
+                    if let Some(tuple_bytes) = slotted.get_tuple(i)? {
                         if tuple_bytes.is_empty() { continue; }
                         let val: Value = serde_json::from_slice(&tuple_bytes)?;
                         
@@ -416,24 +556,36 @@ impl Executor {
     fn handle_update(&self, query: UpdateQuery) -> Result<ExecutionResult> {
         let db_id = self.get_db_id(&query.database)?;
         
-        let catalog_page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: 1 })?;
-        let catalog_guard = catalog_page.read();
-        let catalog = Catalog::from_bytes(&catalog_guard.data)?;
+        let mut catalog = Catalog::load(&self.buffer_pool, db_id)?;
+        let mut fsm = self.free_space_map(&mut catalog, &query.table, db_id)?;
         
         let table_info = catalog.get_table(&query.table)
             .ok_or(anyhow!("Table {} not found", query.table))?;
             
+        let pk_col = table_info.columns.iter().find(|c| c.primary_key);
+        let index = BTreeIndex::new(self.buffer_pool.clone(), db_id, table_info.index_root_page_id)?;
+        let key_of = |val: &Value| pk_col.and_then(|pk| val.get(&pk.name)).and_then(|v| v.as_u64()).map(|key| key as u32);
+        
+        let codec = self.codec(table_info, db_id)?;
         let mut current_page_id = table_info.root_page_id;
         let mut updated_count = 0;
+        // Rows moved to another page, which the scan may reach again
+        let mut moved_to = HashSet::new();
         
         while current_page_id != 0 {
-            let page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: current_page_id })?;
+            let page = self.buffer_pool.fetch_chain_page(GlobalPageId { db_id, page_id: current_page_id }, slotted::next_page_id)?;
             let mut page_guard = page.write();
-            let mut slotted = SlottedPage::new(&mut page_guard);
+            let mut slotted = SlottedPage::with_overflow(&mut page_guard, &self.buffer_pool, db_id).with_codec(&codec);
+            // Old key, new key, slot and where the row is now
+            let mut changed_keys = Vec::new();
+            let mut grown = Vec::new();
             
             let num_slots = slotted.num_slots();
             for i in 0..num_slots {
-                if let Some(tuple_bytes) = slotted.get_tuple(i) {
+                if moved_to.contains(&(current_page_id, i)) {
+                    continue;
+                }
+                if let Some(tuple_bytes) = slotted.get_tuple(i)? {
                     if tuple_bytes.is_empty() { continue; }
                     let mut val: Value = serde_json::from_slice(&tuple_bytes)?;
                     
@@ -444,6 +596,7 @@ impl Executor {
                     }
                     
                     if match_filter {
+                        let old_key = key_of(&val);
                         // Update values
                         if let Value::Object(ref mut map) = val {
                             for (k, v) in &query.set {
@@ -452,13 +605,40 @@ impl Executor {
                         }
                         
                         let new_bytes = serde_json::to_vec(&val)?;
-                        slotted.update_tuple(i, &new_bytes)?;
+                        let new_key = key_of(&val);
+                        if !slotted.update_tuple(i, &new_bytes)? {
+                            // The row grew past the room left on its page, so it moves
+                            slotted.mark_deleted(i)?;
+                            grown.push((old_key, new_key, i, new_bytes));
+                        } else if new_key != old_key {
+                            changed_keys.push((old_key, new_key, i, (current_page_id, i)));
+                        }
                         updated_count += 1;
                     }
                 }
             }
-            current_page_id = slotted.next_page_id();
+            fsm.record(current_page_id, slotted.available_space());
+            let next_page_id = slotted.next_page_id();
+            drop(page_guard);
+            
+            for (old_key, new_key, slot_id, tuple_data) in grown {
+                let location = self.insert_tuple(db_id, &mut fsm, &codec, &tuple_data)?;
+                moved_to.insert(location);
+                changed_keys.push((old_key, new_key, slot_id, location));
+            }
+            // Point the index at moved rows and at the new keys of changed ones
+            for (old_key, new_key, slot_id, location) in changed_keys {
+                if let Some(old_key) = old_key
+                    && index.search(old_key)? == Some((current_page_id, slot_id)) {
+                        index.delete(old_key)?;
+                }
+                if let Some(new_key) = new_key {
+                    index.insert(new_key, location)?;
+                }
+            }
+            current_page_id = next_page_id;
         }
+        fsm.save(&self.buffer_pool)?;
         
         Ok(ExecutionResult::Message(format!("Updated {} rows", updated_count)))
     }
@@ -466,24 +646,32 @@ impl Executor {
     fn handle_delete(&self, query: DeleteQuery) -> Result<ExecutionResult> {
         let db_id = self.get_db_id(&query.database)?;
         
-        let catalog_page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: 1 })?;
-        let catalog_guard = catalog_page.read();
-        let catalog = Catalog::from_bytes(&catalog_guard.data)?;
+        let catalog = Catalog::load(&self.buffer_pool, db_id)?;
         
         let table_info = catalog.get_table(&query.table)
             .ok_or(anyhow!("Table {} not found", query.table))?;
             
+        let pk_col = table_info.columns.iter().find(|c| c.primary_key);
+        let index = BTreeIndex::new(self.buffer_pool.clone(), db_id, table_info.index_root_page_id)?;
+        
+        let codec = self.codec(table_info, db_id)?;
+        let mut fsm = match table_info.fsm_root_page_id {
+            0 => None,
+            root_page_id => Some(FreeSpaceMap::load(&self.buffer_pool, db_id, root_page_id)?),
+        };
         let mut current_page_id = table_info.root_page_id;
+        let mut prev_page_id = 0;
         let mut deleted_count = 0;
         
         while current_page_id != 0 {
-            let page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: current_page_id })?;
+            let page = self.buffer_pool.fetch_chain_page(GlobalPageId { db_id, page_id: current_page_id }, slotted::next_page_id)?;
             let mut page_guard = page.write();
-            let mut slotted = SlottedPage::new(&mut page_guard);
+            let mut slotted = SlottedPage::with_overflow(&mut page_guard, &self.buffer_pool, db_id).with_codec(&codec);
+            let mut deleted_keys = Vec::new();
             
             let num_slots = slotted.num_slots();
             for i in 0..num_slots {
-                if let Some(tuple_bytes) = slotted.get_tuple(i) {
+                if let Some(tuple_bytes) = slotted.get_tuple(i)? {
                     if tuple_bytes.is_empty() { continue; }
                     let val: Value = serde_json::from_slice(&tuple_bytes)?;
                     
@@ -496,12 +684,245 @@ impl Executor {
                     if match_filter {
                         slotted.mark_deleted(i)?;
                         deleted_count += 1;
+                        if let Some(pk) = pk_col
+                            && let Some(key) = val.get(&pk.name).and_then(|v| v.as_u64()) {
+                                deleted_keys.push((key as u32, i));
+                        }
                     }
                 }
             }
-            current_page_id = slotted.next_page_id();
+            let next_page_id = slotted.next_page_id();
+            let emptied = slotted.is_empty();
+            let available = slotted.available_space();
+            drop(page_guard);
+            
+            // Only drop index entries that point at the deleted rows
+            for (key, slot_id) in deleted_keys {
+                if index.search(key)? == Some((current_page_id, slot_id)) {
+                    index.delete(key)?;
+                }
+            }
+            
+            // Unlink emptied pages from the chain and free them. The root page stays.
+            if emptied && current_page_id != table_info.root_page_id {
+                let prev_page = self.buffer_pool.fetch_page(GlobalPageId { db_id, page_id: prev_page_id })?;
+                SlottedPage::new(&mut prev_page.write()).set_next_page_id(next_page_id);
+                self.buffer_pool.free_page(db_id, current_page_id)?;
+                if let Some(fsm) = fsm.as_mut() {
+                    fsm.remove(current_page_id);
+                    if fsm.tail_page_id() == current_page_id {
+                        fsm.set_tail_page_id(prev_page_id);
+                    }
+                }
+            } else {
+                if let Some(fsm) = fsm.as_mut() {
+                    fsm.record(current_page_id, available);
+                }
+                prev_page_id = current_page_id;
+            }
+            current_page_id = next_page_id;
+        }
+        if let Some(fsm) = fsm.as_mut() {
+            fsm.save(&self.buffer_pool)?;
         }
         
         Ok(ExecutionResult::Message(format!("Deleted {} rows", deleted_count)))
     }
+
+    fn handle_vacuum(&self, query: VacuumQuery) -> Result<ExecutionResult> {
+        let db_id = self.get_db_id(&query.database)?;
+        let mut catalog = Catalog::load(&self.buffer_pool, db_id)?;
+        let mut tables: Vec<String> = match query.table {
+            Some(table) if catalog.tables.contains_key(&table) => vec![table],
+            Some(table) => return Err(anyhow!("Table {} not found", table)),
+            None => catalog.tables.keys().cloned().collect(),
+        };
+        tables.sort();
+
+        let mut vacuumed = 0;
+        let mut totals = VacuumStats::default();
+        for table in tables {
+            let table_info = catalog.tables.get_mut(&table)
+                .ok_or(anyhow!("Table {} not found", table))?;
+            if let Some(percent) = query.min_free_percent {
+                // Only worth it when the free space adds up to a page or more
+                let (pages, ratio) = match table_info.fsm_root_page_id {
+                    0 => continue,
+                    root_page_id => {
+                        let fsm = FreeSpaceMap::load(&self.buffer_pool, db_id, root_page_id)?;
+                        (fsm.page_count(), fsm.free_ratio())
+                    }
+                };
+                if ratio * 100.0 < percent as f64 || ratio * (pages as f64) < 1.0 {
+                    continue;
+                }
+            }
+            let codec = self.codec(table_info, db_id)?;
+            let stats = vacuum::vacuum_table(&self.buffer_pool, db_id, table_info, &codec)?;
+            self.forget_codec(db_id, &table);
+            totals.rows_moved += stats.rows_moved;
+            totals.pages_freed += stats.pages_freed;
+            vacuumed += 1;
+        }
+        if vacuumed > 0 {
+            catalog.save(&self.buffer_pool, db_id)?;
+        }
+
+        Ok(ExecutionResult::Json(json!({
+            "tables": vacuumed,
+            "rows_moved": totals.rows_moved,
+            "pages_freed": totals.pages_freed,
+        })))
+    }
+
+    fn handle_backup(&self, query: BackupQuery) -> Result<ExecutionResult> {
+        let db_id = self.get_db_id(&query.database)?;
+        let pager = self.buffer_pool.pager(db_id)?;
+        let root = self.backup_dir.as_ref().ok_or(anyhow!("No backup directory is configured on this server"))?;
+        let dest = backup::destination_under(root, &query.destination)?;
+        let manifest = backup::backup(&pager, &dest, query.incremental)?;
+        Ok(ExecutionResult::Json(serde_json::to_value(manifest)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::pager::Pager;
+    use crate::query::test_util::{create, run};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_tables_use_their_codec() {
+        let temp_dir = TempDir::new().unwrap();
+        let (_, _, executor) = create(temp_dir.path());
+        let executor = executor.with_compression_threshold(16);
+
+        let row = |id: u64| json!({"id": id, "status": "active", "email": format!("user{}@example.com", id)});
+        let samples: Vec<Value> = (0..1000).map(row).collect();
+        let tables = [
+            ("plain", json!({"codec": "none"})),
+            ("fast", json!({"codec": "lz4"})),
+            ("trained", json!({"codec": "zstd", "level": 3, "dictionary_samples": samples})),
+        ];
+        for (table, compression) in &tables {
+            run(&executor, json!({"op": "create_table", "database": "main", "table": table, "compression": compression,
+                "columns": [{"name": "id", "type": "int", "primary_key": true}]}));
+            run(&executor, json!({"op": "insert", "database": "main", "table": table,
+                "values": (0..100).map(row).collect::<Vec<_>>()}));
+            let ExecutionResult::Json(Value::Array(found)) = run(&executor, json!({"op": "select", "database": "main",
+                "from": table, "columns": ["*"], "where": {"column": "id", "cmp": "=", "value": 42}})) else { panic!() };
+            assert_eq!(found, vec![row(42)]);
+        }
+
+        let create = |compression: Value| executor.execute(serde_json::from_value(json!({"op": "create_table",
+            "database": "main", "table": "bad", "compression": compression, "columns": []})).unwrap());
+        assert!(create(json!({"codec": "lz4", "dictionary_samples": [{"a": 1}]})).is_err());
+        assert!(create(json!({"codec": "zstd", "level": 100})).is_err());
+
+        // The dictionary is prepared once, and forgotten with its table
+        assert_eq!(executor.codecs.lock().len(), 1);
+        run(&executor, json!({"op": "drop_table", "database": "main", "table": "trained"}));
+        assert!(executor.codecs.lock().is_empty());
+    }
+
+    #[test]
+    fn test_inserts_reuse_space_through_free_space_map() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("main.db");
+        let (_, _, executor) = create(temp_dir.path());
+
+        run(&executor, json!({"op": "create_table", "database": "main", "table": "t",
+            "columns": [{"name": "id", "type": "int", "primary_key": true}, {"name": "body", "type": "string"}]}));
+        let rows = |ids: std::ops::Range<u64>| json!({"op": "insert", "database": "main", "table": "t",
+            "values": ids.map(|id| json!({"id": id, "body": format!("{:0>200}", id)})).collect::<Vec<_>>()});
+        run(&executor, rows(0..400));
+        let file_size = std::fs::metadata(&db_path).unwrap().len();
+
+        // Every other row deleted leaves room on every page, which new rows fill
+        // instead of growing the table
+        let even: Vec<u64> = (0..400).step_by(2).collect();
+        run(&executor, json!({"op": "delete", "database": "main", "table": "t",
+            "where": {"column": "id", "cmp": "IN", "value": even}}));
+        run(&executor, rows(400..600));
+        assert_eq!(std::fs::metadata(&db_path).unwrap().len(), file_size);
+
+        let ExecutionResult::Json(Value::Array(found)) = run(&executor, json!({"op": "select", "database": "main",
+            "from": "t", "columns": ["*"]})) else { panic!("select returned no rows") };
+        assert_eq!(found.len(), 400);
+        let ExecutionResult::Json(Value::Array(found)) = run(&executor, json!({"op": "select", "database": "main",
+            "from": "t", "columns": ["*"], "where": {"column": "id", "cmp": "=", "value": 599}})) else { panic!() };
+        assert_eq!(found[0]["body"], json!(format!("{:0>200}", 599)));
+    }
+
+    #[test]
+    fn test_updates_move_index_entries_with_the_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let (_, pool, executor) = create(temp_dir.path());
+
+        run(&executor, json!({"op": "create_table", "database": "main", "table": "t",
+            "columns": [{"name": "id", "type": "int", "primary_key": true}]}));
+        run(&executor, json!({"op": "insert", "database": "main", "table": "t", "values": [{"id": 1}, {"id": 2}]}));
+        run(&executor, json!({"op": "update", "database": "main", "table": "t", "set": {"id": 10},
+            "where": {"column": "id", "cmp": "=", "value": 1}}));
+
+        let find = |id: u64| match run(&executor, json!({"op": "select", "database": "main", "from": "t",
+            "columns": ["*"], "where": {"column": "id", "cmp": "=", "value": id}})) {
+            ExecutionResult::Json(Value::Array(found)) => found,
+            _ => panic!("select returned no rows"),
+        };
+        assert_eq!(find(10), vec![json!({"id": 10})]);
+        assert!(find(1).is_empty());
+        let root = Catalog::load(&pool, 0).unwrap().get_table("t").unwrap().index_root_page_id;
+        let (_, entries) = BTreeIndex::new(pool, 0, root).unwrap().walk().unwrap();
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[test]
+    fn test_rows_that_outgrow_their_page_move() {
+        let temp_dir = TempDir::new().unwrap();
+        let (_, _, executor) = create(temp_dir.path());
+
+        run(&executor, json!({"op": "create_table", "database": "main", "table": "t", "compression": {"codec": "none"},
+            "columns": [{"name": "id", "type": "int", "primary_key": true}, {"name": "body", "type": "string"}]}));
+        run(&executor, json!({"op": "insert", "database": "main", "table": "t",
+            "values": (0..60).map(|id| json!({"id": id, "body": "x".repeat(50)})).collect::<Vec<_>>()}));
+        // Every row grows fivefold, far past the room left on the pages
+        run(&executor, json!({"op": "update", "database": "main", "table": "t", "set": {"body": "y".repeat(250)}}));
+
+        let select = |r#where: Option<Value>| match run(&executor, json!({"op": "select", "database": "main",
+            "from": "t", "columns": ["*"], "where": r#where})) {
+            ExecutionResult::Json(Value::Array(found)) => found,
+            _ => panic!("select returned no rows"),
+        };
+        let rows = select(None);
+        assert_eq!(rows.len(), 60);
+        assert!(rows.iter().all(|row| row["body"] == json!("y".repeat(250))));
+        for id in 0..60 {
+            let found = select(Some(json!({"column": "id", "cmp": "=", "value": id})));
+            assert_eq!(found, vec![json!({"id": id, "body": "y".repeat(250)})]);
+        }
+    }
+
+    #[test]
+    fn test_batches_write_to_one_database() {
+        let temp_dir = TempDir::new().unwrap();
+        let (_, pool, executor) = create(temp_dir.path());
+        let other = Pager::create_database(&temp_dir.path().join("other.db"), "other", 4096, None).unwrap();
+        pool.register_pager(database_id("other"), Arc::new(other));
+        let create_table = |database: &str, table: &str| serde_json::from_value(json!({"op": "create_table",
+            "database": database, "table": table, "columns": [{"name": "id", "type": "int", "primary_key": true}]})).unwrap();
+        let select = |database: &str, table: &str| serde_json::from_value(json!({"op": "select",
+            "database": database, "from": table, "columns": ["*"]})).unwrap();
+        executor.execute(create_table("other", "b")).unwrap();
+
+        // Nothing of a batch that writes to two databases runs
+        assert!(executor.execute(Query::Batch(vec![create_table("main", "a"), create_table("other", "c")])).is_err());
+        assert!(executor.execute(select("main", "a")).is_err());
+
+        // Reading another database is fine
+        executor.execute(Query::Batch(vec![create_table("main", "a"), select("other", "b")])).unwrap();
+        executor.execute(select("main", "a")).unwrap();
+    }
 }
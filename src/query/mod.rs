@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
+use crate::storage::codec::Codec;
 
 pub mod executor;
+#[cfg(test)]
+pub mod test_util;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "op", rename_all = "snake_case")]
@@ -11,6 +14,8 @@ pub enum Query {
     Update(UpdateQuery),
     Delete(DeleteQuery),
     DropTable(DropTableQuery),
+    Vacuum(VacuumQuery),
+    Backup(BackupQuery),
     Batch(Vec<Query>),
 }
 
@@ -23,6 +28,8 @@ impl Query {
             Query::Select(q) => &q.database,
             Query::Update(q) => &q.database,
             Query::Delete(q) => &q.database,
+            Query::Vacuum(q) => &q.database,
+            Query::Backup(q) => &q.database,
             Query::Batch(queries) => {
                 if let Some(first) = queries.first() {
                     first.get_database_name()
@@ -32,6 +39,34 @@ impl Query {
             }
         }
     }
+
+    /// True when executing the query cannot modify any page.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            // A backup only reads, and waits for the active transaction itself
+            Query::Select(_) | Query::Backup(_) => true,
+            Query::Batch(queries) => queries.iter().all(|q| q.is_read_only()),
+            _ => false,
+        }
+    }
+
+    /// True when the query vacuums, after which the database files are shrunk.
+    pub fn vacuums(&self) -> bool {
+        match self {
+            Query::Vacuum(_) => true,
+            Query::Batch(queries) => queries.iter().any(|q| q.vacuums()),
+            _ => false,
+        }
+    }
+
+    /// Every database the query may write to, including all members of a batch.
+    pub fn written_database_names(&self) -> Vec<&str> {
+        match self {
+            Query::Batch(queries) => queries.iter().flat_map(|q| q.written_database_names()).collect(),
+            _ if self.is_read_only() => Vec::new(),
+            _ => vec![self.get_database_name()],
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,6 +74,19 @@ pub struct CreateTableQuery {
     pub database: String,
     pub table: String,
     pub columns: Vec<ColumnDef>,
+    #[serde(default)]
+    pub compression: Option<CompressionOptions>,
+}
+
+/// Tuple compression of a new table, e.g. `{"codec": "zstd", "level": 3}`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CompressionOptions {
+    #[serde(flatten)]
+    pub codec: Codec,
+    /// Rows to train a zstd dictionary from, for small rows that barely
+    /// compress on their own
+    #[serde(default)]
+    pub dictionary_samples: Vec<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -94,6 +142,28 @@ pub struct DeleteQuery {
     pub r#where: Option<WhereClause>,
 }
 
+/// Compacts one table, or every table of the database, and shrinks the file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VacuumQuery {
+    pub database: String,
+    #[serde(default)]
+    pub table: Option<String>,
+    /// Skips tables with less of their heap space free than this
+    #[serde(default)]
+    pub min_free_percent: Option<u8>,
+}
+
+/// Copies the database into the directory `destination` on the server while
+/// it keeps taking writes. An incremental backup holds only the pages changed
+/// since the previous backup.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupQuery {
+    pub database: String,
+    pub destination: String,
+    #[serde(default)]
+    pub incremental: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WhereClause {
     pub column: String,
@@ -0,0 +1,47 @@
+use crate::query::executor::{Executor, ExecutionResult};
+use crate::storage::buffer::BufferPool;
+use crate::storage::pager::Pager;
+use serde_json::{Value, json};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Runs a query given as JSON and panics if it fails.
+pub fn run(executor: &Executor, query: Value) -> ExecutionResult {
+    executor.execute(serde_json::from_value(query).unwrap()).unwrap()
+}
+
+/// Creates database "main" as `main.db` in `dir`, behind a buffer pool of its own.
+pub fn create(dir: &Path) -> (Arc<Pager>, Arc<BufferPool>, Executor) {
+    let pager = Arc::new(Pager::create_database(&dir.join("main.db"), "main", 4096, None).unwrap());
+    let pool = Arc::new(BufferPool::new(64));
+    pool.register_pager(0, pager.clone());
+    (pager, pool.clone(), Executor::new(pool))
+}
+
+/// Opens an existing database as "main" behind a buffer pool of its own.
+pub fn open(path: &Path) -> (Arc<Pager>, Executor) {
+    let pager = Arc::new(Pager::open(path).unwrap());
+    let pool = Arc::new(BufferPool::new(64));
+    pool.register_pager(0, pager.clone());
+    (pager, Executor::new(pool))
+}
+
+/// Inserts rows with the given IDs and a 200 byte body into table "t".
+pub fn insert(executor: &Executor, ids: Range<i64>) {
+    let rows: Vec<Value> = ids.map(|id| json!({"id": id, "body": "x".repeat(200)})).collect();
+    run(executor, json!({"op": "insert", "database": "main", "table": "t", "values": rows}));
+}
+
+/// The IDs of the rows in table "t".
+pub fn ids(executor: &Executor) -> Vec<u64> {
+    match run(executor, json!({"op": "select", "database": "main", "from": "t", "columns": ["id"]})) {
+        ExecutionResult::Json(Value::Array(rows)) => rows.iter().map(|row| row["id"].as_u64().unwrap()).collect(),
+        _ => panic!(),
+    }
+}
+
+/// The number of rows in table "t".
+pub fn count_rows(executor: &Executor) -> usize {
+    ids(executor).len()
+}
@@ -13,6 +13,9 @@ pub struct AppState {
     pub logger: Arc<Logger>,
 }
 
+/// Serves requests until SIGINT or SIGTERM. New connections are refused from
+/// then on and in-flight requests get up to `server.shutdown_timeout_secs` to
+/// finish before this returns; flushing storage is up to the caller.
 pub async fn run_server(config: RdbConfig, executor: Arc<Executor>, auth: Arc<AuthManager>, logger: Arc<Logger>) -> std::io::Result<()> {
     let bind_addr = format!("{}:{}", config.server.host, config.server.port);
     let _ = logger.info(format!("Starting RDB Server at http://{}", bind_addr));
@@ -32,6 +35,7 @@ pub async fn run_server(config: RdbConfig, executor: Arc<Executor>, auth: Arc<Au
             .route("/query", web::post().to(query_handler))
             .route("/login", web::post().to(login_handler))
     })
+    .shutdown_timeout(config.server.shutdown_timeout_secs)
     .bind(bind_addr)?
     .run()
     .await
@@ -74,8 +78,7 @@ async fn query_handler(
         let auth_header = req.headers().get("Authorization");
         if let Some(header_val) = auth_header {
             if let Ok(header_str) = header_val.to_str() {
-                if header_str.starts_with("Bearer ") {
-                    let token = &header_str[7..];
+                if let Some(token) = header_str.strip_prefix("Bearer ") {
                     // Verify token and permissions
                     // We need database name from query to check ACL
                     let db_name = query.get_database_name();
@@ -92,6 +95,7 @@ async fn query_handler(
                                 crate::auth::Role::ReadWrite
                             }
                         },
+                        Query::Backup(_) => crate::auth::Role::DbAdmin,
                         _ => crate::auth::Role::ReadWrite,
                     };
                     
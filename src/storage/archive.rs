@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::storage::wal::{self, LogRecord};
+use anyhow::{Result, anyhow};
+
+// With `storage.wal_archive_dir` set, each database's log is copied into
+// `<archive>/<name>/` before a checkpoint empties it. Every segment is named
+// after the LSN of its first record, and each one starts where the one before
+// it ended, so replaying them in order redoes every committed transaction.
+
+/// What replaying an archived log did.
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub segments: usize,
+    pub transactions: usize,
+    /// Commit time of the last transaction replayed, in ms since the epoch
+    pub last_commit_ms: Option<i64>,
+    /// The archive ran out before the requested time
+    pub reached_end: bool,
+}
+
+/// Directory holding the archived log of `database`.
+pub fn segment_dir(archive: &Path, database: &str) -> PathBuf {
+    archive.join(database)
+}
+
+// Archived segments with their first LSN, in log order
+fn segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| anyhow!("Cannot read the log archive {:?}: {}", dir, e))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "wal")
+            && let Some(first_lsn) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) {
+                segments.push((first_lsn, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Replays the archived log in `dir` onto the database file at `path`,
+/// starting at LSN `from_lsn`. Transactions committed after `until_ms` (ms
+/// since the epoch) and everything logged after them are left out.
+pub fn replay(dir: &Path, from_lsn: u64, until_ms: i64, path: &Path, page_size: usize) -> Result<ReplayReport> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let mut report = ReplayReport::default();
+    let mut next_lsn = from_lsn;
+
+    for (first_lsn, segment) in segments(dir)?.into_iter().filter(|(first_lsn, _)| *first_lsn >= from_lsn) {
+        if first_lsn != next_lsn {
+            return Err(anyhow!("The log archive {:?} is missing records {} to {}", dir, next_lsn, first_lsn - 1));
+        }
+        let (records, end_lsn) = wal::read_segment(&segment, page_size)?;
+        report.segments += 1;
+
+        // Pages are only written once their transaction turns out to have
+        // committed in time, after the cut the transaction made, if any
+        let mut frames: HashMap<u64, Vec<(u32, Vec<u8>)>> = HashMap::new();
+        let mut truncates: HashMap<u64, u32> = HashMap::new();
+        for (_, record) in records {
+            match record {
+                LogRecord::Page { txn_id, page_id, data } => frames.entry(txn_id).or_default().push((page_id, data)),
+                LogRecord::Commit { txn_id, at_ms } => {
+                    if at_ms > until_ms {
+                        file.sync_all()?;
+                        return Ok(report);
+                    }
+                    if let Some(pages) = truncates.remove(&txn_id) {
+                        file.set_len(pages as u64 * page_size as u64)?;
+                    }
+                    if let Some(pages) = frames.remove(&txn_id) {
+                        for (page_id, data) in pages {
+                            file.seek(SeekFrom::Start(page_id as u64 * page_size as u64))?;
+                            file.write_all(&data)?;
+                        }
+                        report.transactions += 1;
+                        report.last_commit_ms = Some(at_ms);
+                    }
+                }
+                LogRecord::Abort { txn_id } => {
+                    frames.remove(&txn_id);
+                    truncates.remove(&txn_id);
+                }
+                LogRecord::Truncate { txn_id, pages } => {
+                    truncates.insert(txn_id, pages);
+                }
+            }
+        }
+        next_lsn = end_lsn;
+    }
+
+    file.sync_all()?;
+    report.reached_end = true;
+    Ok(report)
+}
+
+/// Moves the archived log of `database` aside after a restore or upgrade
+/// replaced the database: its new log starts over and must not be mixed up
+/// with the old one. Returns where the old archive went.
+pub fn retire(archive: &Path, database: &str) -> Result<Option<PathBuf>> {
+    let dir = segment_dir(archive, database);
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let retired = archive.join(format!("{}.replaced-{}", database, chrono::Utc::now().timestamp()));
+    std::fs::rename(&dir, &retired)?;
+    Ok(Some(retired))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::executor::Executor;
+    use crate::storage::backup;
+    use crate::storage::buffer::BufferPool;
+    use crate::storage::check;
+    use crate::storage::pager::Pager;
+    use crate::query::test_util::{ids, insert, open, run};
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_restore_until_replays_archived_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("main.db");
+        let archive = temp_dir.path().join("archive");
+        drop(Pager::create_database(&path, "main", 4096, None).unwrap());
+        let pager = Arc::new(Pager::open_with_archive(&path, None, Some(&archive)).unwrap());
+        let pool = Arc::new(BufferPool::new(64));
+        pool.register_pager(0, pager.clone());
+        let executor = Executor::new(pool.clone());
+
+        run(&executor, json!({"op": "create_table", "database": "main", "table": "t", "columns": [{"name": "id", "type": "int", "primary_key": true}]}));
+        insert(&executor, 0..50);
+        let full = backup::backup(&pager, &temp_dir.path().join("full"), false).unwrap();
+
+        // Changes after the backup, including a vacuum that shrinks the file
+        std::thread::sleep(Duration::from_millis(1100));
+        insert(&executor, 50..100);
+        run(&executor, json!({"op": "delete", "database": "main", "table": "t", "where": {"column": "id", "cmp": ">=", "value": 80}}));
+        run(&executor, json!({"op": "vacuum", "database": "main"}));
+        std::thread::sleep(Duration::from_millis(20));
+        let before_mistake = chrono::Utc::now().timestamp_millis();
+        std::thread::sleep(Duration::from_millis(20));
+        run(&executor, json!({"op": "delete", "database": "main", "table": "t"}));
+        pool.shutdown().unwrap();
+        drop((executor, pool, pager));
+
+        let restored = temp_dir.path().join("restored.db");
+        let dir = segment_dir(&archive, "main");
+        let (_, report) = backup::restore_until(&[temp_dir.path().join("full")], &restored, None, &dir, before_mistake).unwrap();
+        assert!(report.transactions > 0);
+        assert!(!report.reached_end);
+        let check = check::check_database(&restored, None, None).unwrap();
+        assert!(check.is_clean(), "{:?}", check.problems);
+        let mut found = ids(&open(&restored).1);
+        found.sort();
+        assert_eq!(found, (0..80).collect::<Vec<u64>>());
+
+        // A missing segment is noticed instead of skipped
+        let first = format!("{:020}.wal", full.wal_lsn.unwrap());
+        std::fs::remove_file(dir.join(first)).unwrap();
+        assert!(backup::restore_until(&[temp_dir.path().join("full")], &restored, None, &dir, before_mistake).is_err());
+    }
+}
@@ -0,0 +1,373 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::storage::archive::{self, ReplayReport};
+use crate::storage::changes::ChangeMap;
+use crate::storage::crypto::EncryptionKey;
+use crate::storage::header::CURRENT_FILE_FORMAT_VERSION;
+use crate::storage::pager::Pager;
+use crate::storage::wal::Wal;
+use anyhow::{Result, anyhow};
+
+// A backup is a directory holding a copy of the database file, header
+// included, and this manifest describing it. An incremental backup holds only
+// the pages changed since the backup before it, each preceded by its ID.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupManifest {
+    #[serde(default)]
+    pub id: String,
+    pub database: String,
+    /// Name of the database file, or of an incremental backup's pages,
+    /// within the backup directory
+    pub file: String,
+    pub file_format_version: u32,
+    pub page_size: usize,
+    /// Page count of the database file
+    pub pages: u32,
+    /// Backup an incremental backup applies on top of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    /// How many pages an incremental backup holds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed_pages: Option<u32>,
+    /// First log record the backup doesn't hold; archived logs are replayed
+    /// from here
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wal_lsn: Option<u64>,
+    pub encrypted: bool,
+    pub engine_version: String,
+    pub created_at: i64,
+    /// SHA-256 of the backup's file, hex encoded
+    pub sha256: String,
+}
+
+// Hashes everything written through it
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Backs a database up into the directory `dest` while it stays open for
+/// writes. The copy holds every transaction committed before it started. An
+/// incremental backup holds what changed since the database's last backup.
+pub fn backup(pager: &Pager, dest: &Path, incremental: bool) -> Result<BackupManifest> {
+    if dest.join(MANIFEST_FILE).exists() {
+        return Err(anyhow!("{:?} already holds a backup", dest));
+    }
+    fs::create_dir_all(dest)?;
+    let file_name = format!("{}.{}", pager.name(), if incremental { "pages" } else { "db" });
+    let path = dest.join(&file_name);
+
+    let mut writer = HashingWriter { inner: BufWriter::new(File::create(&path)?), hasher: Sha256::new() };
+    let snapshot = match pager.snapshot_to(incremental, &mut writer) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            drop(writer);
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+    };
+
+    let id = Uuid::new_v4();
+    let stored = (|| {
+        let HashingWriter { inner, hasher } = writer;
+        inner.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        let header = pager.read_header()?;
+        let manifest = BackupManifest {
+            id: id.to_string(),
+            database: header.database_name,
+            file: file_name,
+            file_format_version: header.file_format_version,
+            page_size: header.page_size as usize,
+            pages: snapshot.pages,
+            base: snapshot.base.map(|base| base.to_string()),
+            changed_pages: incremental.then_some(snapshot.changed.len() as u32),
+            wal_lsn: snapshot.wal_lsn,
+            encrypted: header.encryption,
+            engine_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: chrono::Utc::now().timestamp(),
+            sha256: hex::encode(hasher.finalize()),
+        };
+        // Written last, so a directory without a manifest is an unfinished backup
+        let mut file = File::create(dest.join(MANIFEST_FILE))?;
+        file.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+        file.sync_all()?;
+        Ok(manifest)
+    })();
+    // Only a stored backup can be the base of the next incremental one
+    pager.finish_snapshot(stored.is_ok().then_some((id, &snapshot)))?;
+    stored
+}
+
+/// Resolves the destination of a backup query under `root`. Only relative
+/// paths that stay inside it are accepted, so a client can't have the server
+/// write anywhere else.
+pub fn destination_under(root: &Path, destination: &str) -> Result<PathBuf> {
+    let path = Path::new(destination);
+    let inside = path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !inside || !path.components().any(|c| matches!(c, Component::Normal(_))) {
+        return Err(anyhow!("Backup destination {:?} must be a relative path within the server's backup directory", destination));
+    }
+    Ok(root.join(path))
+}
+
+pub fn read_manifest(dir: &Path) -> Result<BackupManifest> {
+    let bytes = fs::read(dir.join(MANIFEST_FILE))
+        .map_err(|e| anyhow!("{:?} is not a complete backup: {}", dir, e))?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Checks a backup's file against the size and checksum in its manifest.
+pub fn verify(dir: &Path) -> Result<BackupManifest> {
+    let manifest = read_manifest(dir)?;
+    let mut file = File::open(dir.join(&manifest.file))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut len = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        len += read;
+    }
+    let expected = match manifest.changed_pages {
+        Some(changed) => changed as usize * (4 + manifest.page_size),
+        None => manifest.pages as usize * manifest.page_size,
+    };
+    if len != expected {
+        return Err(anyhow!("Backup of {} is damaged: {} bytes instead of {}",
+            manifest.database, len, expected));
+    }
+    if hex::encode(hasher.finalize()) != manifest.sha256 {
+        return Err(anyhow!("Backup of {} is damaged: checksum mismatch", manifest.database));
+    }
+    Ok(manifest)
+}
+
+/// Replaces the database file `target` with a full backup followed by the
+/// incremental backups taken after it, in order. Every backup is verified
+/// first. Nothing may have `target` open. Returns the last manifest.
+pub fn restore(dirs: &[PathBuf], target: &Path, key: Option<EncryptionKey>) -> Result<BackupManifest> {
+    let (staging, last) = stage(dirs, target)?;
+    replace(&staging, target, key)?;
+    // Later incremental backups continue the chain
+    ChangeMap::reset(target, Some(Uuid::parse_str(&last.id)?), last.pages)?;
+    Ok(last)
+}
+
+/// Restores like `restore`, then replays the database's log archived in
+/// `archive` up to the last transaction committed at or before `until_ms`
+/// (ms since the epoch).
+pub fn restore_until(dirs: &[PathBuf], target: &Path, key: Option<EncryptionKey>, archive: &Path, until_ms: i64) -> Result<(BackupManifest, ReplayReport)> {
+    let (staging, last) = stage(dirs, target)?;
+    let replayed = (|| {
+        let from_lsn = last.wal_lsn
+            .ok_or(anyhow!("Backup of {} was taken without a log, so there is nothing to replay onto it", last.database))?;
+        // The backup may hold commits from anywhere in the second it was taken
+        if until_ms < (last.created_at + 1) * 1000 {
+            return Err(anyhow!("Backup of {} was taken after the requested time; restore an older one", last.database));
+        }
+        archive::replay(archive, from_lsn, until_ms, &staging, last.page_size)
+    })();
+    let report = match replayed {
+        Ok(report) => report,
+        Err(e) => {
+            let _ = fs::remove_file(&staging);
+            return Err(e);
+        }
+    };
+    replace(&staging, target, key)?;
+    // Replayed pages aren't in any backup, so the next one must be full
+    ChangeMap::reset(target, None, 0)?;
+    Ok((last, report))
+}
+
+// Verifies a chain of backups and builds the database file from it next to
+// `target`. Returns the file and the last manifest.
+fn stage(dirs: &[PathBuf], target: &Path) -> Result<(PathBuf, BackupManifest)> {
+    let mut manifests: Vec<BackupManifest> = Vec::with_capacity(dirs.len());
+    for dir in dirs {
+        let manifest = verify(dir)?;
+        if manifest.file_format_version != CURRENT_FILE_FORMAT_VERSION {
+            return Err(anyhow!("Backup of {} has file format v{}, but this engine restores v{}",
+                manifest.database, manifest.file_format_version, CURRENT_FILE_FORMAT_VERSION));
+        }
+        match (manifests.last(), &manifest.base) {
+            (None, None) => {}
+            (None, Some(_)) => return Err(anyhow!("{:?} is an incremental backup; restore a full backup first", dir)),
+            (Some(_), None) => return Err(anyhow!("{:?} is a full backup; only incremental backups can follow one", dir)),
+            (Some(previous), Some(base)) if *base != previous.id || manifest.page_size != previous.page_size => {
+                return Err(anyhow!("{:?} does not apply on top of backup {} of {}", dir, previous.id, previous.database));
+            }
+            (Some(_), Some(_)) => {}
+        }
+        manifests.push(manifest);
+    }
+    let Some(last) = manifests.last().cloned() else {
+        return Err(anyhow!("No backup to restore"));
+    };
+
+    let staging = target.with_extension("db.restoring");
+    fs::copy(dirs[0].join(&manifests[0].file), &staging)?;
+    let mut file = OpenOptions::new().write(true).open(&staging)?;
+    for (dir, manifest) in dirs.iter().zip(&manifests).skip(1) {
+        apply_pages(dir, manifest, &mut file)?;
+    }
+    file.sync_all()?;
+    Ok((staging, last))
+}
+
+// Moves a staged database file into place
+fn replace(staging: &Path, target: &Path, key: Option<EncryptionKey>) -> Result<()> {
+    // A log left by the database being replaced would be replayed onto the backup
+    let wal_path = Wal::path_for(target);
+    if wal_path.exists() {
+        fs::remove_file(&wal_path)?;
+    }
+    fs::rename(staging, target)?;
+
+    // Opening checks the key, and closing clears the in-use flag the database
+    // had when it was copied
+    drop(Pager::open_with_key(target, key)?);
+    Ok(())
+}
+
+// Writes an incremental backup's pages into a database file
+fn apply_pages(dir: &Path, manifest: &BackupManifest, file: &mut File) -> Result<()> {
+    let mut reader = BufReader::new(File::open(dir.join(&manifest.file))?);
+    let mut page_id = [0u8; 4];
+    let mut page = vec![0u8; manifest.page_size];
+    for _ in 0..manifest.changed_pages.unwrap_or(0) {
+        reader.read_exact(&mut page_id)?;
+        reader.read_exact(&mut page)?;
+        file.seek(SeekFrom::Start(u32::from_le_bytes(page_id) as u64 * manifest.page_size as u64))?;
+        file.write_all(&page)?;
+    }
+    // The database may have shrunk since the previous backup
+    file.set_len(manifest.pages as u64 * manifest.page_size as u64)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::check;
+    use crate::storage::header::DatabaseHeader;
+    use crate::query::test_util::{count_rows, create, insert, open, run};
+    use serde_json::json;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_backup_while_writing_and_restore() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pager, _, executor) = create(temp_dir.path());
+        let executor = Arc::new(executor);
+        run(&executor, json!({"op": "create_table", "database": "main", "table": "t", "columns": [{"name": "id", "type": "int", "primary_key": true}]}));
+
+        // Inserts of ten rows keep committing while the backup is taken
+        let writer = {
+            let executor = executor.clone();
+            std::thread::spawn(move || {
+                for batch in 0..50 {
+                    insert(&executor, batch * 10..batch * 10 + 10);
+                }
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let backup_dir = temp_dir.path().join("backup");
+        let manifest = backup(&pager, &backup_dir, false).unwrap();
+        writer.join().unwrap();
+        assert_eq!(count_rows(&executor), 500);
+        assert!(backup(&pager, &backup_dir, false).is_err());
+
+        // The copy holds whole transactions and passes a full check
+        let restored = temp_dir.path().join("restored.db");
+        assert_eq!(restore(std::slice::from_ref(&backup_dir), &restored, None).unwrap().sha256, manifest.sha256);
+        assert!(!DatabaseHeader::read_from_file(&restored).unwrap().in_use);
+        let report = check::check_database(&restored, None, None).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.rows % 10, 0);
+
+        // Damage is caught before anything is replaced
+        let copy = backup_dir.join(&manifest.file);
+        let mut bytes = fs::read(&copy).unwrap();
+        bytes[4096 + 100] ^= 0xff;
+        fs::write(&copy, bytes).unwrap();
+        assert!(restore(std::slice::from_ref(&backup_dir), &restored, None).is_err());
+        assert!(check::check_database(&restored, None, None).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_incremental_backups_restore_as_a_chain() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("main.db");
+        drop(Pager::create_database(&path, "main", 4096, None).unwrap());
+        let (pager, executor) = open(&path);
+        run(&executor, json!({"op": "create_table", "database": "main", "table": "t", "columns": [{"name": "id", "type": "int", "primary_key": true}]}));
+        insert(&executor, 0..300);
+
+        // Incremental backups need a full one to start from
+        let dir = |name: &str| temp_dir.path().join(name);
+        assert!(backup(&pager, &dir("early"), true).is_err());
+        assert!(!dir("early").join("main.pages").exists());
+        let full = backup(&pager, &dir("full"), false).unwrap();
+
+        insert(&executor, 300..320);
+        let first = backup(&pager, &dir("first"), true).unwrap();
+        assert_eq!(first.base.as_deref(), Some(full.id.as_str()));
+        assert!(first.changed_pages.unwrap() < first.pages / 2);
+        insert(&executor, 320..700);
+        let second = backup(&pager, &dir("second"), true).unwrap();
+        assert_eq!(second.base, Some(first.id.clone()));
+        assert!(second.pages > first.pages);
+        drop((executor, pager));
+
+        // Backups out of order are refused before anything is replaced
+        let restored = temp_dir.path().join("restored.db");
+        assert!(restore(&[dir("full"), dir("second")], &restored, None).is_err());
+        assert!(restore(&[dir("first")], &restored, None).is_err());
+        assert!(!restored.exists());
+
+        restore(&[dir("full"), dir("first"), dir("second")], &restored, None).unwrap();
+        let report = check::check_database(&restored, None, None).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        let (pager, executor) = open(&restored);
+        assert_eq!(count_rows(&executor), 700);
+
+        // The restored database continues the chain
+        insert(&executor, 700..710);
+        let third = backup(&pager, &dir("third"), true).unwrap();
+        assert_eq!(third.base, Some(second.id));
+    }
+
+    #[test]
+    fn test_backup_destinations_stay_in_the_backup_dir() {
+        let root = Path::new("/srv/backups");
+        assert_eq!(destination_under(root, "main/2024-06-01").unwrap(), root.join("main/2024-06-01"));
+        assert_eq!(destination_under(root, "./main").unwrap(), root.join("main"));
+        for destination in ["", ".", "/etc/rdb", "../elsewhere", "main/../../elsewhere"] {
+            assert!(destination_under(root, destination).is_err(), "{:?} was accepted", destination);
+        }
+    }
+}
@@ -0,0 +1,689 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use crate::core::config::ReplacementPolicy;
+use crate::storage::page::Page;
+use crate::storage::pager::Pager;
+use anyhow::{Result, anyhow};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+mod read_ahead;
+pub mod replacer;
+
+use read_ahead::ReadAhead;
+use replacer::Replacer;
+
+#[derive(Hash, Eq, PartialEq, Clone, Copy, Debug)]
+pub struct GlobalPageId {
+    pub db_id: u32,
+    pub page_id: u32,
+}
+
+// A cached page and the number of `PinnedPage` handles to it. Pins live here
+// rather than in `Page`, so taking one never waits for the page's lock.
+struct Frame {
+    page: RwLock<Page>,
+    pins: AtomicU32,
+}
+
+/// A page held in the buffer pool. The pool will not evict it while the
+/// handle is alive; dropping the handle unpins it.
+pub struct PinnedPage {
+    frame: Arc<Frame>,
+}
+
+impl PinnedPage {
+    fn pin(frame: &Arc<Frame>) -> Self {
+        frame.pins.fetch_add(1, Ordering::SeqCst);
+        Self { frame: frame.clone() }
+    }
+
+    // A page of the caller's own that is never cached
+    fn private(page: Page) -> Self {
+        Self { frame: Arc::new(Frame { page: RwLock::new(page), pins: AtomicU32::new(1) }) }
+    }
+}
+
+impl Clone for PinnedPage {
+    fn clone(&self) -> Self {
+        Self::pin(&self.frame)
+    }
+}
+
+impl Deref for PinnedPage {
+    type Target = RwLock<Page>;
+
+    fn deref(&self) -> &RwLock<Page> {
+        &self.frame.page
+    }
+}
+
+impl Drop for PinnedPage {
+    fn drop(&mut self) {
+        self.frame.pins.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Pools are split into shards of at least this many frames, so a shard is
+// never exhausted by the few pages one query keeps pinned
+const MIN_SHARD_FRAMES: usize = 32;
+const MAX_SHARDS: usize = 64;
+// Pages of a chain read ahead once it is scanned sequentially
+const DEFAULT_READ_AHEAD_PAGES: usize = 16;
+
+// The cached frames of one shard and the policy that picks which one to evict
+struct Frames {
+    map: HashMap<GlobalPageId, Arc<Frame>>,
+    replacer: Box<dyn Replacer>,
+}
+
+impl Frames {
+    fn get(&mut self, global_id: &GlobalPageId) -> Option<&Arc<Frame>> {
+        let frame = self.map.get(global_id)?;
+        self.replacer.touch(*global_id);
+        Some(frame)
+    }
+
+    // Caches a page and returns it pinned
+    fn insert(&mut self, global_id: GlobalPageId, page: Page) -> PinnedPage {
+        self.remove(&global_id);
+        let frame = Arc::new(Frame { page: RwLock::new(page), pins: AtomicU32::new(0) });
+        let pinned = PinnedPage::pin(&frame);
+        self.map.insert(global_id, frame);
+        self.replacer.insert(global_id);
+        pinned
+    }
+
+    fn remove(&mut self, global_id: &GlobalPageId) {
+        if self.map.remove(global_id).is_some() {
+            self.replacer.remove(*global_id);
+        }
+    }
+}
+
+struct Shard {
+    frames: Mutex<Frames>,
+    capacity: usize,
+}
+
+/// Page cache shared by all databases. Pages are hash-partitioned over
+/// shards with their own lock and replacement policy, and no lock is held
+/// while a page is read from or written to disk, so queries on different
+/// pages rarely wait for each other.
+pub struct BufferPool {
+    shards: Vec<Shard>,
+    pagers: RwLock<HashMap<u32, Arc<Pager>>>,
+    // Bumped whenever pages on disk change behind the cache (write-back,
+    // rollback), so a read done outside the lock can be redone
+    invalidations: Arc<AtomicU64>,
+    read_ahead: ReadAhead,
+    // Shard the background flush starts at, so every shard gets its turn
+    next_flush_shard: AtomicUsize,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_policy(capacity, ReplacementPolicy::default())
+    }
+
+    pub fn with_policy(capacity: usize, policy: ReplacementPolicy) -> Self {
+        assert!(capacity > 0, "Capacity must be > 0");
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let count = (capacity / MIN_SHARD_FRAMES).clamp(1, (cores * 4).min(MAX_SHARDS));
+        let shards = (0..count)
+            .map(|i| {
+                let capacity = capacity / count + usize::from(i < capacity % count);
+                Shard {
+                    frames: Mutex::new(Frames { map: HashMap::new(), replacer: replacer::for_policy(policy, capacity) }),
+                    capacity,
+                }
+            })
+            .collect();
+        let invalidations = Arc::new(AtomicU64::new(0));
+        Self {
+            shards,
+            pagers: RwLock::new(HashMap::new()),
+            read_ahead: ReadAhead::new(DEFAULT_READ_AHEAD_PAGES, invalidations.clone()),
+            invalidations,
+            next_flush_shard: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets how many pages of a chain `fetch_chain_page` reads ahead (0 = off).
+    pub fn with_read_ahead(mut self, pages: usize) -> Self {
+        self.read_ahead = ReadAhead::new(pages, self.invalidations.clone());
+        self
+    }
+
+    pub fn register_pager(&self, db_id: u32, pager: Arc<Pager>) {
+        self.pagers.write().insert(db_id, pager);
+    }
+
+    fn shard(&self, global_id: &GlobalPageId) -> &Shard {
+        // Fibonacci hashing spreads consecutive page IDs over all shards
+        let key = ((global_id.db_id as u64) << 32) | global_id.page_id as u64;
+        let hash = key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32;
+        &self.shards[hash as usize % self.shards.len()]
+    }
+
+    /// Returns a pinned page, reading it from disk on a miss. Fails with
+    /// "buffer pool exhausted" when the page is not cached and every frame of
+    /// its shard is pinned.
+    ///
+    /// While another thread runs a transaction, pages it changed are read as
+    /// last committed into a copy of the caller's own instead.
+    pub fn fetch_page(&self, global_id: GlobalPageId) -> Result<PinnedPage> {
+        let pager = self.pager(global_id.db_id)?;
+        let shard = self.shard(&global_id);
+        // Pinned under the shard lock, so the frame can't be evicted first
+        let cached = shard.frames.lock().get(&global_id).map(PinnedPage::pin);
+        if let Some(pinned) = cached {
+            return self.share(&pager, global_id, pinned);
+        }
+
+        // A page read ahead misses changes logged before it was staged
+        let mut read_ahead = self.read_ahead.take(&global_id);
+        if pager.is_uncommitted(global_id.page_id) {
+            if pager.writing_elsewhere() {
+                return Ok(PinnedPage::private(pager.read_page(global_id.page_id)?));
+            }
+            read_ahead = None;
+        }
+        loop {
+            // Load from disk without holding the shard lock, so misses on
+            // different pages are read in parallel
+            let (page, invalidations) = match read_ahead.take() {
+                Some(staged) => staged,
+                None => {
+                    let invalidations = self.invalidations.load(Ordering::SeqCst);
+                    (pager.read_page(global_id.page_id)?, invalidations)
+                }
+            };
+
+            let mut frames = self.lock_with_room(shard)?;
+            if let Some(pinned) = frames.get(&global_id).map(PinnedPage::pin) {
+                // Another thread loaded it first
+                drop(frames);
+                return self.share(&pager, global_id, pinned);
+            }
+            if self.invalidations.load(Ordering::SeqCst) != invalidations {
+                // Pages were written back or rolled back meanwhile, so the copy may be stale
+                continue;
+            }
+            return Ok(frames.insert(global_id, page));
+        }
+    }
+
+    // Hands out a pinned cached page, unless it holds changes of a
+    // transaction another thread runs; then the last committed image is read
+    fn share(&self, pager: &Pager, global_id: GlobalPageId, pinned: PinnedPage) -> Result<PinnedPage> {
+        if pager.writing_elsewhere() && (pinned.read().dirty || pager.is_uncommitted(global_id.page_id)) {
+            return Ok(PinnedPage::private(pager.read_page(global_id.page_id)?));
+        }
+        Ok(pinned)
+    }
+
+    /// Fetches a page of a page chain, `next_page` telling where the chain
+    /// goes on. Once a chain is fetched page after page, the following pages
+    /// are read ahead in the background.
+    pub fn fetch_chain_page(&self, global_id: GlobalPageId, next_page: fn(&Page) -> u32) -> Result<PinnedPage> {
+        let pinned = self.fetch_page(global_id)?;
+        let next_page_id = next_page(&pinned.read());
+        self.read_ahead.record(&self.pager(global_id.db_id)?, global_id, next_page_id, next_page);
+        Ok(pinned)
+    }
+
+    pub fn new_page(&self, db_id: u32) -> Result<PinnedPage> {
+        let pager = self.pager(db_id)?;
+        let page_id = pager.allocate_page()?;
+        let global_id = GlobalPageId { db_id, page_id };
+        match self.lock_with_room(self.shard(&global_id)) {
+            Ok(mut frames) => Ok(frames.insert(global_id, Page::new(page_id, pager.page_size()))),
+            Err(e) => {
+                // Hand the page back rather than leak it
+                let _ = pager.free_page(page_id);
+                Err(e)
+            }
+        }
+    }
+
+    // Locks a shard once it has a free frame. The unpinned page the replacement
+    // policy picks is evicted; a dirty one is pinned and written back with the
+    // lock released, then picked again.
+    fn lock_with_room<'a>(&self, shard: &'a Shard) -> Result<MutexGuard<'a, Frames>> {
+        loop {
+            let mut guard = shard.frames.lock();
+            let frames = &mut *guard;
+            if frames.map.len() < shard.capacity {
+                return Ok(guard);
+            }
+            let map = &frames.map;
+            let victim = frames.replacer
+                .victim(&|id| map.get(&id).is_some_and(|frame| frame.pins.load(Ordering::SeqCst) == 0))
+                .ok_or(anyhow!("Buffer pool exhausted: all {} frames of the shard are pinned", shard.capacity))?;
+
+            let frame = frames.map[&victim].clone();
+            if frame.page.try_read().is_some_and(|page| !page.dirty) {
+                frames.map.remove(&victim);
+                frames.replacer.evict(victim);
+                return Ok(guard);
+            }
+
+            let pinned = PinnedPage::pin(&frame);
+            drop(guard);
+            self.write_back(victim, &mut pinned.write())?;
+        }
+    }
+
+    // Hands a dirty page to its pager and marks it clean
+    fn write_back(&self, global_id: GlobalPageId, page: &mut Page) -> Result<bool> {
+        if !page.dirty {
+            return Ok(false);
+        }
+        self.pager(global_id.db_id)?.write_page(page)?;
+        page.dirty = false;
+        self.invalidations.fetch_add(1, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    // Every cached frame of a database, collected so they can be written
+    // without holding the shard locks
+    fn frames_of(&self, db_id: u32) -> Vec<(GlobalPageId, Arc<Frame>)> {
+        let mut frames = Vec::new();
+        for shard in &self.shards {
+            frames.extend(shard.frames.lock().map.iter()
+                .filter(|(pid, _)| pid.db_id == db_id)
+                .map(|(pid, frame)| (*pid, frame.clone())));
+        }
+        frames
+    }
+
+    /// Writes up to `max_pages` dirty pages to their pagers and returns how many
+    /// were written. Pages another thread holds a lock on are skipped rather
+    /// than waited for.
+    pub fn flush_dirty(&self, max_pages: usize) -> Result<usize> {
+        let first = self.next_flush_shard.fetch_add(1, Ordering::Relaxed);
+        let mut written = 0;
+        for i in 0..self.shards.len() {
+            if written >= max_pages {
+                break;
+            }
+            let shard = &self.shards[(first + i) % self.shards.len()];
+            let dirty: Vec<(GlobalPageId, Arc<Frame>)> = shard.frames.lock().map.iter()
+                .filter(|(_, frame)| frame.page.try_read().is_some_and(|page| page.dirty))
+                .take(max_pages - written)
+                .map(|(pid, frame)| (*pid, frame.clone()))
+                .collect();
+            for (pid, frame) in dirty {
+                if let Some(mut page_guard) = frame.page.try_write()
+                    && self.write_back(pid, &mut page_guard)? {
+                        written += 1;
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    pub fn flush_all(&self) -> Result<()> {
+        self.flush_dirty(usize::MAX)?;
+        Ok(())
+    }
+
+    /// Flushes the pool and closes every database cleanly (see `Pager::close`).
+    /// Pages locked by a running transaction are left to its commit, which
+    /// closing waits for.
+    pub fn shutdown(&self) -> Result<()> {
+        self.flush_all()?;
+        for pager in self.pagers() {
+            pager.close()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the pool and runs a full checkpoint on every database. Pages
+    /// locked by a running transaction are left to its commit, which the
+    /// checkpoint waits for.
+    pub fn checkpoint_all(&self) -> Result<()> {
+        self.flush_all()?;
+        for pager in self.pagers() {
+            pager.full_checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Shrinks a database file by the free pages at its end; see
+    /// `Pager::truncate_free_pages`.
+    pub fn truncate_free_pages(&self, db_id: u32) -> Result<u32> {
+        self.pager(db_id)?.truncate_free_pages()
+    }
+
+    /// Drops a page from the cache and returns it to the database's free list.
+    pub fn free_page(&self, db_id: u32, page_id: u32) -> Result<()> {
+        let pager = self.pager(db_id)?;
+        let global_id = GlobalPageId { db_id, page_id };
+        self.shard(&global_id).frames.lock().remove(&global_id);
+        self.read_ahead.discard(&global_id);
+        pager.free_page(page_id)
+    }
+
+    pub fn catalog_root(&self, db_id: u32) -> Result<u32> {
+        Ok(self.pager(db_id)?.catalog_root())
+    }
+
+    pub fn page_size(&self, db_id: u32) -> Result<usize> {
+        Ok(self.pager(db_id)?.page_size())
+    }
+
+    pub fn pager(&self, db_id: u32) -> Result<Arc<Pager>> {
+        self.pagers.read().get(&db_id).cloned().ok_or(anyhow!("Database not registered"))
+    }
+
+    fn pagers(&self) -> Vec<Arc<Pager>> {
+        self.pagers.read().values().cloned().collect()
+    }
+
+    /// Starts a write transaction on a database. Blocks while another one is active.
+    pub fn begin(&self, db_id: u32) -> Result<()> {
+        self.pager(db_id)?.begin()?;
+        Ok(())
+    }
+
+    /// Hands every dirty page of the database to the pager (the WAL) and commits.
+    /// A transaction that fails to commit is rolled back.
+    pub fn commit(&self, db_id: u32) -> Result<()> {
+        let pager = self.pager(db_id)?;
+        // Only dirty pages are locked for writing. Checking the flag waits for
+        // pages a flush is writing right now, so all of them are logged before
+        // the commit record.
+        let committed = self.frames_of(db_id).into_iter()
+            .filter(|(_, frame)| frame.page.read().dirty)
+            .try_for_each(|(pid, frame)| self.write_back(pid, &mut frame.page.write()).map(|_| ()))
+            .and_then(|_| pager.commit());
+        if let Err(e) = committed {
+            self.rollback(db_id)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Abandons a write transaction. Cached pages that hold its changes, whether
+    /// still dirty or already handed to the WAL, are dropped so the next fetch
+    /// reloads the last committed image.
+    pub fn rollback(&self, db_id: u32) -> Result<()> {
+        let pager = self.pager(db_id)?;
+        // Unwritten changes are discarded under each page's lock before the
+        // transaction ends, so neither eviction nor a flush can write one of
+        // its pages afterwards
+        let mut stale = Vec::new();
+        for (pid, frame) in self.frames_of(db_id) {
+            let mut page_guard = frame.page.write();
+            if page_guard.dirty {
+                page_guard.dirty = false;
+                stale.push(pid);
+            }
+        }
+
+        let rolled_back = pager.rollback();
+        self.invalidations.fetch_add(1, Ordering::SeqCst);
+        match &rolled_back {
+            Ok(discarded) => stale.extend(discarded.iter().map(|page_id| GlobalPageId { db_id, page_id: *page_id })),
+            // Which logged pages went away is unknown, so none of the cached
+            // ones can be trusted
+            Err(_) => stale.extend(self.frames_of(db_id).into_iter().map(|(pid, _)| pid)),
+        }
+        for pid in stale {
+            self.shard(&pid).frames.lock().remove(&pid);
+        }
+        rolled_back.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::pager::Pager;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_buffer_pool_creation() {
+        let pool = BufferPool::new(10);
+        // Just verify it was created successfully
+        assert_eq!(std::mem::size_of_val(&pool), std::mem::size_of::<BufferPool>());
+    }
+
+    #[test]
+    fn test_buffer_pool_register_pager() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let pager = Arc::new(Pager::open(&db_path).unwrap());
+        let pool = BufferPool::new(10);
+        
+        pool.register_pager(0, pager);
+        // Successfully registered
+    }
+
+    #[test]
+    fn test_buffer_pool_fetch_and_allocate() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let pager = Arc::new(Pager::open(&db_path).unwrap());
+        let pool = BufferPool::new(10);
+        
+        pool.register_pager(0, pager.clone());
+        
+        // Allocate pages first
+        pager.allocate_page().unwrap();
+        pager.allocate_page().unwrap();
+        
+        // Fetch page
+        let page = pool.fetch_page(GlobalPageId { db_id: 0, page_id: 0 }).unwrap();
+        let guard = page.read();
+        assert_eq!(guard.id, 0);
+    }
+
+    #[test]
+    fn test_buffer_pool_lru_eviction() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let pager = Arc::new(Pager::open(&db_path).unwrap());
+        let pool = BufferPool::new(3); // Small pool to test eviction
+        
+        pool.register_pager(0, pager.clone());
+        
+        // Allocate more pages than pool size
+        for _ in 0..5 {
+            pager.allocate_page().unwrap();
+        }
+        
+        // Fetch pages - should trigger LRU eviction
+        for i in 0..5 {
+            let _ = pool.fetch_page(GlobalPageId { db_id: 0, page_id: i });
+        }
+        
+        // Pool should handle eviction gracefully
+    }
+
+    #[test]
+    fn test_buffer_pool_dirty_page_flush() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let pager = Arc::new(Pager::open(&db_path).unwrap());
+        let pool = BufferPool::new(10);
+        
+        pool.register_pager(0, pager.clone());
+        pager.allocate_page().unwrap();
+        
+        let page = pool.fetch_page(GlobalPageId { db_id: 0, page_id: 0 }).unwrap();
+        {
+            let mut guard = page.write();
+            guard.data[0] = 42;
+            guard.dirty = true;
+        }
+        
+        // Flush should succeed
+        pool.flush_all().unwrap();
+    }
+
+    #[test]
+    fn test_buffer_pool_never_evicts_pinned_pages() {
+        for policy in [ReplacementPolicy::Lru, ReplacementPolicy::Clock, ReplacementPolicy::LruK, ReplacementPolicy::TwoQ] {
+            never_evicts_pinned_pages(policy);
+        }
+    }
+
+    fn never_evicts_pinned_pages(policy: ReplacementPolicy) {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let pager = Arc::new(Pager::open(&db_path).unwrap());
+        let pool = BufferPool::with_policy(2, policy);
+        pool.register_pager(0, pager.clone());
+        for _ in 0..4 {
+            pager.allocate_page().unwrap();
+        }
+
+        let first = pool.fetch_page(GlobalPageId { db_id: 0, page_id: 0 }).unwrap();
+        first.write().data[0] = 42;
+        let second = pool.fetch_page(GlobalPageId { db_id: 0, page_id: 1 }).unwrap();
+
+        let err = pool.fetch_page(GlobalPageId { db_id: 0, page_id: 2 }).err().unwrap();
+        assert!(err.to_string().contains("Buffer pool exhausted"));
+        assert!(pool.new_page(0).is_err());
+
+        // Unpinning the second page lets it be evicted; the first stays cached
+        drop(second);
+        pool.fetch_page(GlobalPageId { db_id: 0, page_id: 2 }).unwrap();
+        let again = pool.fetch_page(GlobalPageId { db_id: 0, page_id: 0 }).unwrap();
+        assert_eq!(again.read().data[0], 42);
+        assert!(Arc::ptr_eq(&first.frame, &again.frame));
+    }
+
+    #[test]
+    fn test_buffer_pool_reads_chains_ahead() {
+        const PAGES: u32 = 48;
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let pager = Arc::new(Pager::open(&db_path).unwrap());
+        let page_ids: Vec<u32> = (0..PAGES).map(|_| pager.allocate_page().unwrap()).collect();
+        // Chain the pages back to front, each holding its position in the chain
+        let chain: Vec<u32> = page_ids.iter().rev().copied().collect();
+        for (position, &page_id) in chain.iter().enumerate() {
+            let mut page = Page::new(page_id, pager.page_size());
+            page.data[0..4].copy_from_slice(&(position as u32).to_le_bytes());
+            let next = chain.get(position + 1).copied().unwrap_or(0);
+            page.data[4..8].copy_from_slice(&next.to_le_bytes());
+            pager.write_page(&page).unwrap();
+        }
+        let pool = BufferPool::new(16).with_read_ahead(8);
+        pool.register_pager(0, pager);
+        let next_page = |page: &Page| u32::from_le_bytes(page.data[4..8].try_into().unwrap());
+
+        for &page_id in &chain[..2] {
+            pool.fetch_chain_page(GlobalPageId { db_id: 0, page_id }, next_page).unwrap();
+        }
+        // Two pages in a row make a sequential scan, so the third gets read ahead
+        let third = GlobalPageId { db_id: 0, page_id: chain[2] };
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        let (staged, _) = loop {
+            if let Some(staged) = pool.read_ahead.take(&third) {
+                break staged;
+            }
+            assert!(std::time::Instant::now() < deadline, "nothing was read ahead");
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        };
+        assert_eq!(staged.data[0..4], 2u32.to_le_bytes());
+
+        // The rest of the scan sees every page once, in order, whether read ahead or not
+        let mut global_id = third;
+        for position in 2..PAGES {
+            let page = pool.fetch_chain_page(global_id, next_page).unwrap();
+            let guard = page.read();
+            assert_eq!(guard.data[0..4], position.to_le_bytes());
+            global_id.page_id = next_page(&guard);
+        }
+        assert_eq!(global_id.page_id, 0);
+    }
+
+    #[test]
+    fn test_readers_do_not_see_uncommitted_pages() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        Pager::create_database(&db_path, "test", crate::storage::page::DEFAULT_PAGE_SIZE, None).unwrap();
+        // Logging starts once the file is reopened
+        let pager = Arc::new(Pager::open(&db_path).unwrap());
+        let page_id = pager.allocate_page().unwrap();
+        let mut page = Page::new(page_id, pager.page_size());
+        page.data[0] = 1;
+        pager.write_page(&page).unwrap();
+        let pool = Arc::new(BufferPool::new(10));
+        pool.register_pager(0, pager);
+        let global_id = GlobalPageId { db_id: 0, page_id };
+        let read_elsewhere = || {
+            let pool = pool.clone();
+            std::thread::spawn(move || pool.fetch_page(global_id).unwrap().read().data[0]).join().unwrap()
+        };
+
+        pool.begin(0).unwrap();
+        {
+            let page = pool.fetch_page(global_id).unwrap();
+            let mut guard = page.write();
+            guard.data[0] = 2;
+            guard.dirty = true;
+        }
+        assert_eq!(read_elsewhere(), 1);
+        // Written back to the log, but not committed
+        pool.flush_all().unwrap();
+        assert_eq!(read_elsewhere(), 1);
+        assert_eq!(pool.fetch_page(global_id).unwrap().read().data[0], 2);
+
+        pool.commit(0).unwrap();
+        assert_eq!(read_elsewhere(), 2);
+    }
+
+    #[test]
+    fn test_sharded_buffer_pool_keeps_concurrent_updates() {
+        const THREADS: u32 = 8;
+        const PAGES: u32 = 400;
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let pager = Arc::new(Pager::open(&db_path).unwrap());
+        for _ in 0..PAGES {
+            pager.allocate_page().unwrap();
+        }
+        let pool = Arc::new(BufferPool::new(128));
+        assert_eq!(pool.shards.len(), 4);
+        pool.register_pager(0, pager);
+
+        // Every thread bumps a counter on its own pages while the pool evicts
+        // and writes back pages of all threads
+        let handles: Vec<_> = (0..THREADS).map(|thread| {
+            let pool = pool.clone();
+            std::thread::spawn(move || {
+                let mut bumps = vec![0u32; PAGES as usize];
+                let mut seed = thread as u64 + 1;
+                for _ in 0..2000 {
+                    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    let page_id = ((seed >> 33) as u32 % (PAGES / THREADS)) * THREADS + thread;
+                    let page = pool.fetch_page(GlobalPageId { db_id: 0, page_id }).unwrap();
+                    let mut guard = page.write();
+                    let count = u32::from_le_bytes(guard.data[0..4].try_into().unwrap());
+                    guard.data[0..4].copy_from_slice(&(count + 1).to_le_bytes());
+                    guard.dirty = true;
+                    bumps[page_id as usize] += 1;
+                }
+                bumps
+            })
+        }).collect();
+
+        let mut expected = vec![0u32; PAGES as usize];
+        for handle in handles {
+            for (total, bumps) in expected.iter_mut().zip(handle.join().unwrap()) {
+                *total += bumps;
+            }
+        }
+        for page_id in 0..PAGES {
+            let page = pool.fetch_page(GlobalPageId { db_id: 0, page_id }).unwrap();
+            assert_eq!(u32::from_le_bytes(page.read().data[0..4].try_into().unwrap()), expected[page_id as usize]);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread::JoinHandle;
+use parking_lot::Mutex;
+use crate::storage::page::Page;
+use crate::storage::pager::Pager;
+use super::GlobalPageId;
+
+// A chain counts as scanned sequentially once this many pages of it were
+// fetched one after the other
+const SEQUENTIAL_STEPS: u32 = 2;
+// Chains being followed at once; more means some scan is abandoned
+const MAX_STREAMS: usize = 256;
+
+/// Returns the next page of a chain from one of its pages (0 = end of chain).
+pub(super) type NextPage = fn(&Page) -> u32;
+
+// Pages read ahead but not fetched yet, with the buffer pool's invalidation
+// count from before each was read, oldest first
+struct Staged {
+    pages: HashMap<GlobalPageId, (Page, u64)>,
+    order: VecDeque<GlobalPageId>,
+    capacity: usize,
+}
+
+impl Staged {
+    fn insert(&mut self, global_id: GlobalPageId, page: Page, epoch: u64) {
+        if self.pages.insert(global_id, (page, epoch)).is_none() {
+            self.order.push_back(global_id);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.pages.remove(&oldest);
+            }
+        }
+    }
+
+    fn take(&mut self, global_id: &GlobalPageId) -> Option<(Page, u64)> {
+        let staged = self.pages.remove(global_id)?;
+        self.order.retain(|id| id != global_id);
+        Some(staged)
+    }
+}
+
+struct Request {
+    pager: Arc<Pager>,
+    db_id: u32,
+    first_page_id: u32,
+    next_page: NextPage,
+}
+
+/// Prefetches the following pages of a page chain on a background thread
+/// once it is being read page by page. The pages are kept aside until fetched
+/// rather than cached, so a scan that stops early evicts nothing.
+pub(super) struct ReadAhead {
+    depth: usize,
+    invalidations: Arc<AtomicU64>,
+    staged: Arc<Mutex<Staged>>,
+    // Next page expected of each chain being followed -> pages fetched so far
+    streams: Mutex<HashMap<GlobalPageId, u32>>,
+    // Started on first use
+    worker: Mutex<Option<(Sender<Request>, JoinHandle<()>)>>,
+}
+
+impl ReadAhead {
+    pub(super) fn new(depth: usize, invalidations: Arc<AtomicU64>) -> Self {
+        Self {
+            depth,
+            invalidations,
+            staged: Arc::new(Mutex::new(Staged { pages: HashMap::new(), order: VecDeque::new(), capacity: depth * 4 })),
+            streams: Mutex::new(HashMap::new()),
+            worker: Mutex::new(None),
+        }
+    }
+
+    /// Records that `global_id` was fetched as part of a chain whose next page
+    /// is `next_page_id`, and reads ahead once the chain is followed sequentially.
+    pub(super) fn record(&self, pager: &Arc<Pager>, global_id: GlobalPageId, next_page_id: u32, next_page: NextPage) {
+        if self.depth == 0 || next_page_id == 0 {
+            self.streams.lock().remove(&global_id);
+            return;
+        }
+        let steps = {
+            let mut streams = self.streams.lock();
+            let steps = streams.remove(&global_id).unwrap_or(0) + 1;
+            if streams.len() >= MAX_STREAMS {
+                streams.clear();
+            }
+            streams.insert(GlobalPageId { db_id: global_id.db_id, page_id: next_page_id }, steps);
+            steps
+        };
+        if steps >= SEQUENTIAL_STEPS {
+            self.request(Request { pager: pager.clone(), db_id: global_id.db_id, first_page_id: next_page_id, next_page });
+        }
+    }
+
+    /// Hands out a page read ahead, with the invalidation count it was read at.
+    pub(super) fn take(&self, global_id: &GlobalPageId) -> Option<(Page, u64)> {
+        if self.depth == 0 {
+            return None;
+        }
+        self.staged.lock().take(global_id)
+    }
+
+    /// Forgets a page read ahead, for pages that are freed.
+    pub(super) fn discard(&self, global_id: &GlobalPageId) {
+        if self.depth > 0 {
+            self.staged.lock().take(global_id);
+        }
+    }
+
+    fn request(&self, request: Request) {
+        let mut worker = self.worker.lock();
+        if worker.is_none() {
+            let (sender, requests) = mpsc::channel::<Request>();
+            let depth = self.depth;
+            let staged = self.staged.clone();
+            let invalidations = self.invalidations.clone();
+            let handle = std::thread::spawn(move || {
+                while let Ok(request) = requests.recv() {
+                    // A scan asks again at every page, so when the thread falls
+                    // behind only the newest request per database is worth doing
+                    let mut latest = HashMap::from([(request.db_id, request)]);
+                    while let Ok(newer) = requests.try_recv() {
+                        latest.insert(newer.db_id, newer);
+                    }
+                    for request in latest.values() {
+                        // A failed read just ends this read-ahead; the scan reports it
+                        let _ = read_chain(request, depth, &staged, &invalidations);
+                    }
+                }
+            });
+            *worker = Some((sender, handle));
+        }
+        if let Some((sender, _)) = worker.as_ref() {
+            let _ = sender.send(request);
+        }
+    }
+}
+
+// Stages the next `depth` pages of a chain. Pages already staged are only
+// followed, not read again.
+fn read_chain(request: &Request, depth: usize, staged: &Mutex<Staged>, invalidations: &AtomicU64) -> anyhow::Result<()> {
+    let mut page_id = request.first_page_id;
+    for _ in 0..depth {
+        if page_id == 0 {
+            break;
+        }
+        let global_id = GlobalPageId { db_id: request.db_id, page_id };
+        let known_next = staged.lock().pages.get(&global_id).map(|(page, _)| (request.next_page)(page));
+        page_id = match known_next {
+            Some(next_page_id) => next_page_id,
+            None => {
+                let epoch = invalidations.load(Ordering::SeqCst);
+                let page = request.pager.read_page(page_id)?;
+                let next_page_id = (request.next_page)(&page);
+                staged.lock().insert(global_id, page, epoch);
+                next_page_id
+            }
+        };
+    }
+    Ok(())
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        // Closing the channel ends the thread once it finishes its request,
+        // and with it the last references to the pagers
+        if let Some((sender, handle)) = self.worker.lock().take() {
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+}
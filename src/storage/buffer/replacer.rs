@@ -0,0 +1,364 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use lru::LruCache;
+use crate::core::config::ReplacementPolicy;
+use super::GlobalPageId;
+
+// Number of accesses LRU-K remembers per page
+const LRU_K: usize = 2;
+
+/// Decides which cached page the buffer pool gives up when it needs a frame.
+/// The pool reports every access; pages it must not evict (pinned ones) are
+/// filtered out through `evictable` when a victim is picked.
+pub trait Replacer: Send {
+    /// A page was read into the pool.
+    fn insert(&mut self, id: GlobalPageId);
+    /// A cached page was accessed again.
+    fn touch(&mut self, id: GlobalPageId);
+    /// A page left the pool other than by eviction (freed or rolled back).
+    fn remove(&mut self, id: GlobalPageId);
+    /// Picks the page to evict among those `evictable` accepts. It stays
+    /// tracked until `evict`, since a dirty victim is written back first.
+    fn victim(&mut self, evictable: &dyn Fn(GlobalPageId) -> bool) -> Option<GlobalPageId>;
+    /// A page picked by `victim` left the pool.
+    fn evict(&mut self, id: GlobalPageId) {
+        self.remove(id);
+    }
+}
+
+pub fn for_policy(policy: ReplacementPolicy, capacity: usize) -> Box<dyn Replacer> {
+    match policy {
+        ReplacementPolicy::Lru => Box::new(LruReplacer::new()),
+        ReplacementPolicy::Clock => Box::new(ClockReplacer::new()),
+        ReplacementPolicy::LruK => Box::new(LruKReplacer::new(LRU_K, capacity)),
+        ReplacementPolicy::TwoQ => Box::new(TwoQReplacer::new(capacity)),
+    }
+}
+
+/// Evicts the least recently used page.
+struct LruReplacer {
+    order: LruCache<GlobalPageId, ()>,
+}
+
+impl LruReplacer {
+    fn new() -> Self {
+        Self { order: LruCache::unbounded() }
+    }
+}
+
+impl Replacer for LruReplacer {
+    fn insert(&mut self, id: GlobalPageId) {
+        self.order.push(id, ());
+    }
+
+    fn touch(&mut self, id: GlobalPageId) {
+        self.order.promote(&id);
+    }
+
+    fn remove(&mut self, id: GlobalPageId) {
+        self.order.pop(&id);
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(GlobalPageId) -> bool) -> Option<GlobalPageId> {
+        self.order.iter().rev().map(|(id, _)| *id).find(|id| evictable(*id))
+    }
+}
+
+/// Second-chance LRU approximation: a hand sweeps the frames and evicts the
+/// first one not referenced since the hand last passed it.
+struct ClockReplacer {
+    // Page and reference bit per frame; `None` marks a free slot
+    slots: Vec<Option<(GlobalPageId, bool)>>,
+    positions: HashMap<GlobalPageId, usize>,
+    free_slots: Vec<usize>,
+    hand: usize,
+}
+
+impl ClockReplacer {
+    fn new() -> Self {
+        Self { slots: Vec::new(), positions: HashMap::new(), free_slots: Vec::new(), hand: 0 }
+    }
+}
+
+impl Replacer for ClockReplacer {
+    fn insert(&mut self, id: GlobalPageId) {
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+        };
+        self.slots[slot] = Some((id, true));
+        self.positions.insert(id, slot);
+    }
+
+    fn touch(&mut self, id: GlobalPageId) {
+        if let Some(&slot) = self.positions.get(&id)
+            && let Some((_, referenced)) = &mut self.slots[slot] {
+                *referenced = true;
+        }
+    }
+
+    fn remove(&mut self, id: GlobalPageId) {
+        if let Some(slot) = self.positions.remove(&id) {
+            self.slots[slot] = None;
+            self.free_slots.push(slot);
+        }
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(GlobalPageId) -> bool) -> Option<GlobalPageId> {
+        // The first sweep clears every reference bit, so two are always enough
+        for _ in 0..2 * self.slots.len() {
+            let slot = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
+            let Some((id, referenced)) = &mut self.slots[slot] else { continue };
+            if !evictable(*id) {
+                continue;
+            }
+            if *referenced {
+                *referenced = false;
+                continue;
+            }
+            return Some(*id);
+        }
+        None
+    }
+}
+
+/// Evicts the page whose K-th most recent access lies furthest back. Pages
+/// accessed fewer than K times go first, oldest first, so pages touched once
+/// by a scan leave before pages that are used again and again.
+struct LruKReplacer {
+    k: usize,
+    clock: u64,
+    // Up to the last K access times of each page, oldest first. Kept for a
+    // while after eviction, so a page read back soon is known to be reused.
+    history: HashMap<GlobalPageId, VecDeque<u64>>,
+    resident: HashSet<GlobalPageId>,
+    retained_capacity: usize,
+}
+
+impl LruKReplacer {
+    fn new(k: usize, capacity: usize) -> Self {
+        Self { k, clock: 0, history: HashMap::new(), resident: HashSet::new(), retained_capacity: capacity }
+    }
+
+    fn record(&mut self, id: GlobalPageId) {
+        self.clock += 1;
+        let history = self.history.entry(id).or_default();
+        if history.len() == self.k {
+            history.pop_front();
+        }
+        history.push_back(self.clock);
+    }
+
+    // Forgets the evicted page that was accessed longest ago once more than
+    // `retained_capacity` are remembered
+    fn trim_retained(&mut self) {
+        if self.history.len() - self.resident.len() <= self.retained_capacity {
+            return;
+        }
+        let oldest = self.history.iter()
+            .filter(|(id, _)| !self.resident.contains(*id))
+            .min_by_key(|(_, history)| history.back().copied())
+            .map(|(id, _)| *id);
+        if let Some(id) = oldest {
+            self.history.remove(&id);
+        }
+    }
+}
+
+impl Replacer for LruKReplacer {
+    fn insert(&mut self, id: GlobalPageId) {
+        self.resident.insert(id);
+        self.record(id);
+    }
+
+    fn touch(&mut self, id: GlobalPageId) {
+        if self.resident.contains(&id) {
+            self.record(id);
+        }
+    }
+
+    fn remove(&mut self, id: GlobalPageId) {
+        self.resident.remove(&id);
+        self.history.remove(&id);
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(GlobalPageId) -> bool) -> Option<GlobalPageId> {
+        // A linear scan; pools are small enough that this costs less than the
+        // disk read that follows every eviction
+        self.resident.iter()
+            .filter(|id| evictable(**id))
+            .min_by_key(|id| {
+                let history = &self.history[*id];
+                (history.len() >= self.k, history[0])
+            })
+            .copied()
+    }
+
+    fn evict(&mut self, id: GlobalPageId) {
+        self.resident.remove(&id);
+        self.trim_retained();
+    }
+}
+
+/// 2Q: pages read for the first time wait in a small FIFO queue. Only pages
+/// accessed again after leaving it, which a ghost list of recently evicted
+/// IDs tells apart, move on to the main LRU queue. A table scan then cycles
+/// through the FIFO queue without touching the hot pages.
+struct TwoQReplacer {
+    // Pages seen once, in arrival order
+    recent: VecDeque<GlobalPageId>,
+    recent_target: usize,
+    // Pages seen again after being evicted from `recent`
+    frequent: LruCache<GlobalPageId, ()>,
+    // IDs recently evicted from `recent`, oldest first
+    ghosts: VecDeque<GlobalPageId>,
+    ghost_set: HashSet<GlobalPageId>,
+    ghost_capacity: usize,
+}
+
+impl TwoQReplacer {
+    fn new(capacity: usize) -> Self {
+        // The sizes suggested by the 2Q paper: a quarter of the pool for
+        // first-time pages, and ghosts for half of it
+        Self {
+            recent: VecDeque::new(),
+            recent_target: (capacity / 4).max(1),
+            frequent: LruCache::unbounded(),
+            ghosts: VecDeque::new(),
+            ghost_set: HashSet::new(),
+            ghost_capacity: (capacity / 2).max(1),
+        }
+    }
+
+    fn recent_victim(&self, evictable: &dyn Fn(GlobalPageId) -> bool) -> Option<GlobalPageId> {
+        self.recent.iter().copied().find(|id| evictable(*id))
+    }
+
+    fn frequent_victim(&self, evictable: &dyn Fn(GlobalPageId) -> bool) -> Option<GlobalPageId> {
+        self.frequent.iter().rev().map(|(id, _)| *id).find(|id| evictable(*id))
+    }
+}
+
+impl Replacer for TwoQReplacer {
+    fn insert(&mut self, id: GlobalPageId) {
+        if self.ghost_set.remove(&id) {
+            self.ghosts.retain(|ghost| *ghost != id);
+            self.frequent.push(id, ());
+        } else {
+            self.recent.push_back(id);
+        }
+    }
+
+    fn touch(&mut self, id: GlobalPageId) {
+        // Hits on first-time pages don't count: a scan reads each page many
+        // times in a row
+        self.frequent.promote(&id);
+    }
+
+    fn remove(&mut self, id: GlobalPageId) {
+        if self.frequent.pop(&id).is_none() {
+            self.recent.retain(|recent| *recent != id);
+        }
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(GlobalPageId) -> bool) -> Option<GlobalPageId> {
+        if self.recent.len() > self.recent_target || self.frequent.is_empty() {
+            self.recent_victim(evictable).or_else(|| self.frequent_victim(evictable))
+        } else {
+            self.frequent_victim(evictable).or_else(|| self.recent_victim(evictable))
+        }
+    }
+
+    fn evict(&mut self, id: GlobalPageId) {
+        if self.frequent.pop(&id).is_some() {
+            return;
+        }
+        // Remembered, so reading it back soon promotes it
+        self.recent.retain(|recent| *recent != id);
+        if self.ghosts.len() == self.ghost_capacity
+            && let Some(oldest) = self.ghosts.pop_front() {
+                self.ghost_set.remove(&oldest);
+        }
+        self.ghosts.push_back(id);
+        self.ghost_set.insert(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page_id: u32) -> GlobalPageId {
+        GlobalPageId { db_id: 0, page_id }
+    }
+
+    // Runs `accesses` against a pool of `capacity` frames, evicting as needed,
+    // and returns the number of hits
+    fn hits(replacer: &mut dyn Replacer, capacity: usize, accesses: &[u32]) -> usize {
+        let mut cached = HashSet::new();
+        let mut hits = 0;
+        for &page_id in accesses {
+            if cached.contains(&page_id) {
+                replacer.touch(page(page_id));
+                hits += 1;
+                continue;
+            }
+            if cached.len() == capacity {
+                let victim = replacer.victim(&|_| true).unwrap();
+                replacer.evict(victim);
+                assert!(cached.remove(&victim.page_id));
+            }
+            replacer.insert(page(page_id));
+            cached.insert(page_id);
+        }
+        hits
+    }
+
+    #[test]
+    fn test_replacers_skip_unevictable_pages() {
+        for policy in [ReplacementPolicy::Lru, ReplacementPolicy::Clock, ReplacementPolicy::LruK, ReplacementPolicy::TwoQ] {
+            let mut replacer = for_policy(policy, 4);
+            for page_id in 0..4 {
+                replacer.insert(page(page_id));
+            }
+            replacer.touch(page(3));
+            replacer.remove(page(1));
+
+            assert_eq!(replacer.victim(&|id| id.page_id == 2), Some(page(2)), "{:?}", policy);
+            // Picking a victim alone doesn't drop it
+            assert_eq!(replacer.victim(&|id| id.page_id == 2), Some(page(2)), "{:?}", policy);
+            replacer.evict(page(2));
+            assert_eq!(replacer.victim(&|id| id.page_id == 1), None, "{:?}", policy);
+            let mut rest = Vec::new();
+            for _ in 0..2 {
+                let victim = replacer.victim(&|_| true).unwrap();
+                replacer.evict(victim);
+                rest.push(victim);
+            }
+            rest.sort_by_key(|id| id.page_id);
+            assert_eq!(rest, vec![page(0), page(3)], "{:?}", policy);
+            assert_eq!(replacer.victim(&|_| true), None, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_scan_resistant_replacers_keep_hot_pages() {
+        // Pages 0-7 are used over and over while pages from 100 on are read
+        // once each, like a table scan as large as the pool
+        let mut accesses = Vec::new();
+        for round in 0..20 {
+            accesses.extend(0..8);
+            accesses.extend(100 + round * 32..100 + (round + 1) * 32);
+        }
+
+        let lru = hits(&mut LruReplacer::new(), 32, &accesses);
+        let lru_k = hits(&mut LruKReplacer::new(LRU_K, 32), 32, &accesses);
+        let two_q = hits(&mut TwoQReplacer::new(32), 32, &accesses);
+        assert_eq!(lru, 0);
+        assert!(lru_k >= 8 * 18, "LRU-K hits: {}", lru_k);
+        assert!(two_q >= 8 * 18, "2Q hits: {}", two_q);
+    }
+}
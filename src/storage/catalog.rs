@@ -1,14 +1,33 @@
 use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use crate::query::ColumnDef;
-use anyhow::Result;
+use crate::storage::codec::Codec;
+use crate::storage::buffer::{BufferPool, GlobalPageId};
+use crate::storage::page::PAGE_CHECKSUM_SIZE;
+use byteorder::{LittleEndian, ByteOrder};
+use anyhow::{Result, anyhow};
+
+// The catalog is stored as JSON across a chain of pages. Every page starts with
+// the ID of the next one (0 = last); the root page then holds the total length.
+// An all-zero root page is an empty catalog.
+const PAGE_HEADER_SIZE: usize = 4;
+const ROOT_HEADER_SIZE: usize = 8;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TableInfo {
     pub name: String,
     pub root_page_id: u32,
     pub index_root_page_id: u32,
+    // Free space map of the heap pages; 0 until built for tables from before maps existed
+    #[serde(default)]
+    pub fsm_root_page_id: u32,
     pub columns: Vec<ColumnDef>,
+    // Tables from before codecs were configurable use zstd
+    #[serde(default)]
+    pub codec: Codec,
+    // zstd dictionary trained at CREATE TABLE: first overflow page and length
+    #[serde(default)]
+    pub dictionary: Option<(u32, usize)>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -37,20 +56,150 @@ impl Catalog {
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        // Find null terminator or end of valid JSON?
-        // For now assume the whole page is valid JSON or padded with 0s which JSON parser might dislike.
-        // We should store length prefix.
         if bytes.is_empty() {
             return Ok(Catalog::new());
         }
-        
-        // Trim trailing nulls
-        let len = bytes.iter().position(|&x| x == 0).unwrap_or(bytes.len());
-        if len == 0 {
-             return Ok(Catalog::new());
-        }
-        
-        let catalog: Catalog = serde_json::from_slice(&bytes[0..len])?;
+        let catalog: Catalog = serde_json::from_slice(bytes)?;
         Ok(catalog)
     }
+
+    /// Reads the catalog from its page chain, starting at the header's root catalog page.
+    pub fn load(buffer_pool: &BufferPool, db_id: u32) -> Result<Self> {
+        let root_page_id = buffer_pool.catalog_root(db_id)?;
+        let root_page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id: root_page_id })?;
+        let (total_len, mut bytes, mut next_page_id, usable) = {
+            let root_guard = root_page.read();
+            let usable = root_guard.usable_size();
+            let total_len = LittleEndian::read_u32(&root_guard.data[4..8]) as usize;
+            let take = total_len.min(usable - ROOT_HEADER_SIZE);
+            let bytes = root_guard.data[ROOT_HEADER_SIZE..ROOT_HEADER_SIZE + take].to_vec();
+            (total_len, bytes, LittleEndian::read_u32(&root_guard.data[0..4]), usable)
+        };
+
+        while bytes.len() < total_len {
+            if next_page_id == 0 {
+                return Err(anyhow!("Catalog is truncated: {} of {} bytes found", bytes.len(), total_len));
+            }
+            let page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id: next_page_id })?;
+            let guard = page.read();
+            let take = (total_len - bytes.len()).min(usable - PAGE_HEADER_SIZE);
+            bytes.extend_from_slice(&guard.data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + take]);
+            next_page_id = LittleEndian::read_u32(&guard.data[0..4]);
+        }
+
+        Self::from_bytes(&bytes)
+    }
+
+    /// Pages of the catalog's chain, root first.
+    pub fn page_ids(buffer_pool: &BufferPool, db_id: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut page_id = buffer_pool.catalog_root(db_id)?;
+        while page_id != 0 {
+            if chain.contains(&page_id) {
+                return Err(anyhow!("Catalog page chain has a cycle at page {}", page_id));
+            }
+            chain.push(page_id);
+            let page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id })?;
+            page_id = LittleEndian::read_u32(&page.read().data[0..4]);
+        }
+        Ok(chain)
+    }
+
+    /// Writes the catalog back to its page chain, growing or shrinking the chain
+    /// as needed. Surplus pages go back to the free list.
+    pub fn save(&self, buffer_pool: &BufferPool, db_id: u32) -> Result<()> {
+        let bytes = self.to_bytes()?;
+        let root_page_id = buffer_pool.catalog_root(db_id)?;
+        let usable = buffer_pool.page_size(db_id)? - PAGE_CHECKSUM_SIZE;
+
+        // Pages after the root that hold the current catalog. No page guard is
+        // held across fetches, since fetching may evict (and flush) another page.
+        let mut chain: Vec<u32> = Vec::new();
+        let mut page_id = root_page_id;
+        loop {
+            let page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id })?;
+            let next_page_id = LittleEndian::read_u32(&page.read().data[0..4]);
+            if next_page_id == 0 {
+                break;
+            }
+            if next_page_id == root_page_id || chain.contains(&next_page_id) {
+                return Err(anyhow!("Catalog page chain has a cycle at page {}", next_page_id));
+            }
+            chain.push(next_page_id);
+            page_id = next_page_id;
+        }
+
+        let (root_chunk, rest) = bytes.split_at(bytes.len().min(usable - ROOT_HEADER_SIZE));
+        let chunks: Vec<&[u8]> = rest.chunks(usable - PAGE_HEADER_SIZE).collect();
+
+        while chain.len() < chunks.len() {
+            let page = buffer_pool.new_page(db_id)?;
+            let page_id = page.read().id;
+            chain.push(page_id);
+        }
+        for page_id in chain.drain(chunks.len()..) {
+            buffer_pool.free_page(db_id, page_id)?;
+        }
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id: chain[i] })?;
+            let mut guard = page.write();
+            let next = chain.get(i + 1).copied().unwrap_or(0);
+            LittleEndian::write_u32(&mut guard.data[0..4], next);
+            guard.data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+            guard.data[PAGE_HEADER_SIZE + chunk.len()..usable].fill(0);
+            guard.dirty = true;
+        }
+
+        let root_page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id: root_page_id })?;
+        let mut root_guard = root_page.write();
+        LittleEndian::write_u32(&mut root_guard.data[0..4], chain.first().copied().unwrap_or(0));
+        LittleEndian::write_u32(&mut root_guard.data[4..8], bytes.len() as u32);
+        root_guard.data[ROOT_HEADER_SIZE..ROOT_HEADER_SIZE + root_chunk.len()].copy_from_slice(root_chunk);
+        root_guard.data[ROOT_HEADER_SIZE + root_chunk.len()..usable].fill(0);
+        root_guard.dirty = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::header::DatabaseHeader;
+    use crate::storage::pager::Pager;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_catalog_spans_pages() {
+        let temp_dir = TempDir::new().unwrap();
+        let pager = Arc::new(Pager::create(&temp_dir.path().join("test.db"), 1024, None).unwrap());
+        pager.allocate_page().unwrap();
+        pager.write_header(&DatabaseHeader::new("test".to_string(), 1024)).unwrap();
+        pager.allocate_page().unwrap();
+
+        let pool = BufferPool::new(16);
+        pool.register_pager(0, pager.clone());
+        assert!(Catalog::load(&pool, 0).unwrap().tables.is_empty());
+
+        let mut catalog = Catalog::new();
+        for i in 0..50 {
+            let columns = (0..10).map(|c| serde_json::from_value(serde_json::json!({
+                "name": format!("column_{}", c), "type": "string"
+            })).unwrap()).collect();
+            catalog.add_table(TableInfo { name: format!("table_{}", i), root_page_id: i, index_root_page_id: i, fsm_root_page_id: 0, columns, codec: Codec::default(), dictionary: None });
+        }
+        assert!(catalog.to_bytes().unwrap().len() > 10 * 1024);
+        catalog.save(&pool, 0).unwrap();
+
+        let loaded = Catalog::load(&pool, 0).unwrap();
+        assert_eq!(loaded.tables.len(), 50);
+        assert_eq!(loaded.get_table("table_42").unwrap().columns[9].name, "column_9");
+
+        // Shrinking the catalog frees the pages it no longer needs
+        catalog.tables.retain(|name, _| name == "table_1");
+        catalog.save(&pool, 0).unwrap();
+        assert_eq!(Catalog::load(&pool, 0).unwrap().tables.len(), 1);
+        assert!(pager.read_header().unwrap().free_page_count > 10);
+    }
 }
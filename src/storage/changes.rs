@@ -0,0 +1,207 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, ByteOrder};
+use uuid::Uuid;
+use anyhow::{Result, anyhow};
+
+// Which pages of a database file changed since its last backup, for
+// incremental backups. Kept next to the database as `<name>.changes`: magic,
+// ID of the backup the changes are relative to (all zeros = none), how many
+// pages the file had then, and one bit per page. Pages past that count are
+// new and always changed, so they need no bit.
+const MAGIC: &[u8; 8] = b"RDBCHGS1";
+const HEADER_SIZE: usize = 28;
+
+pub struct ChangeMap {
+    path: PathBuf,
+    since: Option<Uuid>,
+    pages: u32,
+    bits: Vec<u8>,
+    // Pages changed before a backup that is still being copied. They stay in
+    // the saved map until the backup completes, so a failed one loses nothing.
+    copying: Option<Vec<u8>>,
+    // Marked pages the saved map doesn't have yet
+    unsaved: bool,
+}
+
+/// What an incremental backup must copy.
+pub struct Changes {
+    /// Backup the changes are relative to
+    pub since: Option<Uuid>,
+    /// Page count of the file at that backup
+    pub pages: u32,
+    /// Pages below that count that changed since
+    pub changed: Vec<u32>,
+}
+
+impl ChangeMap {
+    pub fn path_for(db_path: &Path) -> PathBuf {
+        db_path.with_extension("changes")
+    }
+
+    /// Loads the map of the database at `db_path`. Without one, or when it
+    /// can't be trusted, tracking starts over relative to no backup.
+    pub fn open(db_path: &Path, trusted: bool) -> Result<Self> {
+        let mut map = Self::new(db_path, None, 0);
+        match fs::read(&map.path) {
+            Ok(bytes) if trusted && bytes.len() >= HEADER_SIZE && &bytes[0..8] == MAGIC => {
+                let since = Uuid::from_slice(&bytes[8..24])?;
+                map.since = (!since.is_nil()).then_some(since);
+                map.pages = LittleEndian::read_u32(&bytes[24..28]);
+                map.bits = bytes[HEADER_SIZE..].to_vec();
+            }
+            Ok(_) => map.save()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => map.save()?,
+            Err(e) => return Err(e.into()),
+        }
+        Ok(map)
+    }
+
+    /// Starts tracking a database file that was just restored from a backup
+    /// of `pages` pages, or from something no backup holds.
+    pub fn reset(db_path: &Path, since: Option<Uuid>, pages: u32) -> Result<()> {
+        Self::new(db_path, since, pages).save()
+    }
+
+    fn new(db_path: &Path, since: Option<Uuid>, pages: u32) -> Self {
+        Self { path: Self::path_for(db_path), since, pages, bits: Vec::new(), copying: None, unsaved: false }
+    }
+
+    /// Marks a page changed.
+    pub fn mark(&mut self, page_id: u32) {
+        // While a backup is copied the count it ends up with isn't known yet
+        if page_id >= self.pages && self.copying.is_none() {
+            return;
+        }
+        let (byte, bit) = (page_id as usize / 8, 1u8 << (page_id % 8));
+        if self.bits.len() <= byte {
+            self.bits.resize(byte + 1, 0);
+        }
+        let copying = self.copying.as_ref().is_some_and(|copying| copying.get(byte).is_some_and(|b| b & bit != 0));
+        if self.bits[byte] & bit == 0 && !copying {
+            self.unsaved = true;
+        }
+        self.bits[byte] |= bit;
+    }
+
+    /// The file shrank to `pages` pages: pages that come back later are new.
+    pub fn truncate(&mut self, pages: u32) -> Result<()> {
+        if pages < self.pages {
+            self.pages = pages;
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Starts a backup: returns the changes so far and tracks later ones
+    /// separately.
+    pub fn start_backup(&mut self) -> Result<Changes> {
+        if self.copying.is_some() {
+            return Err(anyhow!("Another backup of this database is running"));
+        }
+        let bits = std::mem::take(&mut self.bits);
+        let changed = bits.iter().enumerate()
+            .flat_map(|(byte, b)| (0..8).filter(move |bit| b & (1 << bit) != 0).map(move |bit| (byte * 8 + bit) as u32))
+            .collect();
+        self.copying = Some(bits);
+        Ok(Changes { since: self.since, pages: self.pages, changed })
+    }
+
+    /// Ends a backup. A completed one, of `pages` pages, becomes what changes
+    /// are tracked relative to; after a failed one the earlier changes count
+    /// again.
+    pub fn finish_backup(&mut self, completed: Option<(Uuid, u32)>) -> Result<()> {
+        let Some(copying) = self.copying.take() else {
+            return Err(anyhow!("No backup in progress"));
+        };
+        match completed {
+            Some((id, pages)) => {
+                self.since = Some(id);
+                // Pages that were new are tracked from here on
+                self.pages = pages;
+                self.save()
+            }
+            None => {
+                self.bits.resize(self.bits.len().max(copying.len()), 0);
+                for (byte, copied) in self.bits.iter_mut().zip(copying) {
+                    *byte |= copied;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Saves the map if pages were marked since it was last saved.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.unsaved {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // Replaces the saved map atomically
+    fn save(&mut self) -> Result<()> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.bits.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(self.since.unwrap_or(Uuid::nil()).as_bytes());
+        bytes.extend_from_slice(&self.pages.to_le_bytes());
+        bytes.extend_from_slice(&self.bits);
+        if let Some(copying) = &self.copying {
+            bytes.resize(bytes.len().max(HEADER_SIZE + copying.len()), 0);
+            for (byte, copied) in bytes[HEADER_SIZE..].iter_mut().zip(copying) {
+                *byte |= copied;
+            }
+        }
+
+        let staging = self.path.with_extension("changes.tmp");
+        let mut file = File::create(&staging)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&staging, &self.path)?;
+        self.unsaved = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_change_map_survives_failed_backups() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        ChangeMap::reset(&db_path, Some(Uuid::new_v4()), 100).unwrap();
+        let mut map = ChangeMap::open(&db_path, true).unwrap();
+        map.mark(3);
+        map.mark(17);
+        map.mark(120);
+        map.flush().unwrap();
+
+        // A backup that fails leaves its pages marked, also on disk
+        let changes = map.start_backup().unwrap();
+        assert_eq!((changes.pages, changes.changed), (100, vec![3, 17]));
+        map.mark(17);
+        assert!(!map.unsaved);
+        map.mark(40);
+        map.flush().unwrap();
+        map.finish_backup(None).unwrap();
+        let mut reopened = ChangeMap::open(&db_path, true).unwrap();
+        assert_eq!(reopened.start_backup().unwrap().changed, vec![3, 17, 40]);
+
+        // A completed one starts a new period
+        let id = Uuid::new_v4();
+        map.start_backup().unwrap();
+        assert!(map.start_backup().is_err());
+        map.mark(5);
+        map.finish_backup(Some((id, 130))).unwrap();
+        let mut reopened = ChangeMap::open(&db_path, true).unwrap();
+        let changes = reopened.start_backup().unwrap();
+        assert_eq!((changes.since, changes.pages, changes.changed), (Some(id), 130, vec![5]));
+
+        // After a crash without a log the map can't be trusted
+        assert_eq!(ChangeMap::open(&db_path, false).unwrap().since, None);
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use crate::storage::buffer::{BufferPool, GlobalPageId};
+use crate::storage::catalog::{Catalog, TableInfo};
+use crate::storage::codec::TupleCodec;
+use crate::storage::crypto::EncryptionKey;
+use crate::storage::fsm::FreeSpaceMap;
+use crate::storage::header::{DatabaseHeader, CURRENT_FILE_FORMAT_VERSION};
+use crate::storage::index::BTreeIndex;
+use crate::storage::overflow;
+use crate::storage::pager::Pager;
+use crate::storage::slotted::{self, SlottedPage};
+use anyhow::Result;
+use serde_json::Value;
+
+/// What `rdb db check` looked at and every problem it found.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub tables: usize,
+    pub rows: usize,
+    pub pages: u32,
+    pub problems: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks a database file that no server has open: its header, catalog, every
+/// table's heap chain, tuples, overflow pages, index and free space map, and
+/// that every page is either used or free. Opening the database logs a header
+/// change, so the log is archived into `archive` like on any other open.
+pub fn check_database(path: &Path, key: Option<EncryptionKey>, archive: Option<&Path>) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    match DatabaseHeader::read_from_file(path) {
+        Err(e) => {
+            report.problems.push(format!("Header is unreadable: {}", e));
+            return Ok(report);
+        }
+        Ok(header) if header.file_format_version != CURRENT_FILE_FORMAT_VERSION => {
+            report.problems.push(format!("Header has file format v{}, but this engine checks v{}; run `rdb db upgrade` first",
+                header.file_format_version, CURRENT_FILE_FORMAT_VERSION));
+            return Ok(report);
+        }
+        Ok(_) => {}
+    }
+
+    check_pager(Arc::new(Pager::open_with_archive(path, key, archive)?))
+}
+
+/// Checks an open database like `check_database`. Nothing else may be using
+/// `pager` meanwhile.
+pub fn check_pager(pager: Arc<Pager>) -> Result<CheckReport> {
+    let buffer_pool = Arc::new(BufferPool::new(64).with_read_ahead(0));
+    buffer_pool.register_pager(0, pager.clone());
+    let mut checker = Checker {
+        buffer_pool: buffer_pool.clone(),
+        total_pages: pager.total_pages.load(Ordering::SeqCst),
+        owners: HashMap::new(),
+        report: CheckReport::default(),
+    };
+    checker.check(&pager);
+    buffer_pool.shutdown()?;
+    Ok(checker.report)
+}
+
+struct Checker {
+    buffer_pool: Arc<BufferPool>,
+    total_pages: u32,
+    // What each page in use belongs to
+    owners: HashMap<u32, String>,
+    report: CheckReport,
+}
+
+impl Checker {
+    fn check(&mut self, pager: &Pager) {
+        self.report.pages = self.total_pages;
+        self.claim(0, "the header");
+
+        match Catalog::page_ids(&self.buffer_pool, 0) {
+            Ok(pages) => {
+                for page_id in pages {
+                    self.claim(page_id, "the catalog");
+                }
+            }
+            Err(e) => self.problem(format!("Catalog: {}", e)),
+        }
+        match pager.free_pages() {
+            Ok(pages) => {
+                for page_id in pages {
+                    self.claim(page_id, "the free list");
+                }
+            }
+            Err(e) => self.problem(format!("Free list: {}", e)),
+        }
+
+        match Catalog::load(&self.buffer_pool, 0) {
+            Ok(catalog) => {
+                let mut tables: Vec<&TableInfo> = catalog.tables.values().collect();
+                tables.sort_by(|a, b| a.name.cmp(&b.name));
+                for table in tables {
+                    self.report.tables += 1;
+                    self.check_table(table);
+                }
+            }
+            Err(e) => self.problem(format!("Catalog is unreadable: {}", e)),
+        }
+
+        // Pages nothing points at can never be reused
+        for page_id in 0..self.total_pages {
+            if !self.owners.contains_key(&page_id) {
+                self.problem(format!("Page {} is orphaned: no table uses it and it is not on the free list", page_id));
+            }
+        }
+    }
+
+    fn problem(&mut self, problem: String) {
+        self.report.problems.push(problem);
+    }
+
+    // Records that `owner` uses a page. False when the page can't be used.
+    fn claim(&mut self, page_id: u32, owner: &str) -> bool {
+        if page_id >= self.total_pages {
+            self.problem(format!("{} points at page {}, past the end of the file ({} pages)", capitalize(owner), page_id, self.total_pages));
+            return false;
+        }
+        if let Some(other) = self.owners.insert(page_id, owner.to_string()) {
+            self.problem(format!("Page {} is used by both {} and {}", page_id, other, owner));
+            return false;
+        }
+        true
+    }
+
+    fn check_table(&mut self, table: &TableInfo) {
+        let name = &table.name;
+        let buffer_pool = self.buffer_pool.clone();
+        let codec = match TupleCodec::for_table(&buffer_pool, 0, table, 0) {
+            Ok(codec) => codec,
+            Err(e) => {
+                self.problem(format!("Table {}: dictionary is unreadable: {}", name, e));
+                TupleCodec::new(table.codec, 0)
+            }
+        };
+        if let Some((first_page_id, len)) = table.dictionary {
+            self.claim_overflow(first_page_id, len, &format!("the dictionary of table {}", name));
+        }
+
+        let pk = table.columns.iter().find(|c| c.primary_key).map(|c| c.name.clone());
+        // Primary key of the row in each (page, slot)
+        let mut rows: HashMap<(u32, u16), Option<u32>> = HashMap::new();
+        let mut heap = HashSet::new();
+        let mut page_id = table.root_page_id;
+        while page_id != 0 {
+            if heap.contains(&page_id) {
+                self.problem(format!("Table {}: next_page_id of the heap chain loops back to page {}", name, page_id));
+                break;
+            }
+            heap.insert(page_id);
+            if !self.claim(page_id, &format!("the heap of table {}", name)) {
+                break;
+            }
+            let page = match buffer_pool.fetch_chain_page(GlobalPageId { db_id: 0, page_id }, slotted::next_page_id) {
+                Ok(page) => page,
+                Err(e) => {
+                    self.problem(format!("Table {}: heap page {} is unreadable: {}", name, page_id, e));
+                    break;
+                }
+            };
+            let guard = page.read();
+            let slotted = SlottedPage::read_only(&guard, &buffer_pool, 0).with_codec(&codec);
+            let layout_problems = slotted.layout_problems();
+            let damaged = !layout_problems.is_empty();
+            for problem in layout_problems {
+                self.problem(format!("Table {}, page {}: {}", name, page_id, problem));
+            }
+            // Slots of a damaged page may point at anything, so its tuples are not read
+            for slot_id in (0..slotted.num_slots()).filter(|_| !damaged) {
+                if !slotted.is_live(slot_id) {
+                    continue;
+                }
+                if let Some((first_page_id, len)) = slotted.overflow_chain(slot_id)
+                    && !self.claim_overflow(first_page_id, len, &format!("row {}:{} of table {}", page_id, slot_id, name)) {
+                        continue;
+                }
+                let row = match slotted.get_tuple(slot_id) {
+                    Ok(Some(bytes)) => serde_json::from_slice::<Value>(&bytes).map_err(|e| e.to_string()),
+                    Ok(None) => Err("it could not be decompressed".to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                match row {
+                    Ok(row) => {
+                        self.report.rows += 1;
+                        let key = pk.as_ref().and_then(|pk| row.get(pk)).and_then(|v| v.as_u64()).map(|key| key as u32);
+                        rows.insert((page_id, slot_id), key);
+                    }
+                    Err(e) => self.problem(format!("Table {}, page {}, slot {}: row is unreadable: {}", name, page_id, slot_id, e)),
+                }
+            }
+            page_id = slotted.next_page_id();
+        }
+
+        self.check_index(table, &rows);
+
+        if table.fsm_root_page_id != 0 {
+            match FreeSpaceMap::load(&buffer_pool, 0, table.fsm_root_page_id) {
+                Ok(fsm) => {
+                    for &page_id in fsm.page_ids() {
+                        self.claim(page_id, &format!("the free space map of table {}", name));
+                    }
+                    for page_id in fsm.heap_page_ids().filter(|page_id| !heap.contains(page_id)) {
+                        self.problem(format!("Table {}: free space map lists page {}, which is not in its heap", name, page_id));
+                    }
+                }
+                Err(e) => self.problem(format!("Table {}: free space map is unreadable: {}", name, e)),
+            }
+        }
+    }
+
+    // Claims the pages of an overflow chain. False when the chain is broken.
+    fn claim_overflow(&mut self, first_page_id: u32, len: usize, owner: &str) -> bool {
+        if first_page_id >= self.total_pages {
+            return self.claim(first_page_id, owner);
+        }
+        match overflow::chain_pages(&self.buffer_pool, 0, first_page_id, len) {
+            Ok(pages) => pages.into_iter().all(|page_id| self.claim(page_id, owner)),
+            Err(e) => {
+                self.problem(format!("{}: {}", capitalize(owner), e));
+                false
+            }
+        }
+    }
+
+    fn check_index(&mut self, table: &TableInfo, rows: &HashMap<(u32, u16), Option<u32>>) {
+        let name = &table.name;
+        let index = match BTreeIndex::new(self.buffer_pool.clone(), 0, table.index_root_page_id) {
+            Ok(index) => index,
+            Err(e) => return self.problem(format!("Table {}: index is unreadable: {}", name, e)),
+        };
+        let (pages, entries) = match index.walk() {
+            Ok(walked) => walked,
+            Err(e) => {
+                // At least the root is the index's
+                self.claim(table.index_root_page_id, &format!("the index of table {}", name));
+                return self.problem(format!("Table {}: {}", name, e));
+            }
+        };
+        for page_id in pages {
+            self.claim(page_id, &format!("the index of table {}", name));
+        }
+
+        let mut indexed = HashSet::new();
+        for (key, (page_id, slot_id)) in entries {
+            if rows.get(&(page_id, slot_id)) == Some(&Some(key)) {
+                indexed.insert((page_id, slot_id));
+            } else {
+                self.problem(format!("Table {}: index entry for key {} points at page {}, slot {}, which does not hold that row",
+                    name, key, page_id, slot_id));
+            }
+        }
+        for (&(page_id, slot_id), key) in rows {
+            if let Some(key) = key
+                && !indexed.contains(&(page_id, slot_id)) {
+                    self.problem(format!("Table {}: row with key {} at page {}, slot {} is missing from the index", name, key, page_id, slot_id));
+            }
+        }
+    }
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::executor::Executor;
+    use byteorder::{ByteOrder, LittleEndian};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    // Opens the database, applies `change` in one transaction and closes it again
+    fn modify(path: &Path, change: impl FnOnce(&Arc<BufferPool>, &TableInfo)) {
+        let pool = Arc::new(BufferPool::new(64));
+        pool.register_pager(0, Arc::new(Pager::open(path).unwrap()));
+        let catalog = Catalog::load(&pool, 0).unwrap();
+        pool.begin(0).unwrap();
+        change(&pool, catalog.get_table("t").unwrap());
+        pool.commit(0).unwrap();
+        pool.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_check_finds_corruption() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.db");
+        let pool = Arc::new(BufferPool::new(64));
+        pool.register_pager(0, Arc::new(Pager::create_database(&path, "test", 4096, None).unwrap()));
+        let executor = Executor::new(pool.clone());
+        let queries = [
+            json!({"op": "create_table", "database": "main", "table": "t", "columns": [{"name": "id", "type": "int", "primary_key": true}]}),
+            json!({"op": "insert", "database": "main", "table": "t",
+                "values": (0..300).map(|i| json!({"id": i, "body": "x".repeat(if i == 7 { 3000 } else { 40 })})).collect::<Vec<_>>()}),
+        ];
+        for query in queries {
+            executor.execute(serde_json::from_value(query).unwrap()).unwrap();
+        }
+        pool.shutdown().unwrap();
+        drop((executor, pool));
+
+        let report = check_database(&path, None, None).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!((report.tables, report.rows), (1, 300));
+
+        // Leak a page, point an index entry at the wrong row and break a slot
+        modify(&path, |pool, table| {
+            pool.new_page(0).unwrap();
+            let index = BTreeIndex::new(pool.clone(), 0, table.index_root_page_id).unwrap();
+            let (page_id, _) = index.search(5).unwrap().unwrap();
+            index.insert(5, (page_id, 200)).unwrap();
+            let page = pool.fetch_page(GlobalPageId { db_id: 0, page_id: table.root_page_id }).unwrap();
+            let mut guard = page.write();
+            LittleEndian::write_u16(&mut guard.data[8 + 4 * 3..8 + 4 * 3 + 2], 4090);
+            guard.dirty = true;
+        });
+        let problems = check_database(&path, None, None).unwrap().problems;
+        assert!(problems.iter().any(|p| p.contains("is orphaned")), "{:?}", problems);
+        assert!(problems.iter().any(|p| p.contains("index entry for key 5")), "{:?}", problems);
+        assert!(problems.iter().any(|p| p.contains("slot 3 points outside the page")), "{:?}", problems);
+
+        // A heap chain that loops
+        modify(&path, |pool, table| {
+            let page = pool.fetch_page(GlobalPageId { db_id: 0, page_id: table.root_page_id }).unwrap();
+            SlottedPage::new(&mut page.write()).set_next_page_id(table.root_page_id);
+        });
+        let problems = check_database(&path, None, None).unwrap().problems;
+        assert!(problems.iter().any(|p| p.contains("loops back")), "{:?}", problems);
+    }
+}
@@ -0,0 +1,172 @@
+use std::io::{Cursor, Write};
+use serde::{Deserialize, Serialize};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+use crate::storage::buffer::BufferPool;
+use crate::storage::catalog::TableInfo;
+use crate::storage::overflow;
+use anyhow::{Result, anyhow};
+
+// Codec IDs stored in the flag byte of compressed tuples. Zero is zstd without
+// a dictionary, which is what tuples from before codecs were configurable use.
+pub const CODEC_ZSTD: u8 = 0;
+pub const CODEC_LZ4: u8 = 1;
+pub const CODEC_ZSTD_DICTIONARY: u8 = 2;
+
+// Largest dictionary trained from sample rows
+const DICTIONARY_MAX_SIZE: usize = 16 * 1024;
+
+/// How a table compresses its tuples, chosen at CREATE TABLE.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "codec", rename_all = "snake_case")]
+pub enum Codec {
+    None,
+    Zstd {
+        #[serde(default)]
+        level: i32,
+    },
+    Lz4,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Zstd { level: 0 }
+    }
+}
+
+impl Codec {
+    pub fn validate(&self) -> Result<()> {
+        if let Codec::Zstd { level } = self
+            && !zstd::compression_level_range().contains(level) {
+                return Err(anyhow!("Invalid zstd level {}: must be between {} and {}",
+                    level, zstd::compression_level_range().start(), zstd::compression_level_range().end()));
+        }
+        Ok(())
+    }
+}
+
+/// Trains a zstd dictionary from sample rows. Small rows compress far better
+/// with one, since they share field names and common values.
+pub fn train_dictionary(samples: &[Vec<u8>]) -> Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, DICTIONARY_MAX_SIZE)
+        .map_err(|e| anyhow!("Could not train a dictionary from {} sample rows ({}); more samples are needed", samples.len(), e))
+}
+
+/// Compresses and decompresses the tuples of one table.
+pub struct TupleCodec {
+    codec: Codec,
+    threshold: usize,
+    dictionary: Option<(EncoderDictionary<'static>, DecoderDictionary<'static>)>,
+}
+
+impl Default for TupleCodec {
+    fn default() -> Self {
+        Self::new(Codec::default(), 64)
+    }
+}
+
+impl TupleCodec {
+    /// A codec that compresses tuples larger than `threshold` bytes.
+    pub fn new(codec: Codec, threshold: usize) -> Self {
+        Self { codec, threshold, dictionary: None }
+    }
+
+    /// Uses a zstd dictionary for new tuples and to read tuples written with it.
+    pub fn with_dictionary(mut self, dictionary: &[u8]) -> Self {
+        let level = match self.codec {
+            Codec::Zstd { level } => level,
+            _ => 0,
+        };
+        self.dictionary = Some((EncoderDictionary::copy(dictionary, level), DecoderDictionary::copy(dictionary)));
+        self
+    }
+
+    /// The codec of a table, with its dictionary loaded if it has one.
+    pub fn for_table(buffer_pool: &BufferPool, db_id: u32, table: &TableInfo, threshold: usize) -> Result<Self> {
+        let codec = Self::new(table.codec, threshold);
+        match table.dictionary {
+            Some((first_page_id, len)) => {
+                Ok(codec.with_dictionary(&overflow::read_chain(buffer_pool, db_id, first_page_id, len)?))
+            }
+            None => Ok(codec),
+        }
+    }
+
+    /// Compressed bytes and codec ID of a tuple, or `None` when it is left as
+    /// is: below the threshold, or not smaller once compressed.
+    pub fn compress(&self, data: &[u8]) -> Option<(Vec<u8>, u8)> {
+        if data.len() <= self.threshold {
+            return None;
+        }
+        let compressed = match (self.codec, &self.dictionary) {
+            (Codec::None, _) => return None,
+            (Codec::Zstd { .. }, Some((dictionary, _))) => {
+                let mut encoder = zstd::stream::write::Encoder::with_prepared_dictionary(Vec::new(), dictionary).ok()?;
+                encoder.write_all(data).ok()?;
+                (encoder.finish().ok()?, CODEC_ZSTD_DICTIONARY)
+            }
+            (Codec::Zstd { level }, None) => (zstd::encode_all(Cursor::new(data), level).ok()?, CODEC_ZSTD),
+            (Codec::Lz4, _) => (lz4_flex::compress_prepend_size(data), CODEC_LZ4),
+        };
+        (compressed.0.len() < data.len()).then_some(compressed)
+    }
+
+    /// Decompresses a tuple written with codec `codec_id`. `Ok(None)` means the
+    /// data is damaged.
+    pub fn decompress(&self, codec_id: u8, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(match codec_id {
+            CODEC_ZSTD => zstd::decode_all(Cursor::new(data)).ok(),
+            CODEC_LZ4 => lz4_flex::decompress_size_prepended(data).ok(),
+            CODEC_ZSTD_DICTIONARY => {
+                let (_, dictionary) = self.dictionary.as_ref()
+                    .ok_or(anyhow!("Tuple was compressed with a dictionary, which is not loaded"))?;
+                let mut decoder = zstd::stream::write::Decoder::with_prepared_dictionary(Vec::new(), dictionary)?;
+                match decoder.write_all(data).and_then(|_| decoder.flush()) {
+                    Ok(()) => Some(decoder.into_inner()),
+                    Err(_) => None,
+                }
+            }
+            _ => return Err(anyhow!("Unknown tuple codec {}", codec_id)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_codecs_round_trip() {
+        let row = serde_json::to_vec(&json!({"name": "a".repeat(200), "tags": ["x", "y", "z"]})).unwrap();
+        for codec in [Codec::Zstd { level: 0 }, Codec::Zstd { level: 19 }, Codec::Lz4] {
+            let codec = TupleCodec::new(codec, 64);
+            let (compressed, codec_id) = codec.compress(&row).unwrap();
+            assert!(compressed.len() < row.len());
+            assert_eq!(codec.decompress(codec_id, &compressed).unwrap().unwrap(), row);
+        }
+        assert!(TupleCodec::new(Codec::None, 64).compress(&row).is_none());
+        assert!(TupleCodec::new(Codec::Lz4, 1024).compress(&row).is_none());
+        assert!(Codec::Zstd { level: 99 }.validate().is_err());
+    }
+
+    #[test]
+    fn test_dictionary_compresses_small_rows() {
+        let rows: Vec<Vec<u8>> = (0..2000).map(|i| serde_json::to_vec(&json!({
+            "id": i, "status": (["active", "pending", "closed"])[i % 3],
+            "email": format!("user{}@example.com", i), "country": "FI",
+        })).unwrap()).collect();
+        let dictionary = train_dictionary(&rows).unwrap();
+
+        let plain = TupleCodec::new(Codec::default(), 0);
+        let trained = TupleCodec::new(Codec::default(), 0).with_dictionary(&dictionary);
+        let row = &rows[1234];
+        let (compressed, codec_id) = trained.compress(row).unwrap();
+        assert_eq!(codec_id, CODEC_ZSTD_DICTIONARY);
+        assert!(compressed.len() * 2 < row.len());
+        assert!(plain.compress(row).is_none_or(|(plain, _)| compressed.len() < plain.len()));
+        assert_eq!(trained.decompress(codec_id, &compressed).unwrap().unwrap(), *row);
+
+        // Without the dictionary the row can't be read
+        assert!(plain.decompress(codec_id, &compressed).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use anyhow::{Result, anyhow};
+
+// Environment variable holding the key as 64 hex characters
+pub const KEY_ENV_VAR: &str = "RDB_ENCRYPTION_KEY";
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+// Bytes at the end of every encrypted page: nonce + authentication tag
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+pub type EncryptionKey = [u8; KEY_SIZE];
+
+/// Looks up the encryption key: the environment variable wins over the key file.
+/// Returns `None` when neither is set.
+pub fn load_key(key_file: Option<&Path>) -> Result<Option<EncryptionKey>> {
+    if let Ok(hex_key) = std::env::var(KEY_ENV_VAR) {
+        return parse_key(hex_key.trim().as_bytes()).map(Some)
+            .map_err(|e| anyhow!("{} is invalid: {}", KEY_ENV_VAR, e));
+    }
+    match key_file {
+        Some(path) => {
+            let bytes = std::fs::read(path)
+                .map_err(|e| anyhow!("Cannot read encryption key file {:?}: {}", path, e))?;
+            parse_key(&bytes).map(Some)
+                .map_err(|e| anyhow!("Encryption key file {:?} is invalid: {}", path, e))
+        }
+        None => Ok(None),
+    }
+}
+
+// Accepts 32 raw bytes or 64 hex characters (surrounding whitespace ignored)
+fn parse_key(bytes: &[u8]) -> Result<EncryptionKey> {
+    if bytes.len() == KEY_SIZE {
+        let mut key = [0u8; KEY_SIZE];
+        key.copy_from_slice(bytes);
+        return Ok(key);
+    }
+    let text = std::str::from_utf8(bytes).map_err(|_| anyhow!("expected {} bytes or {} hex characters", KEY_SIZE, KEY_SIZE * 2))?;
+    let decoded = hex::decode(text.trim()).map_err(|_| anyhow!("expected {} hex characters", KEY_SIZE * 2))?;
+    decoded.try_into().map_err(|_| anyhow!("expected a {} byte key", KEY_SIZE))
+}
+
+/// Short fingerprint stored in the header so a wrong key is reported up front
+/// instead of as a corrupt page.
+pub fn key_check(key: &EncryptionKey) -> [u8; 16] {
+    let digest = Sha256::new()
+        .chain_update(b"rdb-key-check")
+        .chain_update(key)
+        .finalize();
+    let mut check = [0u8; 16];
+    check.copy_from_slice(&digest[..16]);
+    check
+}
+
+/// Authenticated encryption of page images with XChaCha20-Poly1305.
+/// The page ID is bound as associated data, so pages can't be swapped.
+pub struct PageCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl PageCipher {
+    pub fn new(key: &EncryptionKey) -> Self {
+        Self { cipher: XChaCha20Poly1305::new(Key::from_slice(key)) }
+    }
+
+    /// Encrypts a page image into `page.len() + ENCRYPTION_OVERHEAD` bytes.
+    pub fn encrypt(&self, page_id: u32, page: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::rng().fill(&mut nonce[..]);
+
+        let mut out = Vec::with_capacity(page.len() + ENCRYPTION_OVERHEAD);
+        out.extend_from_slice(page);
+        let tag = self.cipher
+            .encrypt_in_place_detached(XNonce::from_slice(&nonce), &page_id.to_le_bytes(), &mut out)
+            .map_err(|_| anyhow!("Failed to encrypt page {}", page_id))?;
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+
+    /// Reverses `encrypt`. Fails if the bytes were modified or the key is wrong.
+    pub fn decrypt(&self, page_id: u32, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < ENCRYPTION_OVERHEAD {
+            return Err(anyhow!("Encrypted page {} is too short", page_id));
+        }
+        let body_len = data.len() - ENCRYPTION_OVERHEAD;
+        let nonce = &data[body_len..body_len + NONCE_SIZE];
+        let tag = &data[body_len + NONCE_SIZE..];
+
+        let mut out = data[..body_len].to_vec();
+        self.cipher
+            .decrypt_in_place_detached(XNonce::from_slice(nonce), &page_id.to_le_bytes(), &mut out, Tag::from_slice(tag))
+            .map_err(|_| anyhow!("Page {} failed authentication", page_id))?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_cipher_round_trip() {
+        let cipher = PageCipher::new(&[7u8; KEY_SIZE]);
+        let page = vec![42u8; 4096 - ENCRYPTION_OVERHEAD];
+
+        let encrypted = cipher.encrypt(3, &page).unwrap();
+        assert_eq!(encrypted.len(), 4096);
+        assert!(!encrypted.windows(64).any(|w| w.iter().all(|&b| b == 42)));
+        assert_eq!(cipher.decrypt(3, &encrypted).unwrap(), page);
+
+        // Tampering, a different page ID or a different key are all rejected
+        let mut tampered = encrypted.clone();
+        tampered[10] ^= 1;
+        assert!(cipher.decrypt(3, &tampered).is_err());
+        assert!(cipher.decrypt(4, &encrypted).is_err());
+        assert!(PageCipher::new(&[8u8; KEY_SIZE]).decrypt(3, &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let hex_key = "00".repeat(31) + "ff";
+        assert_eq!(parse_key(hex_key.as_bytes()).unwrap()[31], 0xff);
+        assert_eq!(parse_key(format!("{}\n", hex_key).as_bytes()).unwrap()[31], 0xff);
+        assert!(parse_key(b"too short").is_err());
+    }
+}
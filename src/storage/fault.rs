@@ -0,0 +1,354 @@
+use std::io;
+use std::sync::Arc;
+use crate::storage::store::PageStore;
+use parking_lot::Mutex;
+
+// A page store for crash-consistency tests. It keeps what a disk would: the
+// bytes reads see, and the bytes that survive a crash, which only catch up
+// at `sync`. The stores of a database and its log share one `Faults`, which
+// counts their writes and picks the one that goes wrong.
+
+/// What happens to the write a fault is injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The write fails and changes nothing
+    Fail,
+    /// The write reports success but changes nothing
+    Drop,
+    /// The machine stops before the write: it and everything after it fails
+    Crash,
+    /// The machine stops halfway through the write, after the first half of
+    /// it reached the disk
+    Tear,
+}
+
+/// Writes of a group of stores, and the fault planned for one of them.
+#[derive(Default)]
+pub struct Faults {
+    state: Mutex<FaultState>,
+    disks: Mutex<Vec<Arc<Mutex<Disk>>>>,
+}
+
+#[derive(Default)]
+struct FaultState {
+    writes: u64,
+    planned: Option<(u64, Fault)>,
+    crashed: bool,
+}
+
+#[derive(Default)]
+struct Disk {
+    bytes: Vec<u8>,
+    durable: Vec<u8>,
+}
+
+impl Faults {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Makes the write `after` writes from now go wrong as `fault`.
+    pub fn inject(&self, after: u64, fault: Fault) {
+        let mut state = self.state.lock();
+        state.planned = Some((state.writes + after, fault));
+    }
+
+    /// Writes so far, the failed ones included.
+    pub fn writes(&self) -> u64 {
+        self.state.lock().writes
+    }
+
+    /// Whether the machine has stopped.
+    pub fn crashed(&self) -> bool {
+        self.state.lock().crashed
+    }
+
+    /// Turns the machine back on after a crash: every store loses what it
+    /// wasn't told to sync, and no fault is planned any more.
+    pub fn restart(&self) {
+        for disk in self.disks.lock().iter() {
+            let mut disk = disk.lock();
+            disk.bytes = disk.durable.clone();
+        }
+        let mut state = self.state.lock();
+        state.planned = None;
+        state.crashed = false;
+    }
+
+    // Counts a write and tells whether it goes wrong
+    fn next_write(&self) -> io::Result<Option<Fault>> {
+        let mut state = self.state.lock();
+        if state.crashed {
+            return Err(crashed());
+        }
+        let index = state.writes;
+        state.writes += 1;
+        let fault = match state.planned {
+            Some((at, fault)) if at == index => fault,
+            _ => return Ok(None),
+        };
+        state.planned = None;
+        state.crashed = matches!(fault, Fault::Crash | Fault::Tear);
+        Ok(Some(fault))
+    }
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("the machine crashed")
+}
+
+/// A store in memory whose writes can go wrong.
+pub struct FaultyStore {
+    faults: Arc<Faults>,
+    disk: Arc<Mutex<Disk>>,
+}
+
+impl FaultyStore {
+    /// A new, empty store whose writes `faults` counts.
+    pub fn new(faults: &Arc<Faults>) -> Arc<Self> {
+        let disk = Arc::new(Mutex::new(Disk::default()));
+        faults.disks.lock().push(disk.clone());
+        Arc::new(Self { faults: faults.clone(), disk })
+    }
+}
+
+impl PageStore for FaultyStore {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let disk = self.disk.lock();
+        let start = offset as usize;
+        match disk.bytes.get(start..start + buf.len()) {
+            Some(stored) => {
+                buf.copy_from_slice(stored);
+                Ok(())
+            }
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        let fault = self.faults.next_write()?;
+        let mut disk = self.disk.lock();
+        match fault {
+            None => {
+                write(&mut disk.bytes, data, offset);
+                Ok(())
+            }
+            Some(Fault::Fail) => Err(io::Error::other("injected write failure")),
+            Some(Fault::Drop) => Ok(()),
+            Some(Fault::Crash) => Err(crashed()),
+            Some(Fault::Tear) => {
+                let half = &data[..data.len() / 2];
+                write(&mut disk.bytes, half, offset);
+                write(&mut disk.durable, half, offset);
+                Err(crashed())
+            }
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.disk.lock().bytes.len() as u64)
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        // Counts as a write; it can't be torn
+        match self.faults.next_write()? {
+            None => {
+                self.disk.lock().bytes.resize(size as usize, 0);
+                Ok(())
+            }
+            Some(Fault::Drop) => Ok(()),
+            Some(Fault::Fail) => Err(io::Error::other("injected write failure")),
+            Some(Fault::Crash | Fault::Tear) => Err(crashed()),
+        }
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        if self.faults.crashed() {
+            return Err(crashed());
+        }
+        let mut disk = self.disk.lock();
+        disk.durable = disk.bytes.clone();
+        Ok(())
+    }
+}
+
+fn write(bytes: &mut Vec<u8>, data: &[u8], offset: u64) {
+    let start = offset as usize;
+    if bytes.len() < start + data.len() {
+        bytes.resize(start + data.len(), 0);
+    }
+    bytes[start..start + data.len()].copy_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::executor::{Executor, ExecutionResult};
+    use crate::storage::buffer::BufferPool;
+    use crate::storage::check;
+    use crate::storage::pager::Pager;
+    use crate::query::test_util::run;
+    use serde_json::{Value, json};
+    use std::collections::BTreeMap;
+
+    // Contents of the test table: id -> (n, body)
+    type Rows = BTreeMap<i64, (i64, String)>;
+    type Step = (Value, fn(&mut Rows));
+
+    fn values(ids: std::ops::Range<i64>, body: &str) -> Value {
+        ids.map(|id| json!({"id": id, "n": id, "body": body})).collect()
+    }
+
+    // Queries that insert, grow, move, delete and vacuum rows, each with what
+    // it does to the table
+    fn workload() -> Vec<Step> {
+        vec![
+            (json!({"op": "insert", "database": "main", "table": "t", "values": values(0..60, &"a".repeat(200))}),
+                |rows| rows.extend((0..60).map(|id| (id, (id, "a".repeat(200)))))),
+            (json!({"op": "update", "database": "main", "table": "t", "set": {"n": -1}, "where": {"column": "id", "cmp": "<", "value": 20}}),
+                |rows| rows.range_mut(..20).for_each(|(_, row)| row.0 = -1)),
+            (json!({"op": "delete", "database": "main", "table": "t", "where": {"column": "id", "cmp": ">=", "value": 40}}),
+                |rows| rows.retain(|id, _| *id < 40)),
+            (json!({"op": "insert", "database": "main", "table": "t", "values": values(60..100, &"b".repeat(1500))}),
+                |rows| rows.extend((60..100).map(|id| (id, (id, "b".repeat(1500)))))),
+            (json!({"op": "update", "database": "main", "table": "t", "set": {"body": "c".repeat(900)}, "where": {"column": "id", "cmp": "<", "value": 10}}),
+                |rows| rows.range_mut(..10).for_each(|(_, row)| row.1 = "c".repeat(900))),
+            (json!({"op": "delete", "database": "main", "table": "t", "where": {"column": "id", "cmp": ">=", "value": 70}}),
+                |rows| rows.retain(|id, _| *id < 70)),
+            (json!({"op": "vacuum", "database": "main"}), |_| {}),
+        ]
+    }
+
+    fn read_rows(executor: &Executor) -> Rows {
+        let query = json!({"op": "select", "database": "main", "from": "t", "columns": ["id", "n", "body"]});
+        match run(executor, query) {
+            ExecutionResult::Json(Value::Array(rows)) => rows.iter()
+                .map(|row| (row["id"].as_i64().unwrap(), (row["n"].as_i64().unwrap(), row["body"].as_str().unwrap().to_string())))
+                .collect(),
+            _ => panic!(),
+        }
+    }
+
+    fn executor(pager: Arc<Pager>) -> Executor {
+        // A small pool writes pages out in the middle of transactions
+        let pool = Arc::new(BufferPool::new(16).with_read_ahead(0));
+        pool.register_pager(0, pager);
+        Executor::new(pool)
+    }
+
+    struct Run {
+        faults: Arc<Faults>,
+        stores: (Arc<FaultyStore>, Arc<FaultyStore>),
+        // Every state the table may be in afterwards
+        states: Vec<Rows>,
+        // Writes of the workload and of closing the database
+        writes: u64,
+    }
+
+    // Runs the workload on a new database, with `fault` injected into a write
+    // of it, then drops the database
+    fn run_workload(fault: Option<(u64, Fault)>) -> Run {
+        let faults = Faults::new();
+        let stores = (FaultyStore::new(&faults), FaultyStore::new(&faults));
+        let pager = Pager::create_database_in("main", stores.0.clone(), stores.1.clone(), 4096, None).unwrap();
+        let executor = executor(Arc::new(pager));
+        run(&executor, json!({"op": "create_table", "database": "main", "table": "t", "columns": [
+            {"name": "id", "type": "int", "primary_key": true}, {"name": "n", "type": "int"}, {"name": "body", "type": "string"}]}));
+
+        let start = faults.writes();
+        if let Some((at, fault)) = fault {
+            faults.inject(at, fault);
+        }
+        let mut states = vec![Rows::new()];
+        for (query, apply) in workload() {
+            if faults.crashed() {
+                break;
+            }
+            let result = executor.execute(serde_json::from_value(query).unwrap());
+            let mut applied = states.clone();
+            applied.iter_mut().for_each(apply);
+            // A query that failed may still have committed, e.g. when its
+            // commit record was written but syncing the log failed
+            match result {
+                Ok(_) => states = applied,
+                Err(_) => states.extend(applied),
+            }
+            states.sort();
+            states.dedup();
+        }
+        drop(executor);
+        let writes = faults.writes() - start;
+        Run { faults, stores, states, writes }
+    }
+
+    // Reopens the database a run left behind and checks it holds one of the
+    // states the run allows, and still takes writes
+    fn reopen_and_check(outcome: Run, what: &str) {
+        let (db, log) = outcome.stores;
+        let pager = Arc::new(Pager::open_store("main", db, log, None)
+            .unwrap_or_else(|e| panic!("{}: reopening failed: {}", what, e)));
+        let report = check::check_pager(pager.clone()).unwrap();
+        assert!(report.is_clean(), "{}: {:?}", what, report.problems);
+
+        let executor = executor(pager);
+        let rows = read_rows(&executor);
+        assert!(outcome.states.contains(&rows), "{}: unexpected rows {:?}", what, rows.keys().collect::<Vec<_>>());
+        run(&executor, json!({"op": "insert", "database": "main", "table": "t", "values": values(1000..1001, "d")}));
+        assert_eq!(read_rows(&executor).len(), rows.len() + 1, "{}", what);
+    }
+
+    #[test]
+    fn test_crash_at_every_write_recovers() {
+        let clean = run_workload(None);
+        assert_eq!(clean.states.len(), 1);
+        let writes = clean.writes;
+        reopen_and_check(clean, "no fault");
+
+        for at in 0..writes {
+            for fault in [Fault::Crash, Fault::Tear] {
+                let run = run_workload(Some((at, fault)));
+                assert!(run.faults.crashed());
+                run.faults.restart();
+                reopen_and_check(run, &format!("{:?} at write {} of {}", fault, at, writes));
+            }
+        }
+    }
+
+    #[test]
+    fn test_failed_writes_leave_database_consistent() {
+        let writes = run_workload(None).writes;
+        for at in 0..writes {
+            let run = run_workload(Some((at, Fault::Fail)));
+            reopen_and_check(run, &format!("Failure at write {} of {}", at, writes));
+        }
+    }
+
+    #[test]
+    fn test_dropped_writes_are_noticed() {
+        let writes = run_workload(None).writes;
+        let mut detected = 0;
+        for at in 0..writes {
+            let run = run_workload(Some((at, Fault::Drop)));
+            let what = format!("Dropped write {} of {}", at, writes);
+            let (db, log) = run.stores;
+            // A lost write may break the database, but then reopening or the
+            // check must find it. Otherwise the rows must be intact.
+            let pager = match Pager::open_store("main", db, log, None) {
+                Ok(pager) => Arc::new(pager),
+                Err(_) => {
+                    detected += 1;
+                    continue;
+                }
+            };
+            if !check::check_pager(pager.clone()).unwrap().is_clean() {
+                detected += 1;
+                continue;
+            }
+            let rows = read_rows(&executor(pager));
+            assert!(run.states.contains(&rows), "{}: unexpected rows {:?}", what, rows.keys().collect::<Vec<_>>());
+        }
+        // Losing a write that is overwritten later does no harm, but not every
+        // write is
+        assert!(detected > 0, "no dropped write of {} was noticed", writes);
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::{BTreeSet, HashMap};
+use crate::storage::buffer::{BufferPool, GlobalPageId};
+use crate::storage::page::PAGE_CHECKSUM_SIZE;
+use crate::storage::slotted::{self, SlottedPage};
+use byteorder::{LittleEndian, ByteOrder};
+use anyhow::{Result, anyhow};
+
+// A table's free space map lists its heap pages with roughly how much room
+// each has left, so inserts go straight to a page that fits. It is stored
+// across a chain of pages: every page starts with the ID of the next one
+// (0 = last), its entry count (u16) and, on the root page, the last page of
+// the heap chain (u32). Entries are a heap page ID (u32) and its free space in
+// 1/256ths of a page (u8). All pages but the last are full.
+const PAGE_HEADER_SIZE: usize = 10;
+const ENTRY_SIZE: usize = 5;
+
+pub struct FreeSpaceMap {
+    db_id: u32,
+    root_page_id: u32,
+    // Pages of the map, root first
+    chain: Vec<u32>,
+    entries: Vec<(u32, u8)>,
+    positions: HashMap<u32, usize>,
+    tail_page_id: u32,
+    usable: usize,
+    // Pages of the map (by position in the chain) that need writing
+    dirty: BTreeSet<usize>,
+}
+
+impl FreeSpaceMap {
+    /// Allocates an empty map for a heap chain that ends at `tail_page_id`.
+    pub fn create(buffer_pool: &BufferPool, db_id: u32, tail_page_id: u32) -> Result<Self> {
+        let root_page_id = buffer_pool.new_page(db_id)?.read().id;
+        Ok(Self {
+            db_id,
+            root_page_id,
+            chain: vec![root_page_id],
+            entries: Vec::new(),
+            positions: HashMap::new(),
+            tail_page_id,
+            usable: buffer_pool.page_size(db_id)? - PAGE_CHECKSUM_SIZE,
+            dirty: BTreeSet::from([0]),
+        })
+    }
+
+    /// Builds and saves a map for the heap chain starting at `heap_root_page_id`,
+    /// for tables created before free space maps existed.
+    pub fn build(buffer_pool: &BufferPool, db_id: u32, heap_root_page_id: u32) -> Result<Self> {
+        let mut fsm = Self::create(buffer_pool, db_id, heap_root_page_id)?;
+        let mut page_id = heap_root_page_id;
+        while page_id != 0 {
+            let page = buffer_pool.fetch_chain_page(GlobalPageId { db_id, page_id }, slotted::next_page_id)?;
+            let guard = page.read();
+            let slotted = SlottedPage::read_only(&guard, buffer_pool, db_id);
+            fsm.record(page_id, slotted.available_space());
+            fsm.tail_page_id = page_id;
+            page_id = slotted.next_page_id();
+        }
+        fsm.save(buffer_pool)?;
+        Ok(fsm)
+    }
+
+    pub fn load(buffer_pool: &BufferPool, db_id: u32, root_page_id: u32) -> Result<Self> {
+        let usable = buffer_pool.page_size(db_id)? - PAGE_CHECKSUM_SIZE;
+        let per_page = (usable - PAGE_HEADER_SIZE) / ENTRY_SIZE;
+        let mut fsm = Self {
+            db_id,
+            root_page_id,
+            chain: Vec::new(),
+            entries: Vec::new(),
+            positions: HashMap::new(),
+            tail_page_id: 0,
+            usable,
+            dirty: BTreeSet::new(),
+        };
+
+        let mut packed = true;
+        let mut page_id = root_page_id;
+        while page_id != 0 {
+            if fsm.chain.contains(&page_id) {
+                return Err(anyhow!("Free space map page chain has a cycle at page {}", page_id));
+            }
+            fsm.chain.push(page_id);
+            let page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id })?;
+            let guard = page.read();
+            let count = LittleEndian::read_u16(&guard.data[4..6]) as usize;
+            if count > per_page {
+                return Err(anyhow!("Free space map page {} is damaged: {} entries", page_id, count));
+            }
+            if page_id == root_page_id {
+                fsm.tail_page_id = LittleEndian::read_u32(&guard.data[6..10]);
+            }
+            for i in 0..count {
+                let offset = PAGE_HEADER_SIZE + i * ENTRY_SIZE;
+                let heap_page_id = LittleEndian::read_u32(&guard.data[offset..offset + 4]);
+                fsm.positions.insert(heap_page_id, fsm.entries.len());
+                fsm.entries.push((heap_page_id, guard.data[offset + 4]));
+            }
+            page_id = LittleEndian::read_u32(&guard.data[0..4]);
+            packed &= count == per_page || page_id == 0;
+        }
+        // Entries are placed by position, so a gap means rewriting every page
+        if !packed {
+            fsm.dirty.extend(0..fsm.chain.len());
+        }
+        Ok(fsm)
+    }
+
+    pub fn root_page_id(&self) -> u32 {
+        self.root_page_id
+    }
+
+    /// Pages the map itself is stored on, root first.
+    pub fn page_ids(&self) -> &[u32] {
+        &self.chain
+    }
+
+    /// Heap pages the map lists.
+    pub fn heap_page_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.entries.iter().map(|(page_id, _)| *page_id)
+    }
+
+    /// Last page of the heap chain, where new pages are linked.
+    pub fn tail_page_id(&self) -> u32 {
+        self.tail_page_id
+    }
+
+    pub fn set_tail_page_id(&mut self, page_id: u32) {
+        if self.tail_page_id != page_id {
+            self.tail_page_id = page_id;
+            self.dirty.insert(0);
+        }
+    }
+
+    fn per_page(&self) -> usize {
+        (self.usable - PAGE_HEADER_SIZE) / ENTRY_SIZE
+    }
+
+    // Free space rounded down to 1/256ths of a page, so a page never has less
+    // room than its entry says
+    fn category(&self, bytes: usize) -> u8 {
+        (bytes * 256 / self.usable).min(255) as u8
+    }
+
+    /// Heap pages in the map.
+    pub fn page_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Share of the table's heap space that is free, from 0 to 1.
+    pub fn free_ratio(&self) -> f64 {
+        if self.entries.is_empty() {
+            return 0.0;
+        }
+        let free: usize = self.entries.iter().map(|(_, category)| *category as usize).sum();
+        free as f64 / (self.entries.len() * 256) as f64
+    }
+
+    /// A heap page with at least `needed` bytes free, if the map knows one.
+    pub fn find(&self, needed: usize) -> Option<u32> {
+        let wanted = (needed * 256).div_ceil(self.usable);
+        self.entries.iter()
+            .find(|(_, category)| *category as usize >= wanted)
+            .map(|(page_id, _)| *page_id)
+    }
+
+    /// Records how many bytes a heap page has free, adding it if it is new.
+    pub fn record(&mut self, page_id: u32, available: usize) {
+        let category = self.category(available);
+        let per_page = self.per_page();
+        match self.positions.get(&page_id) {
+            Some(&i) => {
+                if self.entries[i].1 != category {
+                    self.entries[i].1 = category;
+                    self.dirty.insert(i / per_page);
+                }
+            }
+            None => {
+                self.positions.insert(page_id, self.entries.len());
+                self.entries.push((page_id, category));
+                self.dirty.insert((self.entries.len() - 1) / per_page);
+            }
+        }
+    }
+
+    /// Forgets a heap page that left the chain.
+    pub fn remove(&mut self, page_id: u32) {
+        let Some(i) = self.positions.remove(&page_id) else {
+            return;
+        };
+        // The last entry moves into the gap, keeping every page but the last full
+        let per_page = self.per_page();
+        self.dirty.insert(i / per_page);
+        self.dirty.insert((self.entries.len() - 1) / per_page);
+        self.entries.swap_remove(i);
+        if let Some(&(moved, _)) = self.entries.get(i) {
+            self.positions.insert(moved, i);
+        }
+    }
+
+    /// Writes the changed pages of the map, growing or shrinking its chain as needed.
+    pub fn save(&mut self, buffer_pool: &BufferPool) -> Result<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let per_page = self.per_page();
+        let needed = self.entries.len().div_ceil(per_page).max(1);
+
+        while self.chain.len() < needed {
+            let page_id = buffer_pool.new_page(self.db_id)?.read().id;
+            // The previous last page now points at the new one
+            self.dirty.insert(self.chain.len() - 1);
+            self.dirty.insert(self.chain.len());
+            self.chain.push(page_id);
+        }
+        if self.chain.len() > needed {
+            for page_id in self.chain.drain(needed..) {
+                buffer_pool.free_page(self.db_id, page_id)?;
+            }
+            self.dirty.insert(needed - 1);
+        }
+
+        for index in std::mem::take(&mut self.dirty) {
+            let Some(&page_id) = self.chain.get(index) else {
+                continue;
+            };
+            let entries = self.entries.iter().skip(index * per_page).take(per_page);
+            let page = buffer_pool.fetch_page(GlobalPageId { db_id: self.db_id, page_id })?;
+            let mut guard = page.write();
+            guard.data[..self.usable].fill(0);
+            LittleEndian::write_u32(&mut guard.data[0..4], self.chain.get(index + 1).copied().unwrap_or(0));
+            LittleEndian::write_u16(&mut guard.data[4..6], entries.len() as u16);
+            if index == 0 {
+                LittleEndian::write_u32(&mut guard.data[6..10], self.tail_page_id);
+            }
+            for (i, &(heap_page_id, category)) in entries.enumerate() {
+                let offset = PAGE_HEADER_SIZE + i * ENTRY_SIZE;
+                LittleEndian::write_u32(&mut guard.data[offset..offset + 4], heap_page_id);
+                guard.data[offset + 4] = category;
+            }
+            guard.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Returns the map's pages to the free list.
+    pub fn destroy(self, buffer_pool: &BufferPool) -> Result<()> {
+        for page_id in self.chain {
+            buffer_pool.free_page(self.db_id, page_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::pager::Pager;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_free_space_map_spans_pages() {
+        let temp_dir = TempDir::new().unwrap();
+        let pager = Arc::new(Pager::create_database(&temp_dir.path().join("test.db"), "test", 4096, None).unwrap());
+        let pool = BufferPool::new(16);
+        pool.register_pager(0, pager);
+        let usable = pool.page_size(0).unwrap() - PAGE_CHECKSUM_SIZE;
+
+        // Enough heap pages for several map pages, with only every 100th roomy
+        let mut fsm = FreeSpaceMap::create(&pool, 0, 1).unwrap();
+        for page_id in 1..=5000 {
+            fsm.record(page_id, if page_id % 100 == 0 { usable / 2 } else { 10 });
+        }
+        fsm.set_tail_page_id(5000);
+        fsm.save(&pool).unwrap();
+        assert!(fsm.chain.len() > 1);
+
+        let mut loaded = FreeSpaceMap::load(&pool, 0, fsm.root_page_id()).unwrap();
+        assert_eq!(loaded.tail_page_id(), 5000);
+        assert_eq!(loaded.find(usable / 4), Some(100));
+        assert_eq!(loaded.find(usable), None);
+        // Entries never claim more room than the page has
+        assert_eq!(loaded.find(usable / 2 + 1), None);
+
+        // Removing pages shrinks the map and keeps the rest findable
+        for page_id in 1..=4990 {
+            loaded.remove(page_id);
+        }
+        loaded.record(4995, usable);
+        loaded.save(&pool).unwrap();
+        let reloaded = FreeSpaceMap::load(&pool, 0, fsm.root_page_id()).unwrap();
+        assert_eq!(reloaded.chain.len(), 1);
+        assert_eq!(reloaded.entries.len(), 10);
+        assert_eq!(reloaded.find(usable / 2 + 1), Some(4995));
+        assert!(matches!(reloaded.find(usable / 4), Some(4995 | 5000)));
+    }
+}
@@ -1,11 +1,15 @@
 use serde::{Deserialize, Serialize};
 use std::io::{Cursor, Read, Write};
+use std::path::Path;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use crate::storage::page::PAGE_SIZE;
+use crate::storage::page;
 use anyhow::{Result, anyhow};
 
 pub const MAGIC: &[u8; 7] = b"RDBFILE";
-pub const CURRENT_FILE_FORMAT_VERSION: u32 = 1;
+// v2: every page ends with a CRC32 checksum
+// v3: the catalog is a length-prefixed page chain
+// v4: large tuples spill into overflow page chains
+pub const CURRENT_FILE_FORMAT_VERSION: u32 = 4;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DatabaseHeader {
@@ -20,15 +24,27 @@ pub struct DatabaseHeader {
     pub wal_enabled: bool,
     pub encryption: bool,
     pub root_catalog_page: u32,
+    // Head of the free-page list (0 = empty)
+    pub free_list_head: u32,
+    pub free_page_count: u32,
+    // Fingerprint of the encryption key (all zeros when not encrypted)
+    pub key_check: [u8; 16],
+    // Set by full checkpoints, after which the file holds every committed
+    // change (0 = never checkpointed)
+    pub last_checkpoint_at: i64,
+    pub checkpoint_count: u64,
+    // Set while an engine has the file open and cleared by a clean shutdown,
+    // so finding it set on open means the last session crashed
+    pub in_use: bool,
 }
 
 impl DatabaseHeader {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, page_size: usize) -> Self {
         Self {
             magic: *MAGIC,
             file_format_version: CURRENT_FILE_FORMAT_VERSION,
             rdb_engine_version: env!("CARGO_PKG_VERSION").to_string(),
-            page_size: PAGE_SIZE as u32,
+            page_size: page_size as u32,
             created_at: chrono::Utc::now().timestamp(),
             last_opened_at: chrono::Utc::now().timestamp(),
             last_opened_with_engine: env!("CARGO_PKG_VERSION").to_string(),
@@ -36,11 +52,18 @@ impl DatabaseHeader {
             wal_enabled: true,
             encryption: false,
             root_catalog_page: 1, // Page 1 is usually the start of the catalog
+            free_list_head: 0,
+            free_page_count: 0,
+            key_check: [0; 16],
+            last_checkpoint_at: 0,
+            checkpoint_count: 0,
+            in_use: false,
         }
     }
 
-    pub fn to_bytes(&self) -> Result<[u8; PAGE_SIZE]> {
-        let mut bytes = [0u8; PAGE_SIZE];
+    /// Serializes the header into a full page image of `page_size` bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; self.page_size as usize];
         let mut cursor = Cursor::new(&mut bytes[..]);
 
         cursor.write_all(&self.magic)?;
@@ -65,13 +88,20 @@ impl DatabaseHeader {
         cursor.write_u8(if self.wal_enabled { 1 } else { 0 })?;
         cursor.write_u8(if self.encryption { 1 } else { 0 })?;
         cursor.write_u32::<LittleEndian>(self.root_catalog_page)?;
+        cursor.write_u32::<LittleEndian>(self.free_list_head)?;
+        cursor.write_u32::<LittleEndian>(self.free_page_count)?;
+        cursor.write_all(&self.key_check)?;
+        cursor.write_i64::<LittleEndian>(self.last_checkpoint_at)?;
+        cursor.write_u64::<LittleEndian>(self.checkpoint_count)?;
+        cursor.write_u8(if self.in_use { 1 } else { 0 })?;
 
         Ok(bytes)
     }
 
-    #[allow(dead_code)]
-    pub fn from_bytes(bytes: &[u8; PAGE_SIZE]) -> Result<Self> {
-        let mut cursor = Cursor::new(&bytes[..]);
+    /// Parses a header from the start of page 0. The fields fit in the smallest
+    /// page size, so this can be used to learn the page size of a file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(bytes);
         
         let mut magic = [0u8; 7];
         cursor.read_exact(&mut magic)?;
@@ -87,9 +117,7 @@ impl DatabaseHeader {
         let rdb_engine_version = String::from_utf8(engine_ver_bytes)?;
 
         let page_size = cursor.read_u32::<LittleEndian>()?;
-        if page_size as usize != PAGE_SIZE {
-             return Err(anyhow!("Page size mismatch"));
-        }
+        page::validate_page_size(page_size as usize)?;
 
         let created_at = cursor.read_i64::<LittleEndian>()?;
         let last_opened_at = cursor.read_i64::<LittleEndian>()?;
@@ -107,6 +135,14 @@ impl DatabaseHeader {
         let wal_enabled = cursor.read_u8()? != 0;
         let encryption = cursor.read_u8()? != 0;
         let root_catalog_page = cursor.read_u32::<LittleEndian>()?;
+        let free_list_head = cursor.read_u32::<LittleEndian>()?;
+        let free_page_count = cursor.read_u32::<LittleEndian>()?;
+        let mut key_check = [0u8; 16];
+        cursor.read_exact(&mut key_check)?;
+        // Older files have zero padding here
+        let last_checkpoint_at = cursor.read_i64::<LittleEndian>()?;
+        let checkpoint_count = cursor.read_u64::<LittleEndian>()?;
+        let in_use = cursor.read_u8()? != 0;
 
         Ok(Self {
             magic,
@@ -120,6 +156,25 @@ impl DatabaseHeader {
             wal_enabled,
             encryption,
             root_catalog_page,
+            free_list_head,
+            free_page_count,
+            key_check,
+            last_checkpoint_at,
+            checkpoint_count,
+            in_use,
         })
     }
+
+    /// Reads the header of a database file without opening it. Page 0 is never
+    /// encrypted, so this works for every database.
+    pub fn read_from_file(path: &Path) -> Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        Self::read_from(&mut file)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut buffer = Vec::with_capacity(page::MAX_PAGE_SIZE);
+        reader.take(page::MAX_PAGE_SIZE as u64).read_to_end(&mut buffer)?;
+        Self::from_bytes(&buffer)
+    }
 }
@@ -1,7 +1,8 @@
-use crate::storage::page::{Page, PAGE_SIZE};
+use crate::storage::page::PAGE_CHECKSUM_SIZE;
 use crate::storage::buffer::{BufferPool, GlobalPageId};
 use byteorder::{LittleEndian, ByteOrder};
 use anyhow::{Result, anyhow};
+use std::collections::HashSet;
 use std::sync::Arc;
 
 // B+ Tree Constants
@@ -10,297 +11,358 @@ const KEY_SIZE: usize = 4; // u32 keys for now
 const PTR_SIZE: usize = 4; // u32 page_id
 const VALUE_SIZE: usize = 6; // PageID(4) + SlotID(2)
 
-// Max keys per node (simplified calculation)
-// Internal: Key + Ptr. Leaf: Key + Value.
-const LEAF_ORDER: usize = (PAGE_SIZE - HEADER_SIZE) / (KEY_SIZE + VALUE_SIZE);
+// Max keys per node, given the usable bytes of a page
+// Leaf: [K V] [K V] ...  Internal: [P0] [K1 P1] [K2 P2] ... [Kn Pn]
+fn leaf_order(usable_size: usize) -> usize {
+    (usable_size - HEADER_SIZE) / (KEY_SIZE + VALUE_SIZE)
+}
+
+fn internal_order(usable_size: usize) -> usize {
+    (usable_size - HEADER_SIZE - PTR_SIZE) / (KEY_SIZE + PTR_SIZE)
+}
 
+/// A leaf entry: key and the (page, slot) of its row.
+pub type Entry = (u32, (u32, u16));
+
+/// A B+ tree over u32 keys. The root stays on the page it was created on; when
+/// it splits its contents move to a new page and the root becomes their parent.
 pub struct BTreeIndex {
     buffer_pool: Arc<BufferPool>,
     db_id: u32,
     root_page_id: u32,
+    leaf_order: usize,
+    internal_order: usize,
 }
 
 impl BTreeIndex {
-    pub fn new(buffer_pool: Arc<BufferPool>, db_id: u32, root_page_id: u32) -> Self {
-        Self { buffer_pool, db_id, root_page_id }
+    pub fn new(buffer_pool: Arc<BufferPool>, db_id: u32, root_page_id: u32) -> Result<Self> {
+        let usable_size = buffer_pool.page_size(db_id)? - PAGE_CHECKSUM_SIZE;
+        Ok(Self {
+            buffer_pool,
+            db_id,
+            root_page_id,
+            leaf_order: leaf_order(usable_size),
+            internal_order: internal_order(usable_size),
+        })
     }
 
     pub fn init(&self) -> Result<()> {
-        let page = self.buffer_pool.fetch_page(GlobalPageId { db_id: self.db_id, page_id: self.root_page_id })?;
-        let mut guard = page.write();
-        let mut node = BTreeNode::new(&mut guard);
-        node.init(true); // Root starts as leaf
-        Ok(())
+        // Root starts as leaf
+        self.store(self.root_page_id, &Node::Leaf { entries: Vec::new(), next_leaf: 0 })
     }
 
     pub fn insert(&self, key: u32, value: (u32, u16)) -> Result<()> {
-        // 1. Find leaf
-        let leaf_page_id = self.find_leaf(key)?;
-        let leaf_page = self.buffer_pool.fetch_page(GlobalPageId { db_id: self.db_id, page_id: leaf_page_id })?;
-        let mut leaf_guard = leaf_page.write();
-        let mut leaf_node = BTreeNode::new(&mut leaf_guard);
-
-        // 2. Insert into leaf
-        if leaf_node.num_keys() < LEAF_ORDER as u16 {
-            leaf_node.insert_leaf(key, value);
-            return Ok(());
+        // 1. Find leaf and insert into it
+        let (path, leaf_id) = self.find_path(key)?;
+        let mut node = self.load(leaf_id)?;
+        if let Node::Leaf { entries, .. } = &mut node {
+            let pos = entries.partition_point(|(k, _)| *k <= key);
+            entries.insert(pos, (key, value));
         }
-
-        // 3. Split leaf (Simplified: just error for now or implement split)
-        // Implementing full split is complex. For this demo, let's just error if full or do a simple split.
-        // "Advanced algorithms" -> I should try to implement split.
-        
-        // Split Leaf
-        let new_page = self.buffer_pool.new_page(self.db_id)?;
-        let new_page_id = new_page.read().id;
-        {
-            let mut new_guard = new_page.write();
-            let mut new_node = BTreeNode::new(&mut new_guard);
-            new_node.init(true);
-            
-            // Move half keys
-            let split_idx = LEAF_ORDER / 2;
-            leaf_node.split_leaf_to(&mut new_node, split_idx);
-            
-            // Update links
-            new_node.set_next_leaf(leaf_node.next_leaf());
-            leaf_node.set_next_leaf(new_page_id);
+        if !self.overflows(&node) {
+            return self.store(leaf_id, &node);
         }
-        
-        // Insert key into correct node
-        // We need to propagate up. This requires parent pointers or recursion.
-        // For simplicity, let's assume root split only for now or just panic on deep split.
-        // Or better: just support root split.
-        
-        if leaf_page_id == self.root_page_id {
-            // Create new root
-            let new_root = self.buffer_pool.new_page(self.db_id)?;
-            let _new_root_id = new_root.read().id;
-            
-            {
-                let mut root_guard = new_root.write();
-                let mut root_node = BTreeNode::new(&mut root_guard);
-                root_node.init(false); // Internal
-                
-                // Key to promote is the first key of the new right node
-                let new_page_guard = new_page.read();
-                let new_node_read = BTreeNode::new_read(&new_page_guard);
-                let promote_key = new_node_read.get_key(0);
-                
-                root_node.insert_internal(promote_key, leaf_page_id, new_page_id);
+
+        // 2. Split and push the separator up until a parent has room
+        let (mut sep, mut right_id) = self.split(leaf_id, node)?;
+        for (parent_id, child_idx) in path.into_iter().rev() {
+            let mut parent = self.load(parent_id)?;
+            if let Node::Internal { entries, .. } = &mut parent {
+                entries.insert(child_idx, (sep, right_id));
+            }
+            if !self.overflows(&parent) {
+                return self.store(parent_id, &parent);
             }
-            
-            // Update this struct's root? No, root page ID is fixed in Catalog usually.
-            // We should copy new root content to page 0? No, page 0 is header.
-            // Root page ID is stored in Catalog. We need to update Catalog.
-            // This architecture is getting tricky.
-            // Standard way: Root page ID is fixed, we copy old root content to new page, and init root as new internal.
-            // Let's do that: Copy Root -> New Page. Init Root as Internal pointing to New Page and Split Page.
-            
-            // TODO: Implement full split propagation.
-            return Err(anyhow!("Index split not fully implemented"));
+            (sep, right_id) = self.split(parent_id, parent)?;
         }
-        
-        Ok(())
+
+        // 3. The root split: move its left half out and make it the parent of both halves
+        let left_id = self.allocate()?;
+        let left = self.load(self.root_page_id)?;
+        self.store(left_id, &left)?;
+        self.store(self.root_page_id, &Node::Internal { first_child: left_id, entries: vec![(sep, right_id)] })
     }
 
     pub fn search(&self, key: u32) -> Result<Option<(u32, u16)>> {
-        let leaf_page_id = self.find_leaf(key)?;
-        let leaf_page = self.buffer_pool.fetch_page(GlobalPageId { db_id: self.db_id, page_id: leaf_page_id })?;
-        let leaf_guard = leaf_page.read();
-        let leaf_node = BTreeNode::new_read(&leaf_guard);
-        
-        leaf_node.search_leaf(key)
+        let (_, leaf_id) = self.find_path(key)?;
+        match self.load(leaf_id)? {
+            Node::Leaf { entries, .. } => Ok(entries.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)),
+            Node::Internal { .. } => Ok(None),
+        }
     }
 
-    fn find_leaf(&self, key: u32) -> Result<u32> {
-        let mut current_page_id = self.root_page_id;
-        loop {
-            let page = self.buffer_pool.fetch_page(GlobalPageId { db_id: self.db_id, page_id: current_page_id })?;
-            let guard = page.read();
-            let node = BTreeNode::new_read(&guard);
-            
-            if node.is_leaf() {
-                return Ok(current_page_id);
+    /// Removes the entry for `key`. Nodes that drop below half full are merged
+    /// into a sibling and the emptied page goes back to the free list.
+    pub fn delete(&self, key: u32) -> Result<bool> {
+        let (mut path, leaf_id) = self.find_path(key)?;
+        let mut node = self.load(leaf_id)?;
+        if let Node::Leaf { entries, .. } = &mut node {
+            match entries.iter().position(|(k, _)| *k == key) {
+                Some(pos) => { entries.remove(pos); }
+                None => return Ok(false),
+            }
+        }
+        self.store(leaf_id, &node)?;
+
+        let mut page_id = leaf_id;
+        while let Some((parent_id, child_idx)) = path.pop() {
+            if node.len() >= self.order(&node) / 2 {
+                return Ok(true);
+            }
+
+            let mut parent = self.load(parent_id)?;
+            let Node::Internal { first_child, entries } = &mut parent else {
+                return Err(anyhow!("B+ tree index is corrupt: page {} is not an internal node", parent_id));
+            };
+            if entries.is_empty() {
+                break;
+            }
+
+            // Merge with the left sibling, or the right one for the leftmost child
+            let left_idx = child_idx.saturating_sub(1);
+            let left_id = if left_idx == 0 { *first_child } else { entries[left_idx - 1].1 };
+            let (sep, right_id) = entries[left_idx];
+            let left = if left_id == page_id { node.clone() } else { self.load(left_id)? };
+            let right = if right_id == page_id { node.clone() } else { self.load(right_id)? };
+
+            let merged = match (left, right) {
+                (Node::Leaf { entries: mut l, .. }, Node::Leaf { entries: r, next_leaf }) => {
+                    l.extend(r);
+                    Node::Leaf { entries: l, next_leaf }
+                }
+                (Node::Internal { first_child, entries: mut l }, Node::Internal { first_child: r_first, entries: r }) => {
+                    // The separator comes down between the two halves
+                    l.push((sep, r_first));
+                    l.extend(r);
+                    Node::Internal { first_child, entries: l }
+                }
+                _ => return Err(anyhow!("B+ tree index is corrupt: siblings of page {} are on different levels", parent_id)),
+            };
+            if self.overflows(&merged) {
+                return Ok(true);
             }
-            
-            current_page_id = node.lookup_internal(key);
+
+            self.store(left_id, &merged)?;
+            self.buffer_pool.free_page(self.db_id, right_id)?;
+            entries.remove(left_idx);
+            self.store(parent_id, &parent)?;
+
+            page_id = parent_id;
+            node = parent;
         }
+
+        // An internal root left with a single child takes over that child's contents
+        if page_id == self.root_page_id
+            && let Node::Internal { first_child, entries } = &node
+            && entries.is_empty() {
+                let child = self.load(*first_child)?;
+                self.store(self.root_page_id, &child)?;
+                self.buffer_pool.free_page(self.db_id, *first_child)?;
+        }
+        Ok(true)
     }
-}
 
-struct BTreeNode<'a> {
-    data: &'a mut [u8],
-}
+    /// Returns every page of the tree, including the root, to the free list.
+    pub fn destroy(&self) -> Result<()> {
+        self.free_subtree(self.root_page_id)
+    }
 
-impl<'a> BTreeNode<'a> {
-    fn new(page: &'a mut Page) -> Self {
-        Self { data: &mut page.data }
+    /// Removes every entry. All pages but the root go back to the free list.
+    pub fn clear(&self) -> Result<()> {
+        if let Node::Internal { first_child, entries } = self.load(self.root_page_id)? {
+            self.free_subtree(first_child)?;
+            for (_, child) in entries {
+                self.free_subtree(child)?;
+            }
+        }
+        self.init()
     }
-    
-    // Helper for read-only access (unsafe cast for reuse)
-    fn new_read(page: &'a Page) -> Self {
-        #[allow(mutable_transmutes)]
-        let data = unsafe { std::mem::transmute::<&[u8], &mut [u8]>(&page.data) };
-        Self { data }
+
+    /// Every page of the tree and every entry in its leaves, for `rdb db check`.
+    /// Fails if a page is reached twice or can't be parsed.
+    pub fn walk(&self) -> Result<(Vec<u32>, Vec<Entry>)> {
+        let mut pages = Vec::new();
+        let mut seen = HashSet::new();
+        let mut entries = Vec::new();
+        let mut stack = vec![self.root_page_id];
+        while let Some(page_id) = stack.pop() {
+            if !seen.insert(page_id) {
+                return Err(anyhow!("B+ tree index is corrupt: page {} is reached twice", page_id));
+            }
+            pages.push(page_id);
+            match self.load(page_id)? {
+                Node::Leaf { entries: leaf_entries, .. } => entries.extend(leaf_entries),
+                Node::Internal { first_child, entries } => {
+                    stack.push(first_child);
+                    stack.extend(entries.iter().map(|(_, child)| *child));
+                }
+            }
+        }
+        Ok((pages, entries))
     }
 
-    fn init(&mut self, is_leaf: bool) {
-        self.set_is_leaf(is_leaf);
-        self.set_num_keys(0);
-        self.set_next_leaf(0);
+    fn free_subtree(&self, page_id: u32) -> Result<()> {
+        let mut stack = vec![page_id];
+        while let Some(page_id) = stack.pop() {
+            if let Node::Internal { first_child, entries } = self.load(page_id)? {
+                stack.push(first_child);
+                stack.extend(entries.iter().map(|(_, child)| *child));
+            }
+            self.buffer_pool.free_page(self.db_id, page_id)?;
+        }
+        Ok(())
     }
 
-    fn is_leaf(&self) -> bool {
-        self.data[0] == 1
+    // Walks from the root to the leaf for `key`, recording (page, child index) per level
+    fn find_path(&self, key: u32) -> Result<(Vec<(u32, usize)>, u32)> {
+        let mut path = Vec::new();
+        let mut current_page_id = self.root_page_id;
+        loop {
+            match self.load(current_page_id)? {
+                Node::Leaf { .. } => return Ok((path, current_page_id)),
+                Node::Internal { first_child, entries } => {
+                    if path.len() > 64 {
+                        return Err(anyhow!("B+ tree index is corrupt: cycle below page {}", self.root_page_id));
+                    }
+                    let idx = entries.partition_point(|(k, _)| *k <= key);
+                    path.push((current_page_id, idx));
+                    current_page_id = if idx == 0 { first_child } else { entries[idx - 1].1 };
+                }
+            }
+        }
     }
 
-    fn set_is_leaf(&mut self, val: bool) {
-        self.data[0] = if val { 1 } else { 0 };
+    // Moves the upper half of an overfull node to a new page.
+    // Returns the separator key and the new page.
+    fn split(&self, page_id: u32, node: Node) -> Result<(u32, u32)> {
+        let right_id = self.allocate()?;
+        match node {
+            Node::Leaf { mut entries, next_leaf } => {
+                let right = entries.split_off(entries.len() / 2);
+                let sep = right[0].0;
+                self.store(right_id, &Node::Leaf { entries: right, next_leaf })?;
+                self.store(page_id, &Node::Leaf { entries, next_leaf: right_id })?;
+                Ok((sep, right_id))
+            }
+            Node::Internal { first_child, mut entries } => {
+                let mut right = entries.split_off(entries.len() / 2);
+                let (sep, right_first) = right.remove(0);
+                self.store(right_id, &Node::Internal { first_child: right_first, entries: right })?;
+                self.store(page_id, &Node::Internal { first_child, entries })?;
+                Ok((sep, right_id))
+            }
+        }
     }
 
-    fn num_keys(&self) -> u16 {
-        LittleEndian::read_u16(&self.data[1..3])
+    fn order(&self, node: &Node) -> usize {
+        if node.is_leaf() { self.leaf_order } else { self.internal_order }
     }
 
-    fn set_num_keys(&mut self, val: u16) {
-        LittleEndian::write_u16(&mut self.data[1..3], val);
+    fn overflows(&self, node: &Node) -> bool {
+        node.len() > self.order(node)
     }
-    
-    fn next_leaf(&self) -> u32 {
-        LittleEndian::read_u32(&self.data[7..11])
+
+    fn allocate(&self) -> Result<u32> {
+        let page = self.buffer_pool.new_page(self.db_id)?;
+        let page_id = page.read().id;
+        Ok(page_id)
     }
 
-    fn set_next_leaf(&mut self, val: u32) {
-        LittleEndian::write_u32(&mut self.data[7..11], val);
+    fn load(&self, page_id: u32) -> Result<Node> {
+        let page = self.buffer_pool.fetch_page(GlobalPageId { db_id: self.db_id, page_id })?;
+        let guard = page.read();
+        Node::read(page_id, &guard.data[..guard.usable_size()])
     }
 
-    fn get_key(&self, idx: u16) -> u32 {
-        let offset = HEADER_SIZE + (idx as usize * (KEY_SIZE + if self.is_leaf() { VALUE_SIZE } else { PTR_SIZE }));
-        LittleEndian::read_u32(&self.data[offset..offset+4])
+    fn store(&self, page_id: u32, node: &Node) -> Result<()> {
+        let page = self.buffer_pool.fetch_page(GlobalPageId { db_id: self.db_id, page_id })?;
+        let mut guard = page.write();
+        node.write(&mut guard.data);
+        guard.dirty = true;
+        Ok(())
     }
+}
 
-    fn lookup_internal(&self, key: u32) -> u32 {
-        let num = self.num_keys();
-        
-        // Internal Node Layout: [P0] [K1 P1] [K2 P2] ... [Kn Pn]
-        // P0 is at HEADER_SIZE
-        // K1 is at HEADER_SIZE + 4
-        // P1 is at HEADER_SIZE + 8
-        // K2 is at HEADER_SIZE + 12
-        // P2 is at HEADER_SIZE + 16
-        // etc.
-        
-        if num == 0 {
-            // No keys, return P0 (first pointer)
-            let offset = HEADER_SIZE;
-            return LittleEndian::read_u32(&self.data[offset..offset+4]);
-        }
-        
-        for i in 0..num {
-            let k = self.get_key(i);
-            if key < k {
-                // Key is less than K(i), so go to P(i-1)
-                if i == 0 {
-                    // Go to P0 (leftmost pointer)
-                    let offset = HEADER_SIZE;
-                    return LittleEndian::read_u32(&self.data[offset..offset+4]);
-                } else {
-                    // Go to P(i-1) 
-                    // P(i-1) is at: HEADER_SIZE + 4 + ((i-1) * (KEY_SIZE + POINTER_SIZE)) + KEY_SIZE
-                    let i_usize = i as usize;
-                    let offset = HEADER_SIZE + 4 + ((i_usize - 1) * ((KEY_SIZE as usize) + 4)) + (KEY_SIZE as usize);
-                    return LittleEndian::read_u32(&self.data[offset..offset+4]);
-                }
-            }
+#[derive(Clone)]
+enum Node {
+    Leaf { entries: Vec<(u32, (u32, u16))>, next_leaf: u32 },
+    Internal { first_child: u32, entries: Vec<(u32, u32)> },
+}
+
+impl Node {
+    fn read(page_id: u32, data: &[u8]) -> Result<Self> {
+        let is_leaf = data[0] == 1;
+        let num_keys = LittleEndian::read_u16(&data[1..3]) as usize;
+        let order = if is_leaf { leaf_order(data.len()) } else { internal_order(data.len()) };
+        if num_keys > order {
+            return Err(anyhow!("B+ tree index is corrupt: page {} claims {} keys", page_id, num_keys));
         }
-        
-        // Key >= all keys, go to Pn (rightmost pointer)
-        //  Pn is at: HEADER_SIZE + 4 + ((num - 1) * (KEY_SIZE + POINTER_SIZE)) + KEY_SIZE
-        let num_usize = num as usize;
-        let offset = HEADER_SIZE + 4 + ((num_usize - 1) * ((KEY_SIZE as usize) + 4)) + (KEY_SIZE as usize);
-        LittleEndian::read_u32(&self.data[offset..offset+4])
-    }
 
-    fn search_leaf(&self, key: u32) -> Result<Option<(u32, u16)>> {
-        let num = self.num_keys();
-        for i in 0..num {
-            let k = self.get_key(i);
-            if k == key {
-                let offset = HEADER_SIZE + (i as usize * (KEY_SIZE + VALUE_SIZE)) + KEY_SIZE;
-                let page_id = LittleEndian::read_u32(&self.data[offset..offset+4]);
-                let slot_id = LittleEndian::read_u16(&self.data[offset+4..offset+6]);
-                return Ok(Some((page_id, slot_id)));
-            }
+        if is_leaf {
+            let entries = (0..num_keys).map(|i| {
+                let offset = HEADER_SIZE + i * (KEY_SIZE + VALUE_SIZE);
+                let key = LittleEndian::read_u32(&data[offset..offset+4]);
+                let page_id = LittleEndian::read_u32(&data[offset+4..offset+8]);
+                let slot_id = LittleEndian::read_u16(&data[offset+8..offset+10]);
+                (key, (page_id, slot_id))
+            }).collect();
+            Ok(Node::Leaf { entries, next_leaf: LittleEndian::read_u32(&data[7..11]) })
+        } else {
+            let first_child = LittleEndian::read_u32(&data[HEADER_SIZE..HEADER_SIZE+4]);
+            let entries = (0..num_keys).map(|i| {
+                let offset = HEADER_SIZE + PTR_SIZE + i * (KEY_SIZE + PTR_SIZE);
+                let key = LittleEndian::read_u32(&data[offset..offset+4]);
+                let child = LittleEndian::read_u32(&data[offset+4..offset+8]);
+                (key, child)
+            }).collect();
+            Ok(Node::Internal { first_child, entries })
         }
-        Ok(None)
     }
 
-    fn insert_leaf(&mut self, key: u32, value: (u32, u16)) {
-        let num = self.num_keys();
-        // Find position
-        let mut pos = num;
-        for i in 0..num {
-            if self.get_key(i) > key {
-                pos = i;
-                break;
+    fn write(&self, data: &mut [u8]) {
+        data[..HEADER_SIZE].fill(0);
+        data[0] = if self.is_leaf() { 1 } else { 0 };
+        LittleEndian::write_u16(&mut data[1..3], self.len() as u16);
+
+        match self {
+            Node::Leaf { entries, next_leaf } => {
+                LittleEndian::write_u32(&mut data[7..11], *next_leaf);
+                for (i, (key, (page_id, slot_id))) in entries.iter().enumerate() {
+                    let offset = HEADER_SIZE + i * (KEY_SIZE + VALUE_SIZE);
+                    LittleEndian::write_u32(&mut data[offset..offset+4], *key);
+                    LittleEndian::write_u32(&mut data[offset+4..offset+8], *page_id);
+                    LittleEndian::write_u16(&mut data[offset+8..offset+10], *slot_id);
+                }
+            }
+            Node::Internal { first_child, entries } => {
+                LittleEndian::write_u32(&mut data[HEADER_SIZE..HEADER_SIZE+4], *first_child);
+                for (i, (key, child)) in entries.iter().enumerate() {
+                    let offset = HEADER_SIZE + PTR_SIZE + i * (KEY_SIZE + PTR_SIZE);
+                    LittleEndian::write_u32(&mut data[offset..offset+4], *key);
+                    LittleEndian::write_u32(&mut data[offset+4..offset+8], *child);
+                }
             }
         }
-        
-        // Shift
-        let entry_size = KEY_SIZE + VALUE_SIZE;
-        let offset = HEADER_SIZE + (pos as usize * entry_size);
-        let end = HEADER_SIZE + (num as usize * entry_size);
-        
-        self.data.copy_within(offset..end, offset + entry_size);
-        
-        // Insert
-        LittleEndian::write_u32(&mut self.data[offset..offset+4], key);
-        LittleEndian::write_u32(&mut self.data[offset+4..offset+8], value.0);
-        LittleEndian::write_u16(&mut self.data[offset+8..offset+10], value.1);
-        
-        self.set_num_keys(num + 1);
     }
-    
-    fn split_leaf_to(&mut self, other: &mut Self, split_idx: usize) {
-        // Move keys from split_idx to end -> other
-        let num = self.num_keys() as usize;
-        let count = num - split_idx;
-        let entry_size = KEY_SIZE + VALUE_SIZE;
-        
-        let src_start = HEADER_SIZE + (split_idx * entry_size);
-        let src_end = HEADER_SIZE + (num * entry_size);
-        let dest_start = HEADER_SIZE;
-        
-        other.data[dest_start..dest_start + (count * entry_size)]
-            .copy_from_slice(&self.data[src_start..src_end]);
-            
-        self.set_num_keys(split_idx as u16);
-        other.set_num_keys(count as u16);
+
+    fn is_leaf(&self) -> bool {
+        matches!(self, Node::Leaf { .. })
     }
-    
-    fn insert_internal(&mut self, key: u32, left_pid: u32, right_pid: u32) {
-        // Simplified for root split
-        // [P0] [K1 P1]
-        // P0 = left_pid
-        // K1 = key
-        // P1 = right_pid
-        
-        let p0_offset = HEADER_SIZE;
-        LittleEndian::write_u32(&mut self.data[p0_offset..p0_offset+4], left_pid);
-        
-        let k1_offset = p0_offset + 4;
-        LittleEndian::write_u32(&mut self.data[k1_offset..k1_offset+4], key);
-        LittleEndian::write_u32(&mut self.data[k1_offset+4..k1_offset+8], right_pid);
-        
-        self.set_num_keys(1);
+
+    fn len(&self) -> usize {
+        match self {
+            Node::Leaf { entries, .. } => entries.len(),
+            Node::Internal { entries, .. } => entries.len(),
+        }
     }
+
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::storage::buffer::BufferPool;
+    use crate::storage::header::DatabaseHeader;
     use crate::storage::pager::Pager;
     use std::sync::Arc;
     use tempfile::NamedTempFile;
@@ -312,18 +374,16 @@ mod tests {
         // Allocate header and root
         pager.allocate_page().unwrap(); // 0
         let root_id = pager.allocate_page().unwrap(); // 1
-        
+
         let buffer_pool = Arc::new(BufferPool::new(10));
         buffer_pool.register_pager(0, pager);
-        
-        let index = BTreeIndex::new(buffer_pool.clone(), 0, root_id);
+
+        let index = BTreeIndex::new(buffer_pool.clone(), 0, root_id).unwrap();
         index.init().unwrap();
-        
-        let page = buffer_pool.fetch_page(GlobalPageId { db_id: 0, page_id: root_id }).unwrap();
-        let guard = page.read();
-        let node = BTreeNode::new_read(&guard);
+
+        let node = index.load(root_id).unwrap();
         assert!(node.is_leaf());
-        assert_eq!(node.num_keys(), 0);
+        assert_eq!(node.len(), 0);
     }
 
     #[test]
@@ -332,22 +392,59 @@ mod tests {
         let pager = Arc::new(Pager::open(file.path()).unwrap());
         pager.allocate_page().unwrap(); // 0
         let root_id = pager.allocate_page().unwrap(); // 1
-        
+
         let buffer_pool = Arc::new(BufferPool::new(10));
         buffer_pool.register_pager(0, pager);
-        
-        let index = BTreeIndex::new(buffer_pool.clone(), 0, root_id);
+
+        let index = BTreeIndex::new(buffer_pool.clone(), 0, root_id).unwrap();
         index.init().unwrap();
-        
+
         // Insert
         index.insert(10, (100, 1)).unwrap();
         index.insert(5, (101, 2)).unwrap();
         index.insert(20, (102, 3)).unwrap();
-        
+
         // Search
         assert_eq!(index.search(10).unwrap(), Some((100, 1)));
         assert_eq!(index.search(5).unwrap(), Some((101, 2)));
         assert_eq!(index.search(20).unwrap(), Some((102, 3)));
         assert_eq!(index.search(15).unwrap(), None);
     }
+
+    #[test]
+    fn test_btree_split_and_merge_reuses_pages() {
+        let dir = tempfile::TempDir::new().unwrap();
+        // Small pages give a deeper tree
+        let pager = Arc::new(Pager::create(&dir.path().join("test.db"), 1024, None).unwrap());
+        pager.allocate_page().unwrap(); // 0
+        pager.write_header(&DatabaseHeader::new("test".to_string(), 1024)).unwrap();
+        let root_id = pager.allocate_page().unwrap(); // 1
+
+        let buffer_pool = Arc::new(BufferPool::new(64));
+        buffer_pool.register_pager(0, pager.clone());
+
+        let index = BTreeIndex::new(buffer_pool.clone(), 0, root_id).unwrap();
+        index.init().unwrap();
+
+        // Enough keys for three levels
+        let count = (index.leaf_order * index.internal_order) as u32;
+        for key in (0..count).rev() {
+            index.insert(key, (key, (key % 7) as u16)).unwrap();
+        }
+        for key in (0..count).step_by(97) {
+            assert_eq!(index.search(key).unwrap(), Some((key, (key % 7) as u16)));
+        }
+        let pages_used = pager.total_pages.load(std::sync::atomic::Ordering::SeqCst);
+
+        for key in 0..count {
+            assert!(index.delete(key).unwrap());
+        }
+        assert!(!index.delete(0).unwrap());
+        assert_eq!(index.search(count / 2).unwrap(), None);
+        assert!(index.load(root_id).unwrap().is_leaf());
+
+        // Everything but the root went back to the free list
+        let free = pager.read_header().unwrap().free_page_count;
+        assert_eq!(free, pages_used - 2);
+    }
 }
@@ -1,8 +1,23 @@
+pub mod archive;
+pub mod backup;
 pub mod buffer;
 pub mod pager;
 pub mod page;
 pub mod header;
 pub mod catalog;
+pub mod changes;
+pub mod check;
 pub mod slotted;
+pub mod store;
 pub mod index;
 pub mod cache;
+pub mod wal;
+pub mod crypto;
+#[cfg(test)]
+pub mod fault;
+pub mod overflow;
+pub mod fsm;
+pub mod codec;
+pub mod upgrade;
+pub mod vacuum;
+pub mod writer;
@@ -0,0 +1,80 @@
+use crate::storage::buffer::BufferPool;
+use crate::storage::page::{Page, PAGE_CHECKSUM_SIZE};
+use byteorder::{LittleEndian, ByteOrder};
+use anyhow::{Result, anyhow};
+
+// Tuples too large for a slotted page are moved out to a chain of overflow
+// pages. Every overflow page starts with the ID of the next one (0 = last),
+// followed by as much of the tuple as fits.
+//
+// Overflow pages bypass the buffer pool and go straight through the pager (and
+// so the WAL). A large value then neither flushes hot pages out of the cache nor
+// needs free frames while the page pointing at it is locked.
+const PAGE_HEADER_SIZE: usize = 4;
+
+/// Writes `data` to a new overflow chain and returns its first page.
+pub fn write_chain(buffer_pool: &BufferPool, db_id: u32, data: &[u8]) -> Result<u32> {
+    let pager = buffer_pool.pager(db_id)?;
+    let chunk_size = pager.page_size() - PAGE_CHECKSUM_SIZE - PAGE_HEADER_SIZE;
+
+    // Written back to front, so each page already knows its successor
+    let mut next_page_id = 0;
+    for chunk in data.chunks(chunk_size).rev() {
+        let mut page = Page::new(pager.allocate_page()?, pager.page_size());
+        LittleEndian::write_u32(&mut page.data[0..4], next_page_id);
+        page.data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+        pager.write_page(&page)?;
+        next_page_id = page.id;
+    }
+    Ok(next_page_id)
+}
+
+/// Reads `len` bytes back from the chain starting at `first_page_id`.
+pub fn read_chain(buffer_pool: &BufferPool, db_id: u32, first_page_id: u32, len: usize) -> Result<Vec<u8>> {
+    let pager = buffer_pool.pager(db_id)?;
+    let chunk_size = pager.page_size() - PAGE_CHECKSUM_SIZE - PAGE_HEADER_SIZE;
+    let mut data = Vec::with_capacity(len);
+    let mut page_id = first_page_id;
+    while data.len() < len {
+        if page_id == 0 {
+            return Err(anyhow!("Overflow chain at page {} is truncated: {} of {} bytes found", first_page_id, data.len(), len));
+        }
+        let page = pager.read_page(page_id)?;
+        let take = (len - data.len()).min(chunk_size);
+        data.extend_from_slice(&page.data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + take]);
+        page_id = LittleEndian::read_u32(&page.data[0..4]);
+    }
+    Ok(data)
+}
+
+/// Returns every page of a `len` byte chain to the free list.
+pub fn free_chain(buffer_pool: &BufferPool, db_id: u32, first_page_id: u32, len: usize) -> Result<()> {
+    let pager = buffer_pool.pager(db_id)?;
+    let chunk_size = pager.page_size() - PAGE_CHECKSUM_SIZE - PAGE_HEADER_SIZE;
+    let mut page_id = first_page_id;
+    for _ in 0..len.div_ceil(chunk_size) {
+        if page_id == 0 {
+            break;
+        }
+        let next_page_id = LittleEndian::read_u32(&pager.read_page(page_id)?.data[0..4]);
+        buffer_pool.free_page(db_id, page_id)?;
+        page_id = next_page_id;
+    }
+    Ok(())
+}
+
+/// Pages of a `len` byte chain, in order.
+pub fn chain_pages(buffer_pool: &BufferPool, db_id: u32, first_page_id: u32, len: usize) -> Result<Vec<u32>> {
+    let pager = buffer_pool.pager(db_id)?;
+    let chunk_size = pager.page_size() - PAGE_CHECKSUM_SIZE - PAGE_HEADER_SIZE;
+    let mut pages = Vec::new();
+    let mut page_id = first_page_id;
+    for _ in 0..len.div_ceil(chunk_size) {
+        if page_id == 0 {
+            return Err(anyhow!("Overflow chain at page {} is truncated after {} page(s)", first_page_id, pages.len()));
+        }
+        pages.push(page_id);
+        page_id = LittleEndian::read_u32(&pager.read_page(page_id)?.data[0..4]);
+    }
+    Ok(pages)
+}
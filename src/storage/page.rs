@@ -1,30 +1,73 @@
-pub const PAGE_SIZE: usize = 8192;
+use byteorder::{LittleEndian, ByteOrder};
+use anyhow::{Result, anyhow};
+
+// Page size of files created without an explicit choice. Each database stores
+// its own page size in the header.
+pub const DEFAULT_PAGE_SIZE: usize = 8192;
+pub const MIN_PAGE_SIZE: usize = 1024;
+// Slotted pages address tuples with u16 offsets
+pub const MAX_PAGE_SIZE: usize = 32768;
+// The last bytes of every page hold a CRC32 of everything before them
+pub const PAGE_CHECKSUM_SIZE: usize = 4;
+
+/// Checks that a page size is a power of two between `MIN_PAGE_SIZE` and `MAX_PAGE_SIZE`.
+pub fn validate_page_size(page_size: usize) -> Result<()> {
+    if !page_size.is_power_of_two() || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+        return Err(anyhow!(
+            "Invalid page size {}: must be a power of two between {} and {} bytes",
+            page_size, MIN_PAGE_SIZE, MAX_PAGE_SIZE
+        ));
+    }
+    Ok(())
+}
 
 #[derive(Clone)]
 pub struct Page {
     pub id: u32,
-    pub data: [u8; PAGE_SIZE],
+    pub data: Box<[u8]>,
     pub dirty: bool,
-    #[allow(dead_code)]
-    pub pin_count: u32,
 }
 
 impl Page {
-    pub fn new(id: u32) -> Self {
+    pub fn new(id: u32, page_size: usize) -> Self {
         Self {
             id,
-            data: [0; PAGE_SIZE],
+            data: vec![0; page_size].into_boxed_slice(),
             dirty: false,
-            pin_count: 0,
         }
     }
 
-    pub fn from_bytes(id: u32, bytes: [u8; PAGE_SIZE]) -> Self {
+    pub fn from_bytes(id: u32, bytes: Vec<u8>) -> Self {
         Self {
             id,
-            data: bytes,
+            data: bytes.into_boxed_slice(),
             dirty: false,
-            pin_count: 0,
         }
     }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Bytes that page layouts (slotted, B+ tree, catalog) may use.
+    pub fn usable_size(&self) -> usize {
+        self.data.len() - PAGE_CHECKSUM_SIZE
+    }
+}
+
+/// Computes the checksum of a page image (excluding the trailer itself).
+pub fn compute_checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(&data[..data.len() - PAGE_CHECKSUM_SIZE])
+}
+
+/// Returns the checksum stored in the page trailer.
+pub fn stored_checksum(data: &[u8]) -> u32 {
+    LittleEndian::read_u32(&data[data.len() - PAGE_CHECKSUM_SIZE..])
+}
+
+/// Writes the checksum of the page image into its trailer.
+pub fn stamp_checksum(data: &mut [u8]) {
+    let checksum = compute_checksum(data);
+    let len = data.len();
+    LittleEndian::write_u32(&mut data[len - PAGE_CHECKSUM_SIZE..], checksum);
 }
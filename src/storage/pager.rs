@@ -1,81 +1,1360 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::sync::Mutex;
-use crate::storage::page::{Page, PAGE_SIZE};
-use crate::storage::header::DatabaseHeader;
+use std::collections::{BTreeSet, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::storage::page::{self, Page, DEFAULT_PAGE_SIZE};
+use crate::storage::crypto::{self, EncryptionKey, PageCipher, ENCRYPTION_OVERHEAD};
+use crate::storage::header::{self, DatabaseHeader, CURRENT_FILE_FORMAT_VERSION};
+use crate::core::error::RdbError;
+use crate::storage::wal::{Wal, RecoveryReport, WAL_AUTO_CHECKPOINT_BYTES};
+use crate::storage::archive;
+use crate::storage::changes::ChangeMap;
+use crate::storage::store::{self, FileStore, MemoryStore, PageStore};
 use anyhow::{Result, anyhow};
+use byteorder::{LittleEndian, ByteOrder};
+use memmap2::Mmap;
+use parking_lot::{Condvar, RwLock};
+use uuid::Uuid;
 
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+// Free pages start with this marker followed by the ID of the next free page
+pub const FREE_PAGE_MAGIC: &[u8; 4] = b"FREE";
+
+// Only one write transaction may be active per database at a time.
+struct TxnSlot {
+    active: Option<u64>,
+    next_txn_id: u64,
+    // Size of the file when the active transaction began
+    start_pages: u32,
+}
+
+// A number identifying the calling thread, never 0
+fn thread_tag() -> u64 {
+    static NEXT_TAG: AtomicU64 = AtomicU64::new(1);
+    thread_local!(static TAG: u64 = NEXT_TAG.fetch_add(1, Ordering::Relaxed));
+    TAG.with(|tag| *tag)
+}
+
+// Cached copy of page 0. Changes made inside a transaction (free-list updates)
+// are written once at commit instead of on every allocation.
+struct HeaderState {
+    header: Option<DatabaseHeader>,
+    dirty: bool,
+}
+
+/// What a snapshot copied.
+pub struct Snapshot {
+    /// Page count of the file
+    pub pages: u32,
+    /// Backup an incremental snapshot applies on top of
+    pub base: Option<Uuid>,
+    /// Pages an incremental snapshot copied, in order
+    pub changed: Vec<u32>,
+    /// First log record the copy doesn't hold, when the database has a log
+    pub wal_lsn: Option<u64>,
+}
+
+// Where the log of a database goes once its header asks for one
+enum LogStore {
+    // Next to the database file, created when first opened
+    File(PathBuf),
+    Store(Arc<dyn PageStore>),
+}
 
 pub struct Pager {
-    file: Mutex<File>,
+    name: String,
+    // Size of the page images callers see
+    page_size: usize,
+    // Size of a page in the file; larger when encryption adds its nonce and tag
+    disk_page_size: usize,
+    cipher: Option<PageCipher>,
+    store: Arc<dyn PageStore>,
+    // Read-only map of the store when mmap reads are enabled
+    mmap: RwLock<Option<Mmap>>,
     pub total_pages: AtomicU32,
+    log: LogStore,
+    wal: Option<Wal>,
+    txn: parking_lot::Mutex<TxnSlot>,
+    txn_done: Condvar,
+    // Thread running the active transaction (see `thread_tag`), 0 when none.
+    // Kept outside the slot so reads never wait for its lock.
+    writer: AtomicU64,
+    header: parking_lot::Mutex<HeaderState>,
+    recovery: RecoveryReport,
+    // Opened to be upgraded: the header is never written
+    old_format: bool,
+    // The header still had `in_use` set when the file was opened
+    unclean_shutdown: bool,
+    // Snapshots started and not finished yet. The file must not change under
+    // them, so checkpoints are held off until they end.
+    snapshots: AtomicUsize,
+    snapshot_done: Condvar,
+    // Pages changed since the last backup; None for files being upgraded
+    changes: Option<parking_lot::Mutex<ChangeMap>>,
+    // Where the log is copied before each checkpoint empties it
+    archive: Option<PathBuf>,
 }
 
 impl Pager {
+    /// Opens a database file. The page size comes from its header; files without
+    /// one yet use `DEFAULT_PAGE_SIZE`. Encrypted databases are refused; they
+    /// need `open_with_key`. The path `:memory:` opens a new database in memory.
+    #[cfg(test)]
     pub fn open(path: &Path) -> Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)?;
-        
-        let file_len = file.metadata()?.len();
-        let total_pages = (file_len / PAGE_SIZE as u64) as u32;
+        Self::open_with_key(path, None)
+    }
 
-        Ok(Self {
-            file: Mutex::new(file),
+    /// Opens a database file, using `key` if it turns out to be encrypted.
+    /// Files in another format version are refused.
+    pub fn open_with_key(path: &Path, key: Option<EncryptionKey>) -> Result<Self> {
+        Self::open_inner(Location::at(path)?, None, key, false, None)
+    }
+
+    /// Opens a database kept in `store`, with its log in `log`. `name` stands
+    /// in for the file name.
+    #[cfg(test)]
+    pub fn open_store(name: &str, store: Arc<dyn PageStore>, log: Arc<dyn PageStore>, key: Option<EncryptionKey>) -> Result<Self> {
+        let location = Location { name: name.to_string(), store, log: LogStore::Store(log), path: None };
+        Self::open_inner(location, None, key, false, None)
+    }
+
+    /// Opens a database file like `open_with_key`, archiving the log into
+    /// `<archive>/<name>/` before each checkpoint empties it, recovery at
+    /// open included.
+    pub fn open_with_archive(path: &Path, key: Option<EncryptionKey>, archive: Option<&Path>) -> Result<Self> {
+        Self::open_inner(Location::at(path)?, None, key, false, archive)
+    }
+
+    /// Opens a database written in an older format version so `rdb db upgrade`
+    /// can read it. Nothing but WAL recovery writes to the file.
+    pub fn open_for_upgrade(path: &Path, key: Option<EncryptionKey>) -> Result<Self> {
+        Self::open_inner(Location::at(path)?, None, key, true, None)
+    }
+
+    /// Creates a new, empty database file with the given page size, encrypted
+    /// when a key is given. The caller writes the header (page 0) next.
+    pub fn create(path: &Path, page_size: usize, key: Option<EncryptionKey>) -> Result<Self> {
+        page::validate_page_size(page_size)?;
+        if path.metadata().is_ok_and(|m| m.len() > 0) {
+            return Err(anyhow!("Database file {:?} already exists", path));
+        }
+        Self::open_inner(Location::at(path)?, Some(page_size), key, false, None)
+    }
+
+    /// Creates a database file with its header (page 0) and an empty catalog (page 1).
+    pub fn create_database(path: &Path, name: &str, page_size: usize, key: Option<EncryptionKey>) -> Result<Self> {
+        let mut pager = Self::create(path, page_size, key)?;
+        // A map left by an earlier file of the same name doesn't apply
+        if !store::is_memory(path) {
+            pager.changes = Some(parking_lot::Mutex::new(ChangeMap::open(path, false)?));
+        }
+        pager.initialize(name, key)?;
+        Ok(pager)
+    }
+
+    /// Creates a database in `store`, with its log in `log`, like
+    /// `create_database`.
+    pub fn create_database_in(name: &str, store: Arc<dyn PageStore>, log: Arc<dyn PageStore>, page_size: usize, key: Option<EncryptionKey>) -> Result<Self> {
+        page::validate_page_size(page_size)?;
+        if store.size()? > 0 {
+            return Err(anyhow!("Store of database '{}' is not empty", name));
+        }
+        let location = Location { name: name.to_string(), store, log: LogStore::Store(log), path: None };
+        let mut pager = Self::open_inner(location, Some(page_size), key, false, None)?;
+        pager.initialize(name, key)?;
+        Ok(pager)
+    }
+
+    // Writes the header and the catalog of a new database
+    fn initialize(&mut self, name: &str, key: Option<EncryptionKey>) -> Result<()> {
+        // Allocate Page 0 for Header
+        let header_page_id = self.allocate_page()?;
+        assert_eq!(header_page_id, 0);
+
+        let mut header = DatabaseHeader::new(name.to_string(), self.disk_page_size);
+        header.in_use = true;
+        if let Some(key) = &key {
+            header.encryption = true;
+            header.key_check = crypto::key_check(key);
+        }
+        self.write_header(&header)?;
+
+        // Create Catalog Page (Page 1). A zeroed page is an empty catalog.
+        let page_id = self.allocate_page()?;
+        assert_eq!(page_id, 1);
+        self.sync()?;
+
+        // A file starts logging when it is next opened. Databases in other
+        // stores may never be reopened, so they start right away.
+        if let LogStore::Store(log) = &self.log {
+            self.wal = Some(Wal::open_store(log.clone(), self.disk_page_size)?.0);
+        }
+        Ok(())
+    }
+
+    fn open_inner(location: Location, page_size: Option<usize>, key: Option<EncryptionKey>, old_format: bool, archive: Option<&Path>) -> Result<Self> {
+        let Location { name, store, log, path } = location;
+        let file_len = store.size()?;
+        let header = if file_len > 0 && page_size.is_none() { Self::probe_header(store.as_ref(), &name)? } else { None };
+        if let Some(header) = &header {
+            let found = header.file_format_version;
+            if found > CURRENT_FILE_FORMAT_VERSION {
+                return Err(RdbError::FormatTooNew { database: name, found, supported: CURRENT_FILE_FORMAT_VERSION }.into());
+            }
+            if found < CURRENT_FILE_FORMAT_VERSION && !old_format {
+                return Err(RdbError::UpgradeRequired { database: name, found, current: CURRENT_FILE_FORMAT_VERSION }.into());
+            }
+        }
+
+        let (page_size, key) = match (&header, page_size) {
+            (_, Some(page_size)) => (page_size, key),
+            (Some(header), None) if header.encryption => {
+                let key = match key {
+                    Some(key) => key,
+                    None => crypto::load_key(None)?.ok_or(anyhow!(
+                        "Database '{}' is encrypted: set {} or storage.encryption_key_file", name, crypto::KEY_ENV_VAR
+                    ))?,
+                };
+                if crypto::key_check(&key) != header.key_check {
+                    return Err(anyhow!("Wrong encryption key for database '{}'", name));
+                }
+                (header.page_size as usize, Some(key))
+            }
+            (Some(header), None) => (header.page_size as usize, None),
+            (None, None) => (DEFAULT_PAGE_SIZE, None),
+        };
+        let total_pages = (file_len / page_size as u64) as u32;
+        let cipher = key.as_ref().map(PageCipher::new);
+        let image_size = if cipher.is_some() { page_size - ENCRYPTION_OVERHEAD } else { page_size };
+
+        let mut pager = Self {
+            name,
+            page_size: image_size,
+            disk_page_size: page_size,
+            cipher,
+            store,
+            mmap: RwLock::new(None),
             total_pages: AtomicU32::new(total_pages),
-        })
+            log,
+            wal: None,
+            txn: parking_lot::Mutex::new(TxnSlot { active: None, next_txn_id: 1, start_pages: 0 }),
+            txn_done: Condvar::new(),
+            writer: AtomicU64::new(0),
+            header: parking_lot::Mutex::new(HeaderState { header: None, dirty: false }),
+            recovery: RecoveryReport::default(),
+            old_format,
+            unclean_shutdown: false,
+            snapshots: AtomicUsize::new(0),
+            snapshot_done: Condvar::new(),
+            changes: None,
+            archive: None,
+        };
+        pager.archive = archive.map(|archive| archive::segment_dir(archive, &pager.name));
+
+        // A file written in place that wasn't closed cleanly may have changes
+        // the map missed. With the log, recovery marks them again.
+        if !old_format
+            && let Some(header) = &header
+            && let Some(path) = &path {
+                let trusted = header.wal_enabled || !header.in_use;
+                pager.changes = Some(parking_lot::Mutex::new(ChangeMap::open(path, trusted)?));
+        }
+
+        // Files without a header (still being created) are written in place.
+        // Otherwise the header decides whether page writes go through the log.
+        if header.is_some_and(|header| header.wal_enabled) {
+            let (wal, report) = match &pager.log {
+                LogStore::File(path) => Wal::open(path, page_size)?,
+                LogStore::Store(log) => Wal::open_store(log.clone(), page_size)?,
+            };
+            pager.wal = Some(wal);
+            pager.recovery = report;
+        }
+
+        if total_pages > 0 {
+            pager.header.lock().header = pager.read_header().ok();
+        }
+
+        // Remember who opened the file last and that it is open. Old files are
+        // left as they are.
+        let header = pager.header.lock().header.clone();
+        if !old_format
+            && let Some(mut header) = header {
+                pager.unclean_shutdown = header.in_use;
+                header.last_opened_at = chrono::Utc::now().timestamp();
+                header.last_opened_with_engine = env!("CARGO_PKG_VERSION").to_string();
+                header.in_use = true;
+                pager.write_header(&header)?;
+        }
+
+        // Redo committed frames into the database file and drop the rest
+        if pager.wal.is_some() {
+            pager.checkpoint()?;
+            // Pages the file gained before a crash may only have survived in
+            // the log
+            let redone = (pager.store.size()? / pager.disk_page_size as u64) as u32;
+            pager.total_pages.fetch_max(redone, Ordering::SeqCst);
+        }
+
+        Ok(pager)
+    }
+
+    // Reads the header of an existing file. A page 0 without the magic bytes
+    // belongs to a file that was never finished and counts as no header.
+    fn probe_header(store: &dyn PageStore, name: &str) -> Result<Option<DatabaseHeader>> {
+        let mut magic = [0u8; 7];
+        if store.read_at(&mut magic, 0).is_err() || magic == [0u8; 7] {
+            return Ok(None);
+        }
+        if &magic != header::MAGIC {
+            return Err(anyhow!("Database file '{}' is not an RDB database", name));
+        }
+        let mut bytes = vec![0u8; store.size()?.min(page::MAX_PAGE_SIZE as u64) as usize];
+        store.read_at(&mut bytes, 0)?;
+        DatabaseHeader::from_bytes(&bytes)
+            .map(Some)
+            .map_err(|e| anyhow!("Header of database '{}' is unreadable: {}", name, e))
+    }
+
+    /// Name of the database, from its file name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Size of the page images handed to and returned by the pager. Smaller than
+    /// the on-disk page size for encrypted databases.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// What the log scan found when this database was opened.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery
     }
 
+    /// Whether the previous session ended without `close`, i.e. crashed or was killed.
+    pub fn unclean_shutdown(&self) -> bool {
+        self.unclean_shutdown
+    }
+
+    /// Reads a page as the calling thread may see it: with the active
+    /// transaction's uncommitted changes only on the thread running it.
     pub fn read_page(&self, page_id: u32) -> Result<Page> {
-        let mut file = self.file.lock().map_err(|_| anyhow!("Lock poisoned"))?;
+        self.read_page_as(page_id, self.in_transaction())
+    }
+
+    // Reads a page as last committed, or with `own` as the active transaction sees it
+    fn read_page_as(&self, page_id: u32, own: bool) -> Result<Page> {
         let total_pages = self.total_pages.load(Ordering::SeqCst);
-        
         if page_id >= total_pages {
              return Err(anyhow!("Page ID {} out of bounds (total: {})", page_id, total_pages));
         }
 
-        file.seek(SeekFrom::Start((page_id as u64) * (PAGE_SIZE as u64)))?;
-        
-        let mut buffer = [0u8; PAGE_SIZE];
-        file.read_exact(&mut buffer)?;
+        if let Some(wal) = &self.wal {
+            let mut buffer = vec![0u8; self.disk_page_size];
+            if wal.read_page(page_id, &mut buffer, own)? {
+                let image = self.decode(page_id, buffer)?;
+                self.verify_checksum(page_id, &image)?;
+                return Ok(Page::from_bytes(page_id, image));
+            }
+        }
+
+        self.read_page_from_file(page_id)
+    }
+
+    /// True when the calling thread runs the active transaction.
+    pub fn in_transaction(&self) -> bool {
+        let writer = self.writer.load(Ordering::SeqCst);
+        writer != 0 && writer == thread_tag()
+    }
+
+    /// True when another thread runs a transaction, whose changes the calling
+    /// thread must not see until they are committed.
+    pub fn writing_elsewhere(&self) -> bool {
+        let writer = self.writer.load(Ordering::SeqCst);
+        writer != 0 && writer != thread_tag()
+    }
+
+    /// True when the active transaction logged a change to the page it hasn't
+    /// committed yet.
+    pub fn is_uncommitted(&self, page_id: u32) -> bool {
+        self.wal.as_ref().is_some_and(|wal| wal.is_pending(page_id))
+    }
+
+    fn read_page_from_file(&self, page_id: u32) -> Result<Page> {
+        let offset = (page_id as u64) * (self.disk_page_size as u64);
+        let mut buffer = vec![0u8; self.disk_page_size];
+        if !self.read_mapped(offset, &mut buffer)? {
+            self.store.read_at(&mut buffer, offset)?;
+        }
+
+        let image = self.decode(page_id, buffer)?;
+        self.verify_checksum(page_id, &image)?;
+        Ok(Page::from_bytes(page_id, image))
+    }
+
+    // Turns a page image into the bytes stored on disk. The header page is
+    // never encrypted, only padded to the full page size.
+    fn encode(&self, page_id: u32, mut image: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.cipher {
+            Some(cipher) if page_id != 0 => cipher.encrypt(page_id, &image),
+            _ => {
+                image.resize(self.disk_page_size, 0);
+                Ok(image)
+            }
+        }
+    }
+
+    fn decode(&self, page_id: u32, mut data: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.cipher {
+            Some(cipher) if page_id != 0 => cipher.decrypt(page_id, &data)
+                .map_err(|e| anyhow!("Corruption detected in database '{}': {}", self.name, e)),
+            _ => {
+                data.truncate(self.page_size);
+                Ok(data)
+            }
+        }
+    }
 
-        Ok(Page::from_bytes(page_id, buffer))
+    fn verify_checksum(&self, page_id: u32, data: &[u8]) -> Result<()> {
+        let stored = page::stored_checksum(data);
+        let computed = page::compute_checksum(data);
+        if stored != computed {
+            return Err(RdbError::PageCorruption {
+                database: self.name.clone(),
+                page_id,
+                stored,
+                computed,
+            }.into());
+        }
+        Ok(())
     }
 
+    /// Writes a page. With the WAL enabled the image is appended to the log as part
+    /// of the active transaction (or as its own committed transaction if none is
+    /// active); the database file itself only changes at checkpoint.
     pub fn write_page(&self, page: &Page) -> Result<()> {
-        let mut file = self.file.lock().map_err(|_| anyhow!("Lock poisoned"))?;
-        
-        file.seek(SeekFrom::Start((page.id as u64) * (PAGE_SIZE as u64)))?;
-        file.write_all(&page.data)?;
-        
+        let mut slot = self.txn.lock();
+        self.write_page_locked(&mut slot, page)
+    }
+
+    fn write_page_locked(&self, slot: &mut TxnSlot, page: &Page) -> Result<()> {
+        if page.size() != self.page_size {
+            return Err(anyhow!("Page {} has {} bytes, database '{}' uses {} byte pages", page.id, page.size(), self.name, self.page_size));
+        }
+        let mut image = page.data.to_vec();
+        page::stamp_checksum(&mut image);
+        let data = self.encode(page.id, image)?;
+
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return self.write_page_to_file(page.id, &data),
+        };
+
+        match slot.active {
+            Some(txn_id) => {
+                wal.append_page(txn_id, page.id, &data)?;
+            }
+            None => {
+                let txn_id = slot.next_txn_id;
+                slot.next_txn_id += 1;
+                wal.append_page(txn_id, page.id, &data)?;
+                wal.commit(txn_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_page_to_file(&self, page_id: u32, data: &[u8]) -> Result<()> {
+        if let Some(changes) = &self.changes {
+            let mut changes = changes.lock();
+            changes.mark(page_id);
+            // Without the log the map must know before the file changes;
+            // checkpoints save it once at the end
+            if self.wal.is_none() {
+                changes.flush()?;
+            }
+        }
+        self.store.write_at(data, (page_id as u64) * (self.disk_page_size as u64))?;
+        Ok(())
+    }
+
+    /// Serves reads from the database file through a read-only memory map
+    /// instead of `pread`. Pages are still copied out, checksummed and decrypted.
+    /// Stores that can't be mapped, like memory, keep reading as before.
+    pub fn enable_mmap_reads(&self) -> Result<()> {
+        *self.mmap.write() = self.store.map()?;
         Ok(())
     }
-    
+
+    // Copies bytes out of the memory map. Returns false when mmap reads are off.
+    // The map is redone once the file has grown past it.
+    fn read_mapped(&self, offset: u64, buffer: &mut [u8]) -> Result<bool> {
+        let start = offset as usize;
+        let end = start + buffer.len();
+        {
+            let map = self.mmap.read();
+            match map.as_ref() {
+                None => return Ok(false),
+                Some(map) if end <= map.len() => {
+                    buffer.copy_from_slice(&map[start..end]);
+                    return Ok(true);
+                }
+                Some(_) => {}
+            }
+        }
+
+        let mut map = self.mmap.write();
+        if map.as_ref().is_some_and(|map| end > map.len()) {
+            *map = self.store.map()?;
+        }
+        match map.as_ref() {
+            Some(map) if end <= map.len() => {
+                buffer.copy_from_slice(&map[start..end]);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Returns a page ID for new data, reusing the head of the free list when
+    /// there is one and growing the file otherwise.
     pub fn allocate_page(&self) -> Result<u32> {
-        let mut file = self.file.lock().map_err(|_| anyhow!("Lock poisoned"))?;
+        {
+            let mut slot = self.txn.lock();
+            let mut state = self.header.lock();
+            if let Some(header) = state.header.as_mut()
+                && header.free_list_head != 0 {
+                    let page_id = header.free_list_head;
+                    // The cached header holds the active transaction's changes,
+                    // so its free list is read the same way
+                    let page = self.read_page_as(page_id, true)?;
+                    if &page.data[0..4] != FREE_PAGE_MAGIC {
+                        return Err(anyhow!("Free list of database '{}' is corrupt: page {} is not free", self.name, page_id));
+                    }
+                    header.free_list_head = LittleEndian::read_u32(&page.data[4..8]);
+                    header.free_page_count = header.free_page_count.saturating_sub(1);
+                    self.header_changed(&mut slot, &mut state)?;
+                    return Ok(page_id);
+            }
+        }
+
         let page_id = self.total_pages.fetch_add(1, Ordering::SeqCst);
-        
+
         // Write empty page to extend file
-        let page = Page::new(page_id);
-        file.seek(SeekFrom::Start((page_id as u64) * (PAGE_SIZE as u64)))?;
-        file.write_all(&page.data)?;
-        
+        let mut image = vec![0u8; self.page_size];
+        page::stamp_checksum(&mut image);
+        let data = self.encode(page_id, image)?;
+        self.write_page_to_file(page_id, &data)?;
+
         Ok(page_id)
     }
 
-    #[allow(dead_code)]
+    /// Puts a page on the free list so `allocate_page` can hand it out again.
+    /// The caller must make sure nothing references the page any more.
+    pub fn free_page(&self, page_id: u32) -> Result<()> {
+        if page_id == 0 {
+            return Err(anyhow!("Cannot free the header page"));
+        }
+
+        let mut slot = self.txn.lock();
+        let mut state = self.header.lock();
+        let header = state.header.as_mut().ok_or(anyhow!("Database '{}' has no header", self.name))?;
+
+        let mut page = Page::new(page_id, self.page_size);
+        page.data[0..4].copy_from_slice(FREE_PAGE_MAGIC);
+        LittleEndian::write_u32(&mut page.data[4..8], header.free_list_head);
+        self.write_page_locked(&mut slot, &page)?;
+
+        let header = state.header.as_mut().ok_or(anyhow!("Database '{}' has no header", self.name))?;
+        header.free_list_head = page_id;
+        header.free_page_count += 1;
+        self.header_changed(&mut slot, &mut state)
+    }
+
+    // Inside a transaction the header is written at commit; otherwise right away.
+    fn header_changed(&self, slot: &mut TxnSlot, state: &mut HeaderState) -> Result<()> {
+        if slot.active.is_some() {
+            state.dirty = true;
+            return Ok(());
+        }
+        if let Some(header) = &state.header {
+            let page = self.header_page(header)?;
+            self.write_page_locked(slot, &page)?;
+        }
+        Ok(())
+    }
+
+    /// Starts a write transaction, waiting for any other writer to finish first.
+    pub fn begin(&self) -> Result<u64> {
+        let mut slot = self.txn.lock();
+        while slot.active.is_some() {
+            self.txn_done.wait(&mut slot);
+        }
+        let txn_id = slot.next_txn_id;
+        slot.next_txn_id += 1;
+        slot.active = Some(txn_id);
+        slot.start_pages = self.total_pages.load(Ordering::SeqCst);
+        self.writer.store(thread_tag(), Ordering::SeqCst);
+        Ok(txn_id)
+    }
+
+    /// Makes the active transaction durable. Page images must already have been
+    /// handed to `write_page`. A transaction that fails to commit stays active
+    /// and must be rolled back.
+    pub fn commit(&self) -> Result<()> {
+        let mut slot = self.txn.lock();
+        let txn_id = slot.active.ok_or(anyhow!("No active transaction"))?;
+
+        self.write_dirty_header(&mut slot).and_then(|_| match &self.wal {
+            Some(wal) => wal.commit(txn_id).map(|_| ()),
+            None => self.sync(),
+        })?;
+
+        self.header.lock().dirty = false;
+        slot.active = None;
+        self.writer.store(0, Ordering::SeqCst);
+        self.txn_done.notify_all();
+
+        // The slot lock is still held, so no new transaction can start
+        // mid-checkpoint. The commit is durable either way: a checkpoint that
+        // fails is retried by the next one.
+        if let Some(wal) = &self.wal
+            && wal.size() > WAL_AUTO_CHECKPOINT_BYTES {
+                let _ = self.checkpoint_locked(wal);
+        }
+        Ok(())
+    }
+
+    /// Abandons the active transaction. Returns the pages whose logged images were
+    /// discarded so cached copies can be dropped as well.
+    pub fn rollback(&self) -> Result<Vec<u32>> {
+        let mut slot = self.txn.lock();
+        let txn_id = slot.active.ok_or(anyhow!("No active transaction"))?;
+
+        let result = match &self.wal {
+            // Pages the transaction added are only referenced by pages the
+            // log just discarded
+            Some(wal) => {
+                let discarded = wal.abort(txn_id);
+                if self.total_pages.load(Ordering::SeqCst) > slot.start_pages {
+                    self.cut(slot.start_pages).map(|_| discarded)
+                } else {
+                    Ok(discarded)
+                }
+            }
+            None => Ok(Vec::new()),
+        };
+
+        // Forget free-list changes made by the transaction
+        let mut state = self.header.lock();
+        let reload = if state.dirty {
+            state.dirty = false;
+            self.read_header().map(|header| state.header = Some(header))
+        } else {
+            Ok(())
+        };
+        drop(state);
+
+        slot.active = None;
+        self.writer.store(0, Ordering::SeqCst);
+        self.txn_done.notify_all();
+        reload?;
+        result
+    }
+
+    // The header stays dirty until the commit is durable, so a rollback after
+    // a failed commit still reloads it
+    fn write_dirty_header(&self, slot: &mut TxnSlot) -> Result<()> {
+        let state = self.header.lock();
+        if state.dirty
+            && let Some(header) = &state.header {
+                let page = self.header_page(header)?;
+                self.write_page_locked(slot, &page)?;
+        }
+        Ok(())
+    }
+
+    /// Copies every committed log frame into the database file, syncs it and
+    /// empties the log. Waits for an active transaction to finish first.
+    pub fn checkpoint(&self) -> Result<()> {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return self.sync(),
+        };
+
+        let mut slot = self.txn.lock();
+        while slot.active.is_some() {
+            self.txn_done.wait(&mut slot);
+        }
+        self.checkpoint_locked(wal)
+    }
+
+    /// Checkpoints and records it in the header, so the file itself shows when
+    /// it last held every committed change.
+    pub fn full_checkpoint(&self) -> Result<()> {
+        let mut slot = self.txn.lock();
+        self.wait_for_checkpoint(&mut slot);
+
+        let marker = {
+            let mut state = self.header.lock();
+            match state.header.as_mut() {
+                Some(header) => {
+                    header.last_checkpoint_at = chrono::Utc::now().timestamp();
+                    header.checkpoint_count += 1;
+                    Some(self.header_page(header)?)
+                }
+                None => None,
+            }
+        };
+        if let Some(page) = marker {
+            self.write_page_locked(&mut slot, &page)?;
+        }
+
+        match &self.wal {
+            Some(wal) => self.checkpoint_locked(wal),
+            None => self.sync(),
+        }
+    }
+
+    /// Shuts the database down cleanly: waits for the active transaction,
+    /// marks the header as no longer in use, checkpoints and fsyncs. Dropping
+    /// the pager does the same.
+    pub fn close(&self) -> Result<()> {
+        let mut slot = self.txn.lock();
+        self.wait_for_checkpoint(&mut slot);
+
+        let closed = {
+            let mut state = self.header.lock();
+            match state.header.as_mut() {
+                Some(header) if !self.old_format && header.in_use => {
+                    header.in_use = false;
+                    Some(self.header_page(header)?)
+                }
+                _ => None,
+            }
+        };
+        if let Some(page) = closed {
+            self.write_page_locked(&mut slot, &page)?;
+        }
+
+        match &self.wal {
+            Some(wal) => self.checkpoint_locked(wal),
+            None => self.sync(),
+        }
+    }
+
+    /// Cuts free pages off the end of the file and returns how many were cut.
+    /// Waits for the active transaction, and checkpoints first so the log holds
+    /// no page past the new end.
+    pub fn truncate_free_pages(&self) -> Result<u32> {
+        let mut slot = self.txn.lock();
+        while slot.active.is_some() {
+            self.txn_done.wait(&mut slot);
+        }
+        if let Some(wal) = &self.wal {
+            self.checkpoint_locked(wal)?;
+        }
+
+        // A snapshot is copying the file; a later vacuum shrinks it
+        if self.snapshots.load(Ordering::SeqCst) > 0 {
+            return Ok(0);
+        }
+
+        let mut state = self.header.lock();
+        let Some(header) = state.header.as_mut() else {
+            return Ok(0);
+        };
+        let total_pages = self.total_pages.load(Ordering::SeqCst);
+
+        let mut free = self.read_free_list(header)?;
+        let free_ids: HashSet<u32> = free.iter().map(|(page_id, _)| *page_id).collect();
+        let mut new_total = total_pages;
+        while new_total > 1 && free_ids.contains(&(new_total - 1)) {
+            new_total -= 1;
+        }
+        if new_total == total_pages {
+            return Ok(0);
+        }
+
+        free.retain(|(page_id, _)| *page_id < new_total);
+        let Some(wal) = &self.wal else {
+            // The relinked free list reaches the file before it shrinks, so a
+            // crash in between only leaks the pages past the end
+            self.relink_free_pages(&mut slot, &free, header)?;
+            self.sync()?;
+            self.cut(new_total)?;
+            self.sync()?;
+            return Ok(total_pages - new_total);
+        };
+
+        // With the log the relinked free list commits together with the cut,
+        // which the checkpoint then makes. After a crash, recovery redoes both.
+        let txn_id = slot.next_txn_id;
+        slot.next_txn_id += 1;
+        slot.active = Some(txn_id);
+        let logged = self.relink_free_pages(&mut slot, &free, header)
+            .and_then(|_| wal.log_truncate(txn_id, new_total))
+            .and_then(|_| wal.commit(txn_id).map(|_| ()));
+        slot.active = None;
+        if let Err(e) = logged {
+            wal.abort(txn_id);
+            *header = self.read_header()?;
+            return Err(e);
+        }
+        self.checkpoint_locked(wal)?;
+        Ok(total_pages - new_total)
+    }
+
+    // Points each free page at the one after it and the header at the first
+    fn relink_free_pages(&self, slot: &mut TxnSlot, free: &[(u32, u32)], header: &mut DatabaseHeader) -> Result<()> {
+        for i in 0..free.len() {
+            let next = free.get(i + 1).map_or(0, |(page_id, _)| *page_id);
+            if free[i].1 != next {
+                let mut page = Page::new(free[i].0, self.page_size);
+                page.data[0..4].copy_from_slice(FREE_PAGE_MAGIC);
+                LittleEndian::write_u32(&mut page.data[4..8], next);
+                self.write_page_locked(slot, &page)?;
+            }
+        }
+        header.free_list_head = free.first().map_or(0, |(page_id, _)| *page_id);
+        header.free_page_count = free.len() as u32;
+        let page = self.header_page(header)?;
+        self.write_page_locked(slot, &page)
+    }
+
+    // Cuts the file to `pages` pages
+    fn cut(&self, pages: u32) -> Result<()> {
+        if let Some(changes) = &self.changes {
+            changes.lock().truncate(pages)?;
+        }
+        // The map must not cover bytes that are about to go away
+        let mut map = self.mmap.write();
+        let mapped = map.take().is_some();
+        self.store.set_size(pages as u64 * self.disk_page_size as u64)?;
+        self.total_pages.store(pages, Ordering::SeqCst);
+        if mapped {
+            *map = self.store.map()?;
+        }
+        Ok(())
+    }
+
+    /// Copies the database file as of the last commit to `writer`. Waits for
+    /// the active transaction and any other snapshot, then checkpoints. With
+    /// the WAL, the file then stays unchanged until the snapshot is finished:
+    /// later commits go to the log and checkpoints are held off. So writers
+    /// only wait for the checkpoint. Without the WAL they wait for the whole
+    /// copy.
+    ///
+    /// An incremental snapshot copies only the pages changed since the last
+    /// backup, each preceded by its ID. Either kind must be followed by
+    /// `finish_snapshot` once the backup is safely stored, or not.
+    pub fn snapshot_to(&self, incremental: bool, writer: &mut dyn Write) -> Result<Snapshot> {
+        let mut slot = self.txn.lock();
+        self.wait_for_checkpoint(&mut slot);
+        if let Some(wal) = &self.wal {
+            self.checkpoint_locked(wal)?;
+        }
+        let pages = self.total_pages.load(Ordering::SeqCst);
+        let changes = match &self.changes {
+            Some(changes) => Some(changes.lock().start_backup()?),
+            None => None,
+        };
+        self.snapshots.fetch_add(1, Ordering::SeqCst);
+
+        let wal_lsn = self.wal.as_ref().map(|wal| wal.base_lsn());
+        let mut snapshot = Snapshot { pages, base: None, changed: Vec::new(), wal_lsn };
+        if incremental {
+            match changes {
+                Some(changes) if changes.since.is_some() => {
+                    let mut changed: BTreeSet<u32> = changes.changed.into_iter().filter(|&page_id| page_id < pages).collect();
+                    changed.extend(changes.pages.min(pages)..pages);
+                    snapshot.base = changes.since;
+                    snapshot.changed = changed.into_iter().collect();
+                }
+                _ => {
+                    drop(slot);
+                    self.finish_snapshot(None)?;
+                    return Err(anyhow!("Database '{}' has no backup to base an incremental backup on; take a full backup first", self.name));
+                }
+            }
+        }
+
+        let held = if self.wal.is_some() {
+            drop(slot);
+            None
+        } else {
+            Some(slot)
+        };
+        let copied = if incremental {
+            self.copy_pages(snapshot.changed.iter().copied(), true, writer)
+        } else {
+            self.copy_pages(0..pages, false, writer)
+        };
+        drop(held);
+        if let Err(e) = copied {
+            self.finish_snapshot(None)?;
+            return Err(e);
+        }
+        Ok(snapshot)
+    }
+
+    /// Ends a snapshot. Once its backup is stored, pass the backup's ID:
+    /// changes are tracked relative to it from then on.
+    pub fn finish_snapshot(&self, completed: Option<(Uuid, &Snapshot)>) -> Result<()> {
+        let finished = match &self.changes {
+            Some(changes) => changes.lock().finish_backup(completed.map(|(id, snapshot)| (id, snapshot.pages))),
+            None => Ok(()),
+        };
+        // Under the slot lock, so a checkpoint about to wait can't miss the wakeup
+        let _slot = self.txn.lock();
+        self.snapshots.fetch_sub(1, Ordering::SeqCst);
+        self.snapshot_done.notify_all();
+        finished
+    }
+
+    // Waits until neither a transaction nor a snapshot is running, so a
+    // checkpoint can copy the log into the file. The slot lock is released
+    // while waiting.
+    fn wait_for_checkpoint(&self, slot: &mut parking_lot::MutexGuard<'_, TxnSlot>) {
+        loop {
+            while slot.active.is_some() {
+                self.txn_done.wait(slot);
+            }
+            if self.snapshots.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            self.snapshot_done.wait(slot);
+        }
+    }
+
+    fn copy_pages(&self, page_ids: impl Iterator<Item = u32>, with_ids: bool, writer: &mut dyn Write) -> Result<()> {
+        let mut buffer = vec![0u8; self.disk_page_size];
+        for page_id in page_ids {
+            if with_ids {
+                writer.write_all(&page_id.to_le_bytes())?;
+            }
+            match self.store.read_at(&mut buffer, page_id as u64 * self.disk_page_size as u64) {
+                Ok(()) => writer.write_all(&buffer)?,
+                // Allocated just before the snapshot but not written out yet:
+                // nothing committed refers to it, so it is copied as a new page
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    let mut image = vec![0u8; self.page_size];
+                    page::stamp_checksum(&mut image);
+                    writer.write_all(&self.encode(page_id, image)?)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Pages on the free list, in list order.
+    pub fn free_pages(&self) -> Result<Vec<u32>> {
+        let state = self.header.lock();
+        match state.header.as_ref() {
+            Some(header) => Ok(self.read_free_list(header)?.into_iter().map(|(page_id, _)| page_id).collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // Free pages with their successor on the list
+    fn read_free_list(&self, header: &DatabaseHeader) -> Result<Vec<(u32, u32)>> {
+        let total_pages = self.total_pages.load(Ordering::SeqCst);
+        let mut free = Vec::new();
+        let mut page_id = header.free_list_head;
+        while page_id != 0 {
+            if free.len() >= total_pages as usize {
+                return Err(anyhow!("Free list of database '{}' has a cycle", self.name));
+            }
+            let page = self.read_page_as(page_id, true)?;
+            if &page.data[0..4] != FREE_PAGE_MAGIC {
+                return Err(anyhow!("Free list of database '{}' is corrupt: page {} is not free", self.name, page_id));
+            }
+            let next = LittleEndian::read_u32(&page.data[4..8]);
+            free.push((page_id, next));
+            page_id = next;
+        }
+        Ok(free)
+    }
+
+    fn checkpoint_locked(&self, wal: &Wal) -> Result<()> {
+        // A snapshot is reading the file, which must not change under it. The
+        // log keeps growing until the next checkpoint after the snapshot;
+        // callers that need the checkpoint wait for snapshots first.
+        if wal.is_empty() || self.snapshots.load(Ordering::SeqCst) > 0 {
+            return Ok(());
+        }
+        if let Some(archive) = &self.archive {
+            wal.archive_to(archive)?;
+        }
+        wal.apply_truncate(|pages| self.cut(pages))?;
+        wal.checkpoint(|page_id, data| self.write_page_to_file(page_id, data))?;
+        if let Some(changes) = &self.changes {
+            changes.lock().flush()?;
+        }
+        self.sync()?;
+        wal.reset()
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.store.sync()?;
+        Ok(())
+    }
+
+    /// First page of the catalog chain.
+    pub fn catalog_root(&self) -> u32 {
+        self.header.lock().header.as_ref().map_or(1, |header| header.root_catalog_page)
+    }
+
+    // The header fits in the first bytes of page 0; the rest of the image is zeros
+    fn header_page(&self, header: &DatabaseHeader) -> Result<Page> {
+        let mut bytes = header.to_bytes()?;
+        bytes.truncate(self.page_size);
+        Ok(Page::from_bytes(0, bytes))
+    }
+
     pub fn read_header(&self) -> Result<DatabaseHeader> {
         let page0 = self.read_page(0)?;
         DatabaseHeader::from_bytes(&page0.data)
     }
 
     pub fn write_header(&self, header: &DatabaseHeader) -> Result<()> {
-        let bytes = header.to_bytes()?;
-        let page = Page::from_bytes(0, bytes);
-        self.write_page(&page)
+        if header.page_size as usize != self.disk_page_size {
+            return Err(anyhow!("Header page size {} does not match the file's {}", header.page_size, self.disk_page_size));
+        }
+        if header.encryption != self.cipher.is_some() {
+            return Err(anyhow!("Header encryption flag does not match how database '{}' was opened", self.name));
+        }
+        let page = self.header_page(header)?;
+        self.write_page(&page)?;
+        self.header.lock().header = Some(header.clone());
+        Ok(())
+    }
+}
+
+// What a pager is opened on
+struct Location {
+    name: String,
+    store: Arc<dyn PageStore>,
+    log: LogStore,
+    // The database file, for the files kept next to it
+    path: Option<PathBuf>,
+}
+
+impl Location {
+    fn at(path: &Path) -> Result<Self> {
+        if store::is_memory(path) {
+            return Ok(Self {
+                name: "memory".to_string(),
+                store: Arc::new(MemoryStore::new()),
+                log: LogStore::Store(Arc::new(MemoryStore::new())),
+                path: None,
+            });
+        }
+        let name = path.file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(Self {
+            name,
+            store: Arc::new(FileStore::open(path)?),
+            log: LogStore::File(Wal::path_for(path)),
+            path: Some(path.to_path_buf()),
+        })
+    }
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        // A clean close leaves an empty log behind
+        if self.txn.lock().active.is_none() {
+            let _ = self.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn create_db(path: &Path) {
+        let pager = Pager::open(path).unwrap();
+        pager.allocate_page().unwrap();
+        pager.write_header(&DatabaseHeader::new("test".to_string(), DEFAULT_PAGE_SIZE)).unwrap();
+        pager.allocate_page().unwrap();
+    }
+
+    #[test]
+    fn test_pager_recovers_committed_transaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        create_db(&db_path);
+
+        {
+            let pager = Pager::open(&db_path).unwrap();
+            assert!(pager.wal.is_some());
+
+            pager.begin().unwrap();
+            let mut page = Page::new(1, DEFAULT_PAGE_SIZE);
+            page.data[0] = 42;
+            pager.write_page(&page).unwrap();
+            pager.commit().unwrap();
+
+            pager.begin().unwrap();
+            page.data[0] = 99;
+            pager.write_page(&page).unwrap();
+            // Simulate a crash: skip the checkpoint that a clean close would do
+            std::mem::forget(pager);
+        }
+
+        let pager = Pager::open(&db_path).unwrap();
+        assert_eq!(pager.recovery_report().committed_txns, 1);
+        assert_eq!(pager.recovery_report().rolled_back_txns, 1);
+        assert_eq!(pager.read_page(1).unwrap().data[0], 42);
+    }
+
+    #[test]
+    fn test_pager_rollback_discards_frames() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        create_db(&db_path);
+
+        let pager = Pager::open(&db_path).unwrap();
+        pager.begin().unwrap();
+        let mut page = Page::new(1, DEFAULT_PAGE_SIZE);
+        page.data[0] = 7;
+        pager.write_page(&page).unwrap();
+        assert_eq!(pager.rollback().unwrap(), vec![1]);
+
+        assert_eq!(pager.read_page(1).unwrap().data[0], 0);
+    }
+
+    #[test]
+    fn test_pager_reuses_freed_pages() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        create_db(&db_path);
+
+        let pager = Pager::open(&db_path).unwrap();
+        let a = pager.allocate_page().unwrap();
+        let b = pager.allocate_page().unwrap();
+        pager.free_page(a).unwrap();
+        pager.free_page(b).unwrap();
+        assert_eq!(pager.read_header().unwrap().free_page_count, 2);
+
+        // A rolled-back allocation leaves the free list as it was
+        pager.begin().unwrap();
+        assert_eq!(pager.allocate_page().unwrap(), b);
+        pager.rollback().unwrap();
+        assert_eq!(pager.read_header().unwrap().free_list_head, b);
+
+        assert_eq!(pager.allocate_page().unwrap(), b);
+        assert_eq!(pager.allocate_page().unwrap(), a);
+        assert_eq!(pager.allocate_page().unwrap(), 4);
+        assert_eq!(pager.read_header().unwrap().free_page_count, 0);
+    }
+
+    #[test]
+    fn test_snapshot_waits_for_the_snapshot_before_it() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        create_db(&db_path);
+        let pager = Arc::new(Pager::open(&db_path).unwrap());
+        let first = pager.snapshot_to(false, &mut Vec::new()).unwrap();
+
+        // Committed to the log while the first snapshot holds off checkpoints
+        let mut page = Page::new(1, DEFAULT_PAGE_SIZE);
+        page.data[0] = 42;
+        pager.write_page(&page).unwrap();
+
+        let second = {
+            let pager = pager.clone();
+            std::thread::spawn(move || {
+                let mut copy = Vec::new();
+                pager.snapshot_to(false, &mut copy).unwrap();
+                pager.finish_snapshot(None).unwrap();
+                copy
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!second.is_finished());
+
+        pager.finish_snapshot(None).unwrap();
+        drop(first);
+        let copy = second.join().unwrap();
+        assert_eq!(copy[DEFAULT_PAGE_SIZE], 42);
+    }
+
+    #[test]
+    fn test_pager_uses_page_size_from_header() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        {
+            let pager = Pager::create(&db_path, 4096, None).unwrap();
+            pager.allocate_page().unwrap();
+            pager.write_header(&DatabaseHeader::new("test".to_string(), 4096)).unwrap();
+            pager.allocate_page().unwrap();
+            assert!(Pager::create(&db_path, 4096, None).is_err());
+        }
+        assert_eq!(std::fs::metadata(&db_path).unwrap().len(), 2 * 4096);
+
+        let pager = Pager::open(&db_path).unwrap();
+        assert_eq!(pager.page_size(), 4096);
+        let mut page = Page::new(1, 4096);
+        page.data[0] = 9;
+        pager.write_page(&page).unwrap();
+        assert_eq!(pager.read_page(1).unwrap().data[0], 9);
+        assert!(pager.write_page(&Page::new(1, DEFAULT_PAGE_SIZE)).is_err());
+        assert!(Pager::create(&temp_dir.path().join("odd.db"), 3000, None).is_err());
+    }
+
+    #[test]
+    fn test_pager_encrypts_pages() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let key = [3u8; crypto::KEY_SIZE];
+        {
+            let pager = Pager::create(&db_path, 4096, Some(key)).unwrap();
+            pager.allocate_page().unwrap();
+            let mut header = DatabaseHeader::new("test".to_string(), 4096);
+            header.encryption = true;
+            header.key_check = crypto::key_check(&key);
+            pager.write_header(&header).unwrap();
+            pager.allocate_page().unwrap();
+        }
+        {
+            let pager = Pager::open_with_key(&db_path, Some(key)).unwrap();
+            assert_eq!(pager.page_size(), 4096 - ENCRYPTION_OVERHEAD);
+            pager.begin().unwrap();
+            let mut page = Page::new(1, pager.page_size());
+            page.data[..12].copy_from_slice(b"secret value");
+            pager.write_page(&page).unwrap();
+            pager.commit().unwrap();
+            // Leave the committed frame in the WAL
+            std::mem::forget(pager);
+        }
+        let wal_bytes = std::fs::read(Wal::path_for(&db_path)).unwrap();
+        assert!(!wal_bytes.windows(12).any(|w| w == b"secret value"));
+
+        let pager = Pager::open_with_key(&db_path, Some(key)).unwrap();
+        assert_eq!(&pager.read_page(1).unwrap().data[..12], b"secret value");
+        drop(pager);
+
+        // The header stays readable, the data does not
+        let file_bytes = std::fs::read(&db_path).unwrap();
+        assert!(!file_bytes.windows(12).any(|w| w == b"secret value"));
+        assert!(DatabaseHeader::read_from_file(&db_path).unwrap().encryption);
+
+        let err = Pager::open_with_key(&db_path, Some([4u8; crypto::KEY_SIZE])).err().unwrap();
+        assert!(err.to_string().contains("Wrong encryption key"));
+    }
+
+    #[test]
+    fn test_pager_mmap_reads_from_many_threads() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        create_db(&db_path);
+
+        let pager = std::sync::Arc::new(Pager::open(&db_path).unwrap());
+        pager.enable_mmap_reads().unwrap();
+        for i in 0..32u8 {
+            let mut page = Page::new(pager.allocate_page().unwrap(), DEFAULT_PAGE_SIZE);
+            page.data[0] = i;
+            pager.write_page(&page).unwrap();
+        }
+        // Move the pages from the WAL into the file, past the end of the first map
+        pager.checkpoint().unwrap();
+
+        let readers: Vec<_> = (0..4).map(|t| {
+            let pager = pager.clone();
+            std::thread::spawn(move || {
+                for i in (t..32u8).step_by(4) {
+                    assert_eq!(pager.read_page(2 + i as u32).unwrap().data[0], i);
+                }
+            })
+        }).collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert!(pager.mmap.read().as_ref().unwrap().len() >= 34 * DEFAULT_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_pager_detects_unclean_shutdown() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        drop(Pager::create_database(&db_path, "test", DEFAULT_PAGE_SIZE, None).unwrap());
+        assert!(!DatabaseHeader::read_from_file(&db_path).unwrap().in_use);
+
+        let pager = Pager::open(&db_path).unwrap();
+        assert!(!pager.unclean_shutdown());
+        assert!(DatabaseHeader::read_from_file(&db_path).unwrap().in_use);
+        std::mem::forget(pager);
+
+        let pager = Pager::open(&db_path).unwrap();
+        assert!(pager.unclean_shutdown());
+        pager.close().unwrap();
+        assert!(!DatabaseHeader::read_from_file(&db_path).unwrap().in_use);
+        drop(pager);
+
+        assert!(!Pager::open(&db_path).unwrap().unclean_shutdown());
+    }
+
+    #[test]
+    fn test_pager_checks_format_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        create_db(&db_path);
+        {
+            let pager = Pager::open(&db_path).unwrap();
+            let mut header = pager.read_header().unwrap();
+            header.last_opened_at = 0;
+            header.last_opened_with_engine = "0.0.1".to_string();
+            pager.write_header(&header).unwrap();
+        }
+
+        // Every open records when and by which engine
+        let pager = Pager::open(&db_path).unwrap();
+        let mut header = pager.read_header().unwrap();
+        assert!(header.last_opened_at > 0);
+        assert_eq!(header.last_opened_with_engine, env!("CARGO_PKG_VERSION"));
+
+        header.file_format_version = CURRENT_FILE_FORMAT_VERSION + 1;
+        pager.write_header(&header).unwrap();
+        drop(pager);
+        let err = Pager::open(&db_path).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RdbError>(), Some(RdbError::FormatTooNew { .. })));
+    }
+
+    #[test]
+    fn test_pager_detects_corrupted_page() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        {
+            let pager = Pager::open(&db_path).unwrap();
+            pager.allocate_page().unwrap();
+            let mut page = Page::new(1, DEFAULT_PAGE_SIZE);
+            pager.allocate_page().unwrap();
+            page.data[100] = 5;
+            pager.write_page(&page).unwrap();
+        }
+
+        // Flip a byte in page 1 behind the pager's back
+        let mut bytes = std::fs::read(&db_path).unwrap();
+        bytes[DEFAULT_PAGE_SIZE + 100] ^= 0xFF;
+        std::fs::write(&db_path, bytes).unwrap();
+
+        let pager = Pager::open(&db_path).unwrap();
+        let err = pager.read_page(1).err().unwrap();
+        match err.downcast_ref::<RdbError>() {
+            Some(RdbError::PageCorruption { database, page_id, .. }) => {
+                assert_eq!(database, "test");
+                assert_eq!(*page_id, 1);
+            }
+            _ => panic!("expected a corruption error, got {}", err),
+        }
+        assert!(pager.read_page(0).is_ok());
     }
 }
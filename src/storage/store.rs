@@ -0,0 +1,250 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use memmap2::Mmap;
+use parking_lot::RwLock;
+
+/// Path that opens a database kept in memory instead of a file. Every open
+/// gets a new, empty one, which goes away with its pager.
+pub const MEMORY_PATH: &str = ":memory:";
+
+/// Returns true for the path of an in-memory database.
+pub fn is_memory(path: &Path) -> bool {
+    path.as_os_str() == MEMORY_PATH
+}
+
+/// Where a pager keeps its pages, and a log its records: bytes at offsets.
+/// Pages are read and written whole at multiples of the page size, so
+/// different pages never overlap. A database file is one store, its log
+/// another.
+pub trait PageStore: Send + Sync {
+    /// Fills `buf` from `offset`. Fails with `UnexpectedEof` when the store
+    /// ends first.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Writes `data` at `offset`, growing the store as needed.
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Current size in bytes.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Cuts the store to `size` bytes or grows it with zeros.
+    fn set_size(&self, size: u64) -> io::Result<()>;
+
+    /// Makes every write so far survive a crash.
+    fn sync(&self) -> io::Result<()>;
+
+    /// A read-only map of the whole store, for stores that can be mapped.
+    fn map(&self) -> io::Result<Option<Mmap>> {
+        Ok(None)
+    }
+}
+
+/// A store in a file.
+pub struct FileStore {
+    // Accessed with positional reads and writes only, so no lock is needed
+    file: File,
+}
+
+impl FileStore {
+    /// Opens the file at `path`, creating it when it doesn't exist.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Self { file })
+    }
+}
+
+impl PageStore for FileStore {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_exact_at(&self.file, buf, offset)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        write_all_at(&self.file, data, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        // Also flushes the file size, which is all the metadata reads need
+        self.file.sync_data()
+    }
+
+    fn map(&self) -> io::Result<Option<Mmap>> {
+        // SAFETY: the file is only changed through this store's positional
+        // writes, which a shared mapping observes, and the pager drops its map
+        // before the file shrinks
+        Ok(Some(unsafe { Mmap::map(&self.file)? }))
+    }
+}
+
+/// A store in memory. Syncing does nothing: nothing outlives the process.
+#[derive(Default)]
+pub struct MemoryStore {
+    bytes: RwLock<Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PageStore for MemoryStore {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let bytes = self.bytes.read();
+        let start = offset as usize;
+        match bytes.get(start..start + buf.len()) {
+            Some(stored) => {
+                buf.copy_from_slice(stored);
+                Ok(())
+            }
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        let mut bytes = self.bytes.write();
+        let start = offset as usize;
+        if bytes.len() < start + data.len() {
+            bytes.resize(start + data.len(), 0);
+        }
+        bytes[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.bytes.read().len() as u64)
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.bytes.write().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Positional file access: reads and writes on different pages never wait for each other
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::Query;
+    use crate::query::executor::{Executor, ExecutionResult};
+    use crate::storage::backup;
+    use crate::storage::buffer::BufferPool;
+    use crate::storage::pager::Pager;
+    use serde_json::{Value, json};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn count_rows(executor: &Executor) -> usize {
+        let query = json!({"op": "select", "database": "main", "from": "t", "columns": ["id"]});
+        match executor.execute(serde_json::from_value(query).unwrap()).unwrap() {
+            ExecutionResult::Json(Value::Array(rows)) => rows.len(),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_memory_database_works_like_a_file() {
+        let pager = Arc::new(Pager::create_database(Path::new(MEMORY_PATH), "main", 4096, None).unwrap());
+        pager.enable_mmap_reads().unwrap();
+        // Uncommitted writes only reach the log
+        pager.begin().unwrap();
+        let mut page = pager.read_page(1).unwrap();
+        page.data[100] = 0xff;
+        pager.write_page(&page).unwrap();
+        pager.rollback().unwrap();
+        assert_eq!(pager.read_page(1).unwrap().data[100], 0);
+
+        // A small pool writes pages out before their transaction ends
+        let pool = Arc::new(BufferPool::new(8));
+        pool.register_pager(0, pager.clone());
+        let executor = Executor::new(pool.clone());
+        let run = |query: Value| executor.execute(serde_json::from_value(query).unwrap());
+
+        run(json!({"op": "create_table", "database": "main", "table": "t", "columns": [{"name": "id", "type": "int", "primary_key": true}]})).unwrap();
+        let rows: Vec<Value> = (0..200).map(|id| json!({"id": id, "body": "x".repeat(300)})).collect();
+        run(json!({"op": "insert", "database": "main", "table": "t", "values": rows})).unwrap();
+
+        // A failed batch is rolled back through the log
+        let rows: Vec<Value> = (200..400).map(|id| json!({"id": id, "body": "y".repeat(300)})).collect();
+        let batch = Query::Batch(vec![
+            serde_json::from_value(json!({"op": "insert", "database": "main", "table": "t", "values": rows})).unwrap(),
+            serde_json::from_value(json!({"op": "insert", "database": "main", "table": "missing", "values": []})).unwrap(),
+        ]);
+        assert!(executor.execute(batch).is_err());
+        assert_eq!(count_rows(&executor), 200);
+
+        run(json!({"op": "delete", "database": "main", "table": "t", "where": {"column": "id", "cmp": ">=", "value": 100}})).unwrap();
+        run(json!({"op": "vacuum", "database": "main"})).unwrap();
+        assert_eq!(count_rows(&executor), 100);
+
+        // A backup of it restores to a file
+        let temp_dir = TempDir::new().unwrap();
+        backup::backup(&pager, &temp_dir.path().join("backup"), false).unwrap();
+        let restored = temp_dir.path().join("restored.db");
+        backup::restore(&[temp_dir.path().join("backup")], &restored, None).unwrap();
+        let pool = Arc::new(BufferPool::new(64));
+        pool.register_pager(0, Arc::new(Pager::open(&restored).unwrap()));
+        assert_eq!(count_rows(&Executor::new(pool)), 100);
+
+        // Every open is a new database
+        assert_eq!(Pager::open(Path::new(MEMORY_PATH)).unwrap().total_pages.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::core::error::RdbError;
+use crate::query::{Query, CreateTableQuery, InsertQuery};
+use crate::query::executor::Executor;
+use crate::storage::buffer::{BufferPool, GlobalPageId};
+use crate::storage::catalog::Catalog;
+use crate::storage::changes::ChangeMap;
+use crate::storage::crypto::{self, EncryptionKey};
+use crate::storage::header::{DatabaseHeader, CURRENT_FILE_FORMAT_VERSION};
+use crate::storage::page::{Page, PAGE_CHECKSUM_SIZE};
+use crate::storage::pager::Pager;
+use crate::storage::slotted::SlottedPage;
+use crate::storage::wal::Wal;
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+
+// v1 files have 8192 byte pages without checksums
+const V1_PAGE_SIZE: usize = 8192;
+// Rows are copied over in inserts of this many
+const INSERT_BATCH: usize = 500;
+
+pub struct UpgradeReport {
+    pub from_version: u32,
+    pub tables: usize,
+    pub rows: usize,
+}
+
+// The database being upgraded. Formats from v2 on are read through the pager,
+// which verifies checksums and replays the WAL.
+enum Source {
+    Raw(File),
+    Paged(Arc<BufferPool>),
+}
+
+impl Source {
+    fn open(path: &Path, header: &DatabaseHeader, key: Option<EncryptionKey>) -> Result<Self> {
+        if header.file_format_version > 1 {
+            let pool = Arc::new(BufferPool::new(64));
+            pool.register_pager(0, Arc::new(Pager::open_for_upgrade(path, key)?));
+            return Ok(Source::Paged(pool));
+        }
+        let wal_path = Wal::path_for(path);
+        if wal_path.metadata().is_ok_and(|m| m.len() > 0) {
+            return Err(anyhow!("{:?} has a write-ahead log this engine cannot replay; open the database with the engine that wrote it first", wal_path));
+        }
+        Ok(Source::Raw(File::open(path)?))
+    }
+
+    fn read_page(&mut self, page_id: u32) -> Result<Page> {
+        match self {
+            Source::Raw(file) => {
+                // The spare bytes stand in for the checksum v1 pages lack, so
+                // slotted pages see all 8192 bytes as usable
+                let mut data = vec![0u8; V1_PAGE_SIZE + PAGE_CHECKSUM_SIZE];
+                file.seek(SeekFrom::Start(page_id as u64 * V1_PAGE_SIZE as u64))?;
+                file.read_exact(&mut data[..V1_PAGE_SIZE])?;
+                Ok(Page::from_bytes(page_id, data))
+            }
+            Source::Paged(pool) => Ok(pool.fetch_page(GlobalPageId { db_id: 0, page_id })?.read().clone()),
+        }
+    }
+
+    fn catalog(&mut self, header: &DatabaseHeader) -> Result<Catalog> {
+        if let Source::Paged(pool) = self
+            && header.file_format_version >= 3 {
+                return Catalog::load(pool, 0);
+        }
+        // Up to v2 the catalog is JSON on a single page, padded with zeros
+        let page = self.read_page(header.root_catalog_page)?;
+        let usable = &page.data[..page.usable_size()];
+        let len = usable.iter().position(|&b| b == 0).unwrap_or(usable.len());
+        Catalog::from_bytes(&usable[..len])
+    }
+}
+
+/// Copies a database written in an older format version into a new file at
+/// `target` in the current format. Tables and rows are carried over logically,
+/// so every layout change between the two versions is covered.
+pub fn upgrade(source: &Path, target: &Path, key: Option<EncryptionKey>) -> Result<UpgradeReport> {
+    let header = DatabaseHeader::read_from_file(source)?;
+    let name = source.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let found = header.file_format_version;
+    if found > CURRENT_FILE_FORMAT_VERSION {
+        return Err(RdbError::FormatTooNew { database: name, found, supported: CURRENT_FILE_FORMAT_VERSION }.into());
+    }
+    if found == CURRENT_FILE_FORMAT_VERSION {
+        return Err(anyhow!("Database '{}' already uses file format v{}", name, found));
+    }
+    if target.exists() {
+        return Err(anyhow!("{:?} already exists", target));
+    }
+    let key = if header.encryption {
+        Some(key.ok_or(anyhow!("Database '{}' is encrypted: set {} or storage.encryption_key_file", name, crypto::KEY_ENV_VAR))?)
+    } else {
+        None
+    };
+
+    let mut source_db = Source::open(source, &header, key)?;
+    let catalog = source_db.catalog(&header)?;
+
+    let pager = Arc::new(Pager::create_database(target, &header.database_name, header.page_size as usize, key)?);
+    let mut new_header = pager.read_header()?;
+    new_header.created_at = header.created_at;
+    pager.write_header(&new_header)?;
+
+    let buffer_pool = Arc::new(BufferPool::new(256));
+    buffer_pool.register_pager(0, pager);
+    let executor = Executor::new(buffer_pool);
+
+    let mut report = UpgradeReport { from_version: found, tables: 0, rows: 0 };
+    let mut tables: Vec<_> = catalog.tables.values().collect();
+    tables.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for table in tables {
+        executor.execute(Query::CreateTable(CreateTableQuery {
+            database: "main".to_string(),
+            table: table.name.clone(),
+            columns: table.columns.clone(),
+            compression: None,
+        }))?;
+        report.tables += 1;
+
+        let insert = |values: Vec<Value>| executor.execute(Query::Insert(InsertQuery {
+            database: "main".to_string(),
+            table: table.name.clone(),
+            values,
+        }));
+
+        let mut batch = Vec::new();
+        let mut visited = HashSet::new();
+        let mut page_id = table.root_page_id;
+        while page_id != 0 {
+            if !visited.insert(page_id) {
+                return Err(anyhow!("Page chain of table {} has a cycle at page {}", table.name, page_id));
+            }
+            let mut page = source_db.read_page(page_id)?;
+            let slotted = SlottedPage::new(&mut page);
+            for i in 0..slotted.num_slots() {
+                if let Some(tuple_bytes) = slotted.get_tuple(i)?
+                    && !tuple_bytes.is_empty() {
+                        batch.push(serde_json::from_slice(&tuple_bytes)?);
+                }
+            }
+            page_id = slotted.next_page_id();
+
+            if batch.len() >= INSERT_BATCH {
+                report.rows += batch.len();
+                insert(std::mem::take(&mut batch))?;
+            }
+        }
+        if !batch.is_empty() {
+            report.rows += batch.len();
+            insert(batch)?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Upgrades a database file in place. The original is kept next to it as
+/// `<name>.v<N>.bak`, whose path is returned with the report.
+pub fn upgrade_in_place(path: &Path, key: Option<EncryptionKey>) -> Result<(UpgradeReport, PathBuf)> {
+    let temp = path.with_extension("upgrade.db");
+    let remove_temp = || {
+        let _ = std::fs::remove_file(&temp);
+        let _ = std::fs::remove_file(Wal::path_for(&temp));
+        let _ = std::fs::remove_file(ChangeMap::path_for(&temp));
+    };
+    // Left over from an interrupted upgrade
+    remove_temp();
+
+    let report = match upgrade(path, &temp, key) {
+        Ok(report) => report,
+        Err(e) => {
+            remove_temp();
+            return Err(e);
+        }
+    };
+
+    let backup = path.with_extension(format!("v{}.bak", report.from_version));
+    std::fs::rename(path, &backup)?;
+    // The old log was checkpointed when the source was closed
+    let wal_path = Wal::path_for(path);
+    if wal_path.metadata().is_ok_and(|m| m.len() == 0) {
+        std::fs::remove_file(&wal_path)?;
+    }
+    std::fs::rename(&temp, path)?;
+    // The new file needs a full backup before incremental ones
+    std::fs::rename(ChangeMap::path_for(&temp), ChangeMap::path_for(path))?;
+    remove_temp();
+
+    Ok((report, backup))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::executor::ExecutionResult;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    fn users_catalog() -> Vec<u8> {
+        serde_json::to_vec(&json!({"tables": {"users": {
+            "name": "users", "root_page_id": 2, "index_root_page_id": 3,
+            "columns": [{"name": "id", "type": "int", "primary_key": true}, {"name": "name", "type": "string"}]
+        }}})).unwrap()
+    }
+
+    // Fills a heap page with rows until it is full
+    fn fill_rows(page: &mut Page) -> usize {
+        let mut slotted = SlottedPage::new(page);
+        slotted.init();
+        let mut rows = 0;
+        while slotted.insert_tuple(&serde_json::to_vec(&json!({"id": rows, "name": format!("user {}", rows)})).unwrap()).is_ok() {
+            rows += 1;
+        }
+        rows
+    }
+
+    fn select_all(path: &Path) -> Vec<Value> {
+        let buffer_pool = Arc::new(BufferPool::new(16));
+        buffer_pool.register_pager(0, Arc::new(Pager::open(path).unwrap()));
+        let query = serde_json::from_value(json!({"op": "select", "database": "main", "from": "users", "columns": ["*"]})).unwrap();
+        match Executor::new(buffer_pool).execute(query).unwrap() {
+            ExecutionResult::Json(Value::Array(rows)) => rows,
+            _ => panic!("expected rows"),
+        }
+    }
+
+    #[test]
+    fn test_upgrade_v1_file_in_place() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("legacy.db");
+
+        // v1: no checksums, so rows run up to the very end of the page
+        let mut header = DatabaseHeader::new("legacy".to_string(), V1_PAGE_SIZE);
+        header.file_format_version = 1;
+        let mut bytes = header.to_bytes().unwrap();
+        let mut catalog = users_catalog();
+        catalog.resize(V1_PAGE_SIZE, 0);
+        bytes.extend_from_slice(&catalog);
+        let mut heap = Page::new(2, V1_PAGE_SIZE + PAGE_CHECKSUM_SIZE);
+        let rows = fill_rows(&mut heap);
+        bytes.extend_from_slice(&heap.data[..V1_PAGE_SIZE]);
+        bytes.extend_from_slice(&[0u8; V1_PAGE_SIZE]);
+        std::fs::write(&path, bytes).unwrap();
+
+        let err = Pager::open(&path).err().unwrap();
+        assert!(matches!(err.downcast_ref::<RdbError>(), Some(RdbError::UpgradeRequired { found: 1, .. })));
+
+        let (report, backup) = upgrade_in_place(&path, None).unwrap();
+        assert_eq!((report.from_version, report.tables, report.rows), (1, 1, rows));
+        assert!(backup.ends_with("legacy.v1.bak"));
+
+        let upgraded = DatabaseHeader::read_from_file(&path).unwrap();
+        assert_eq!(upgraded.file_format_version, CURRENT_FILE_FORMAT_VERSION);
+        assert_eq!(upgraded.created_at, header.created_at);
+        let selected = select_all(&path);
+        assert_eq!(selected.len(), rows);
+        assert!(selected.contains(&json!({"id": rows - 1, "name": format!("user {}", rows - 1)})));
+    }
+
+    #[test]
+    fn test_upgrade_v2_file_into_copy() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("old.db");
+        let copy = temp_dir.path().join("new.db");
+        let rows = {
+            let pager = Pager::create_database(&path, "old", 4096, None).unwrap();
+            let mut header = pager.read_header().unwrap();
+            header.file_format_version = 2;
+            pager.write_header(&header).unwrap();
+
+            let mut catalog = Page::new(1, pager.page_size());
+            let json = users_catalog();
+            catalog.data[..json.len()].copy_from_slice(&json);
+            pager.write_page(&catalog).unwrap();
+            let mut heap = Page::new(pager.allocate_page().unwrap(), pager.page_size());
+            let rows = fill_rows(&mut heap);
+            pager.write_page(&heap).unwrap();
+            pager.allocate_page().unwrap();
+            rows
+        };
+
+        let report = upgrade(&path, &copy, None).unwrap();
+        assert_eq!((report.from_version, report.rows), (2, rows));
+        assert_eq!(DatabaseHeader::read_from_file(&path).unwrap().file_format_version, 2);
+        assert_eq!(select_all(&copy).len(), rows);
+        assert!(upgrade(&copy, &temp_dir.path().join("again.db"), None).is_err());
+    }
+}
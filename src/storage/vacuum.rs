@@ -0,0 +1,218 @@
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use crate::query::{Query, VacuumQuery};
+use crate::query::executor::{Executor, ExecutionResult};
+use crate::storage::buffer::{BufferPool, GlobalPageId};
+use crate::storage::catalog::TableInfo;
+use crate::storage::codec::TupleCodec;
+use crate::storage::fsm::FreeSpaceMap;
+use crate::storage::index::BTreeIndex;
+use crate::storage::slotted::{self, SlottedPage};
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct VacuumStats {
+    pub rows_moved: usize,
+    pub pages_freed: usize,
+}
+
+/// Compacts a table's heap pages and merges sparse ones: rows move from the
+/// end of the chain into free space near its start, and the emptied pages go
+/// back to the free list. The primary key index and free space map are then
+/// rebuilt. Must run inside a transaction; the caller saves the catalog.
+pub fn vacuum_table(buffer_pool: &Arc<BufferPool>, db_id: u32, table: &mut TableInfo, codec: &TupleCodec) -> Result<VacuumStats> {
+    let chain = heap_chain(buffer_pool, db_id, table.root_page_id)?;
+    let mut stats = VacuumStats::default();
+
+    // Fill pages from the front with rows taken from the pages after them
+    let mut target = 0;
+    compact_page(buffer_pool, db_id, chain[0])?;
+    for source in 1..chain.len() {
+        loop {
+            if target == source {
+                compact_page(buffer_pool, db_id, chain[source])?;
+                break;
+            }
+            let target_page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id: chain[target] })?;
+            let source_page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id: chain[source] })?;
+            let mut target_guard = target_page.write();
+            let mut source_guard = source_page.write();
+            let mut source_slotted = SlottedPage::new(&mut source_guard);
+            stats.rows_moved += source_slotted.move_tuples_to(&mut SlottedPage::new(&mut target_guard));
+            if source_slotted.is_empty() {
+                break;
+            }
+            // The target is full; what is left goes to the next page
+            drop((target_guard, source_guard));
+            target += 1;
+            if target < source {
+                compact_page(buffer_pool, db_id, chain[target])?;
+            }
+        }
+    }
+
+    // Everything past the last filled page is empty now
+    if target + 1 < chain.len() {
+        let last = buffer_pool.fetch_page(GlobalPageId { db_id, page_id: chain[target] })?;
+        SlottedPage::new(&mut last.write()).set_next_page_id(0);
+        for &page_id in &chain[target + 1..] {
+            buffer_pool.free_page(db_id, page_id)?;
+            stats.pages_freed += 1;
+        }
+    }
+
+    rebuild_index(buffer_pool, db_id, table, codec, &chain[..=target])?;
+
+    if table.fsm_root_page_id != 0 {
+        FreeSpaceMap::load(buffer_pool, db_id, table.fsm_root_page_id)?.destroy(buffer_pool)?;
+    }
+    table.fsm_root_page_id = FreeSpaceMap::build(buffer_pool, db_id, table.root_page_id)?.root_page_id();
+    Ok(stats)
+}
+
+// Page IDs of a heap chain, root first
+fn heap_chain(buffer_pool: &BufferPool, db_id: u32, root_page_id: u32) -> Result<Vec<u32>> {
+    let mut chain = Vec::new();
+    let mut page_id = root_page_id;
+    while page_id != 0 {
+        if chain.contains(&page_id) {
+            return Err(anyhow!("Heap page chain has a cycle at page {}", page_id));
+        }
+        chain.push(page_id);
+        let page = buffer_pool.fetch_chain_page(GlobalPageId { db_id, page_id }, slotted::next_page_id)?;
+        page_id = slotted::next_page_id(&page.read());
+    }
+    Ok(chain)
+}
+
+fn compact_page(buffer_pool: &BufferPool, db_id: u32, page_id: u32) -> Result<()> {
+    let page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id })?;
+    SlottedPage::new(&mut page.write()).compact();
+    Ok(())
+}
+
+// Points the primary key index at where the rows ended up
+fn rebuild_index(buffer_pool: &Arc<BufferPool>, db_id: u32, table: &TableInfo, codec: &TupleCodec, chain: &[u32]) -> Result<()> {
+    let index = BTreeIndex::new(buffer_pool.clone(), db_id, table.index_root_page_id)?;
+    index.clear()?;
+    let Some(pk) = table.columns.iter().find(|c| c.primary_key) else {
+        return Ok(());
+    };
+    for &page_id in chain {
+        let page = buffer_pool.fetch_page(GlobalPageId { db_id, page_id })?;
+        let guard = page.read();
+        let slotted = SlottedPage::read_only(&guard, buffer_pool, db_id).with_codec(codec);
+        let mut keys = Vec::new();
+        for slot_id in 0..slotted.num_slots() {
+            if let Some(tuple_bytes) = slotted.get_tuple(slot_id)?
+                && !tuple_bytes.is_empty() {
+                    let val: Value = serde_json::from_slice(&tuple_bytes)?;
+                    if let Some(key) = val.get(&pk.name).and_then(|v| v.as_u64()) {
+                        keys.push((key as u32, slot_id));
+                    }
+            }
+        }
+        drop(guard);
+        for (key, slot_id) in keys {
+            index.insert(key, (page_id, slot_id))?;
+        }
+    }
+    Ok(())
+}
+
+/// Vacuums tables in the background once enough of their heap space is free.
+pub struct AutoVacuum {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl AutoVacuum {
+    /// Every `interval`, vacuums each table of `databases` with at least
+    /// `threshold_percent` of its heap space free. Results that did something
+    /// go to `on_vacuum`, failures to `on_error`.
+    pub fn start<F, E>(executor: Arc<Executor>, databases: Vec<String>, interval: Duration, threshold_percent: u8, on_vacuum: F, on_error: E) -> Self
+    where
+        F: Fn(&str, &Value) + Send + 'static,
+        E: Fn(&str, anyhow::Error) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = std::thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                for database in &databases {
+                    let query = Query::Vacuum(VacuumQuery {
+                        database: database.clone(),
+                        table: None,
+                        min_free_percent: Some(threshold_percent),
+                    });
+                    match executor.execute(query) {
+                        Ok(ExecutionResult::Json(stats)) => {
+                            if stats.get("tables").and_then(|v| v.as_u64()).unwrap_or(0) > 0 {
+                                on_vacuum(database, &stats);
+                            }
+                        }
+                        Ok(ExecutionResult::Message(_)) => {}
+                        Err(e) => on_error(database, e),
+                    }
+                }
+            }
+        });
+        Self { stop: Some(stop), handle: Some(handle) }
+    }
+}
+
+impl Drop for AutoVacuum {
+    fn drop(&mut self) {
+        // A vacuum in progress finishes first
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::test_util::{create, run};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_vacuum_merges_pages_and_shrinks_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("main.db");
+        let (_, pool, executor) = create(temp_dir.path());
+
+        run(&executor, json!({"op": "create_table", "database": "main", "table": "t", "columns": [
+            {"name": "id", "type": "int", "primary_key": true},
+        ], "compression": {"codec": "none"}}));
+        let rows: Vec<Value> = (0..400).map(|i| json!({"id": i, "keep": i % 10 == 0, "body": "x".repeat(100)})).collect();
+        run(&executor, json!({"op": "insert", "database": "main", "table": "t", "values": rows}));
+        // Keep every tenth row, so no page empties by itself
+        run(&executor, json!({"op": "delete", "database": "main", "table": "t",
+            "where": {"column": "keep", "cmp": "=", "value": false}}));
+        pool.flush_all().unwrap();
+        let size_before = std::fs::metadata(&path).unwrap().len();
+
+        let ExecutionResult::Json(stats) = run(&executor, json!({"op": "vacuum", "database": "main"})) else { panic!() };
+        assert_eq!(stats["tables"], 1);
+        assert!(stats["pages_freed"].as_u64().unwrap() > 5);
+        assert!(stats["pages_truncated"].as_u64().unwrap() > 0);
+        assert!(std::fs::metadata(&path).unwrap().len() < size_before);
+
+        let select = |filter: Value| match run(&executor, json!({"op": "select", "database": "main",
+            "from": "t", "columns": ["*"], "where": filter})) {
+            ExecutionResult::Json(Value::Array(found)) => found,
+            _ => panic!(),
+        };
+        assert_eq!(select(json!({"column": "keep", "cmp": "=", "value": true})).len(), 40);
+        assert_eq!(select(json!({"column": "id", "cmp": "=", "value": 390}))[0]["id"], 390);
+
+        // Nothing is left to reclaim
+        let ExecutionResult::Json(stats) = run(&executor, json!({"op": "vacuum", "database": "main", "min_free_percent": 30})) else { panic!() };
+        assert_eq!(stats["tables"], 0);
+    }
+}
@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::storage::store::{FileStore, PageStore};
+use parking_lot::Mutex;
+use byteorder::{LittleEndian, ByteOrder};
+use anyhow::{Result, anyhow};
+
+// WAL file layout:
+//   Header: magic (8) + base_lsn (8)
+//   Records: crc (4) + payload_len (4) + lsn (8) + txn_id (8) + kind (1) + page_id (4) + payload
+// Commit records carry their time (ms since the epoch) as payload; truncate
+// records hold the new page count in page_id. A committed cut is made before
+// any page of the log is written to the file.
+// The CRC covers everything in the record after the CRC field itself, so a torn
+// tail record is detected and treated as the end of the log.
+const WAL_MAGIC: &[u8; 8] = b"RDBWAL01";
+const WAL_HEADER_SIZE: u64 = 16;
+const RECORD_HEADER_SIZE: usize = 29;
+
+// Checkpoint automatically once the log grows past this many bytes.
+pub const WAL_AUTO_CHECKPOINT_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordKind {
+    PageWrite = 1,
+    Commit = 2,
+    Abort = 3,
+    Truncate = 4,
+}
+
+impl RecordKind {
+    fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            1 => Some(RecordKind::PageWrite),
+            2 => Some(RecordKind::Commit),
+            3 => Some(RecordKind::Abort),
+            4 => Some(RecordKind::Truncate),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of scanning the log when a database is opened.
+#[derive(Debug, Default, Clone)]
+pub struct RecoveryReport {
+    /// Transactions whose page writes were replayed (redo).
+    pub committed_txns: usize,
+    /// Transactions without a commit record whose page writes were discarded (undo).
+    pub rolled_back_txns: usize,
+    /// Distinct pages restored from the log.
+    pub pages_restored: usize,
+}
+
+impl RecoveryReport {
+    pub fn is_clean(&self) -> bool {
+        self.committed_txns == 0 && self.rolled_back_txns == 0
+    }
+}
+
+struct WalInner {
+    store: Arc<dyn PageStore>,
+    base_lsn: u64,
+    end: u64,
+    next_lsn: u64,
+    // Page ID -> file offset of the latest frame payload
+    committed: HashMap<u32, u64>,
+    pending: HashMap<u32, u64>,
+    // Page count the file is cut to at the next checkpoint
+    committed_truncate: Option<u32>,
+    pending_truncate: Option<u32>,
+}
+
+pub struct Wal {
+    page_size: usize,
+    inner: Mutex<WalInner>,
+}
+
+impl Wal {
+    /// Returns the log path that belongs to a database file (`main.db` -> `main.wal`).
+    pub fn path_for(db_path: &Path) -> PathBuf {
+        db_path.with_extension("wal")
+    }
+
+    /// Opens (or creates) the log and scans it. Frames of committed transactions are
+    /// indexed so they can be replayed; frames of transactions that never committed
+    /// are dropped.
+    pub fn open(path: &Path, page_size: usize) -> Result<(Self, RecoveryReport)> {
+        Self::open_store(Arc::new(FileStore::open(path)?), page_size)
+            .map_err(|e| anyhow!("{} in {:?}", e, path))
+    }
+
+    /// Opens the log kept in `store`, like `open`.
+    pub fn open_store(store: Arc<dyn PageStore>, page_size: usize) -> Result<(Self, RecoveryReport)> {
+        let file_len = store.size()?;
+        let mut report = RecoveryReport::default();
+
+        if file_len < WAL_HEADER_SIZE {
+            let wal = Self {
+                page_size,
+                inner: Mutex::new(WalInner {
+                    store,
+                    base_lsn: 1,
+                    end: WAL_HEADER_SIZE,
+                    next_lsn: 1,
+                    committed: HashMap::new(),
+                    pending: HashMap::new(),
+                    committed_truncate: None,
+                    pending_truncate: None,
+                }),
+            };
+            wal.reset_to(1)?;
+            return Ok((wal, report));
+        }
+
+        let mut header = [0u8; WAL_HEADER_SIZE as usize];
+        store.read_at(&mut header, 0)?;
+        if &header[0..8] != WAL_MAGIC {
+            return Err(anyhow!("Invalid WAL magic"));
+        }
+        let base_lsn = LittleEndian::read_u64(&header[8..16]);
+
+        let mut bytes = vec![0u8; (file_len - WAL_HEADER_SIZE) as usize];
+        store.read_at(&mut bytes, WAL_HEADER_SIZE)?;
+
+        let mut offset = 0usize;
+        let mut next_lsn = base_lsn;
+        let mut txn_frames: HashMap<u64, Vec<(u32, u64)>> = HashMap::new();
+        let mut committed_frames: Vec<(u32, u64)> = Vec::new();
+        let mut txn_truncates: HashMap<u64, u32> = HashMap::new();
+        let mut committed_truncate = None;
+
+        while offset + RECORD_HEADER_SIZE <= bytes.len() {
+            let record = &bytes[offset..];
+            let crc = LittleEndian::read_u32(&record[0..4]);
+            let payload_len = LittleEndian::read_u32(&record[4..8]) as usize;
+            let record_len = RECORD_HEADER_SIZE + payload_len;
+            if offset + record_len > bytes.len() {
+                break; // Torn tail
+            }
+            if crc32fast::hash(&record[4..record_len]) != crc {
+                break; // Torn or corrupt tail
+            }
+
+            let lsn = LittleEndian::read_u64(&record[8..16]);
+            let txn_id = LittleEndian::read_u64(&record[16..24]);
+            let kind = RecordKind::from_u8(record[24]);
+            let page_id = LittleEndian::read_u32(&record[25..29]);
+            let payload_offset = WAL_HEADER_SIZE + (offset + RECORD_HEADER_SIZE) as u64;
+            if lsn != next_lsn {
+                break; // Left over from before the last reset
+            }
+
+            match kind {
+                Some(RecordKind::PageWrite) => {
+                    if payload_len != page_size {
+                        break;
+                    }
+                    txn_frames.entry(txn_id).or_default().push((page_id, payload_offset));
+                }
+                Some(RecordKind::Commit) => {
+                    if let Some(frames) = txn_frames.remove(&txn_id) {
+                        committed_frames.extend(frames);
+                        report.committed_txns += 1;
+                    }
+                    if let Some(pages) = txn_truncates.remove(&txn_id) {
+                        committed_truncate = Some(pages);
+                    }
+                }
+                Some(RecordKind::Abort) => {
+                    txn_frames.remove(&txn_id);
+                    txn_truncates.remove(&txn_id);
+                }
+                Some(RecordKind::Truncate) => {
+                    txn_truncates.insert(txn_id, page_id);
+                }
+                None => break,
+            }
+
+            next_lsn = lsn + 1;
+            offset += record_len;
+        }
+
+        report.rolled_back_txns = txn_frames.len();
+
+        // Drop anything past the last valid record so it can never be misread later
+        let end = WAL_HEADER_SIZE + offset as u64;
+        if end < file_len {
+            store.set_size(end)?;
+        }
+
+        let mut committed = HashMap::new();
+        for (page_id, payload_offset) in committed_frames {
+            committed.insert(page_id, payload_offset);
+        }
+        report.pages_restored = committed.len();
+
+        let wal = Self {
+            page_size,
+            inner: Mutex::new(WalInner {
+                store,
+                base_lsn,
+                end,
+                next_lsn,
+                committed,
+                pending: HashMap::new(),
+                committed_truncate,
+                pending_truncate: None,
+            }),
+        };
+
+        Ok((wal, report))
+    }
+
+    /// Current size of the log in bytes.
+    pub fn size(&self) -> u64 {
+        self.inner.lock().end
+    }
+
+    /// LSN of the first record in the log. Every earlier record reached the
+    /// database file at a checkpoint.
+    pub fn base_lsn(&self) -> u64 {
+        self.inner.lock().base_lsn
+    }
+
+    /// True when the log holds no records at all.
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().end == WAL_HEADER_SIZE
+    }
+
+    /// Appends a full page image for `txn_id`. The frame is visible to readers
+    /// immediately but only survives a crash once the transaction commits.
+    pub fn append_page(&self, txn_id: u64, page_id: u32, data: &[u8]) -> Result<u64> {
+        if data.len() != self.page_size {
+            return Err(anyhow!("WAL frame size mismatch: {} != {}", data.len(), self.page_size));
+        }
+        let mut inner = self.inner.lock();
+        let (lsn, record_offset) = Self::append_record(&mut inner, RecordKind::PageWrite, txn_id, page_id, data)?;
+        inner.pending.insert(page_id, record_offset + RECORD_HEADER_SIZE as u64);
+        Ok(lsn)
+    }
+
+    /// Writes the commit record and forces the log to disk.
+    pub fn commit(&self, txn_id: u64) -> Result<u64> {
+        let mut inner = self.inner.lock();
+        let mut payload = [0u8; 8];
+        LittleEndian::write_i64(&mut payload, chrono::Utc::now().timestamp_millis());
+        let (lsn, _) = Self::append_record(&mut inner, RecordKind::Commit, txn_id, 0, &payload)?;
+        inner.store.sync()?;
+
+        let pending: Vec<(u32, u64)> = inner.pending.drain().collect();
+        for (page_id, payload_offset) in pending {
+            inner.committed.insert(page_id, payload_offset);
+        }
+        if let Some(pages) = inner.pending_truncate.take() {
+            inner.committed_truncate = Some(pages);
+        }
+        Ok(lsn)
+    }
+
+    /// Writes an abort record and forgets the transaction's frames. Returns the
+    /// page IDs whose logged images were discarded.
+    pub fn abort(&self, txn_id: u64) -> Vec<u32> {
+        let mut inner = self.inner.lock();
+        let discarded: Vec<u32> = inner.pending.drain().map(|(page_id, _)| page_id).collect();
+        let truncated = inner.pending_truncate.take().is_some();
+        if !discarded.is_empty() || truncated {
+            // Recovery ignores frames that were never committed anyway, so a
+            // record that can't be written is no reason to keep them
+            let _ = Self::append_record(&mut inner, RecordKind::Abort, txn_id, 0, &[]);
+        }
+        discarded
+    }
+
+    /// Reads the newest committed image of a page, if the log has one. With
+    /// `own` the active transaction's uncommitted images are read as well,
+    /// which only that transaction may see.
+    pub fn read_page(&self, page_id: u32, buf: &mut [u8], own: bool) -> Result<bool> {
+        let inner = self.inner.lock();
+        let pending = if own { inner.pending.get(&page_id) } else { None };
+        let offset = match pending.or_else(|| inner.committed.get(&page_id)) {
+            Some(offset) => *offset,
+            None => return Ok(false),
+        };
+        inner.store.read_at(buf, offset)?;
+        Ok(true)
+    }
+
+    /// True when the active transaction logged an image of the page it hasn't
+    /// committed yet.
+    pub fn is_pending(&self, page_id: u32) -> bool {
+        self.inner.lock().pending.contains_key(&page_id)
+    }
+
+    /// Hands every committed page image to `apply` (redo). The caller is expected to
+    /// sync the database file and then call `reset`.
+    pub fn checkpoint<F>(&self, mut apply: F) -> Result<usize>
+    where
+        F: FnMut(u32, &[u8]) -> Result<()>,
+    {
+        let inner = self.inner.lock();
+        if !inner.pending.is_empty() {
+            return Err(anyhow!("Cannot checkpoint while a transaction has uncommitted frames"));
+        }
+
+        let mut frames: Vec<(u32, u64)> = inner.committed.iter().map(|(k, v)| (*k, *v)).collect();
+        frames.sort_unstable();
+
+        let mut buf = vec![0u8; self.page_size];
+        for (page_id, offset) in &frames {
+            inner.store.read_at(&mut buf, *offset)?;
+            apply(*page_id, &buf)?;
+        }
+        Ok(frames.len())
+    }
+
+    /// Logs that `txn_id` cuts the database file to `pages` pages. Once the
+    /// transaction commits, `apply_truncate` hands the cut to the checkpoint.
+    pub fn log_truncate(&self, txn_id: u64, pages: u32) -> Result<()> {
+        let mut inner = self.inner.lock();
+        Self::append_record(&mut inner, RecordKind::Truncate, txn_id, pages, &[])?;
+        inner.pending_truncate = Some(pages);
+        Ok(())
+    }
+
+    /// Hands a committed cut the file hasn't had yet to `cut`, until it
+    /// succeeds once. The checkpoint makes the cut before writing any page:
+    /// every page logged after it belongs to the shorter file.
+    pub fn apply_truncate<F>(&self, cut: F) -> Result<()>
+    where
+        F: FnOnce(u32) -> Result<()>,
+    {
+        let mut inner = self.inner.lock();
+        if let Some(pages) = inner.committed_truncate {
+            cut(pages)?;
+            inner.committed_truncate = None;
+        }
+        Ok(())
+    }
+
+    /// Copies the log into `dir` as a segment named after its first LSN.
+    /// Call before `reset`, so the archive holds every record ever logged.
+    pub fn archive_to(&self, dir: &Path) -> Result<()> {
+        let inner = self.inner.lock();
+        if inner.end == WAL_HEADER_SIZE {
+            return Ok(());
+        }
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{:020}.wal", inner.base_lsn));
+        let staging = path.with_extension("wal.tmp");
+
+        let mut bytes = vec![0u8; inner.end as usize];
+        inner.store.read_at(&mut bytes, 0)?;
+        let mut segment = File::create(&staging)?;
+        segment.write_all(&bytes)?;
+        segment.sync_all()?;
+        std::fs::rename(&staging, &path)?;
+        Ok(())
+    }
+
+    /// Empties the log after a checkpoint. LSNs keep increasing across resets.
+    pub fn reset(&self) -> Result<()> {
+        let next_lsn = self.inner.lock().next_lsn;
+        self.reset_to(next_lsn)
+    }
+
+    fn reset_to(&self, base_lsn: u64) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let mut header = [0u8; WAL_HEADER_SIZE as usize];
+        header[0..8].copy_from_slice(WAL_MAGIC);
+        LittleEndian::write_u64(&mut header[8..16], base_lsn);
+
+        // Once the new header is written the old records are dead: their LSNs
+        // don't follow the new base. So the log is empty even if cutting it
+        // off fails.
+        inner.store.write_at(&header, 0)?;
+        inner.base_lsn = base_lsn;
+        inner.end = WAL_HEADER_SIZE;
+        inner.next_lsn = base_lsn;
+        inner.committed.clear();
+        inner.pending.clear();
+        inner.committed_truncate = None;
+        inner.pending_truncate = None;
+
+        inner.store.set_size(WAL_HEADER_SIZE)?;
+        inner.store.sync()?;
+        Ok(())
+    }
+
+    fn append_record(inner: &mut WalInner, kind: RecordKind, txn_id: u64, page_id: u32, payload: &[u8]) -> Result<(u64, u64)> {
+        let lsn = inner.next_lsn;
+        let mut record = vec![0u8; RECORD_HEADER_SIZE + payload.len()];
+        LittleEndian::write_u32(&mut record[4..8], payload.len() as u32);
+        LittleEndian::write_u64(&mut record[8..16], lsn);
+        LittleEndian::write_u64(&mut record[16..24], txn_id);
+        record[24] = kind as u8;
+        LittleEndian::write_u32(&mut record[25..29], page_id);
+        record[RECORD_HEADER_SIZE..].copy_from_slice(payload);
+        let crc = crc32fast::hash(&record[4..]);
+        LittleEndian::write_u32(&mut record[0..4], crc);
+
+        let offset = inner.end;
+        inner.store.write_at(&record, offset)?;
+
+        inner.end += record.len() as u64;
+        inner.next_lsn += 1;
+        Ok((lsn, offset))
+    }
+}
+
+/// A record of an archived log segment.
+#[derive(Debug)]
+pub enum LogRecord {
+    Page { txn_id: u64, page_id: u32, data: Vec<u8> },
+    Commit { txn_id: u64, at_ms: i64 },
+    Abort { txn_id: u64 },
+    Truncate { txn_id: u64, pages: u32 },
+}
+
+/// Reads an archived segment: its records with their LSNs, in log order,
+/// and the LSN that follows the last one. Stops at a torn tail like `open`.
+pub fn read_segment(path: &Path, page_size: usize) -> Result<(Vec<(u64, LogRecord)>, u64)> {
+    let bytes = std::fs::read(path)?;
+    if bytes.len() < WAL_HEADER_SIZE as usize || &bytes[0..8] != WAL_MAGIC {
+        return Err(anyhow!("{:?} is not a log segment", path));
+    }
+    let mut next_lsn = LittleEndian::read_u64(&bytes[8..16]);
+    let mut records = Vec::new();
+    let mut offset = WAL_HEADER_SIZE as usize;
+    while offset + RECORD_HEADER_SIZE <= bytes.len() {
+        let record = &bytes[offset..];
+        let crc = LittleEndian::read_u32(&record[0..4]);
+        let payload_len = LittleEndian::read_u32(&record[4..8]) as usize;
+        let record_len = RECORD_HEADER_SIZE + payload_len;
+        if offset + record_len > bytes.len() || crc32fast::hash(&record[4..record_len]) != crc {
+            break;
+        }
+
+        let lsn = LittleEndian::read_u64(&record[8..16]);
+        let txn_id = LittleEndian::read_u64(&record[16..24]);
+        let page_id = LittleEndian::read_u32(&record[25..29]);
+        let payload = &record[RECORD_HEADER_SIZE..record_len];
+        let parsed = match RecordKind::from_u8(record[24]) {
+            Some(RecordKind::PageWrite) if payload_len == page_size => LogRecord::Page { txn_id, page_id, data: payload.to_vec() },
+            Some(RecordKind::Commit) if payload_len == 8 => LogRecord::Commit { txn_id, at_ms: LittleEndian::read_i64(payload) },
+            Some(RecordKind::Abort) => LogRecord::Abort { txn_id },
+            Some(RecordKind::Truncate) => LogRecord::Truncate { txn_id, pages: page_id },
+            _ => break,
+        };
+        records.push((lsn, parsed));
+        next_lsn = lsn + 1;
+        offset += record_len;
+    }
+    Ok((records, next_lsn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use tempfile::TempDir;
+
+    const TEST_PAGE: usize = 64;
+
+    #[test]
+    fn test_wal_committed_frames_survive_reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.wal");
+
+        {
+            let (wal, report) = Wal::open(&path, TEST_PAGE).unwrap();
+            assert!(report.is_clean());
+            wal.append_page(1, 3, &[7u8; TEST_PAGE]).unwrap();
+            wal.commit(1).unwrap();
+            wal.append_page(2, 4, &[9u8; TEST_PAGE]).unwrap();
+            // Crash before txn 2 commits
+        }
+
+        let (wal, report) = Wal::open(&path, TEST_PAGE).unwrap();
+        assert_eq!(report.committed_txns, 1);
+        assert_eq!(report.rolled_back_txns, 1);
+
+        let mut buf = [0u8; TEST_PAGE];
+        assert!(wal.read_page(3, &mut buf, false).unwrap());
+        assert_eq!(buf, [7u8; TEST_PAGE]);
+        assert!(!wal.read_page(4, &mut buf, true).unwrap());
+    }
+
+    #[test]
+    fn test_wal_torn_tail_is_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.wal");
+
+        {
+            let (wal, _) = Wal::open(&path, TEST_PAGE).unwrap();
+            wal.append_page(1, 2, &[1u8; TEST_PAGE]).unwrap();
+            wal.commit(1).unwrap();
+            wal.append_page(2, 2, &[2u8; TEST_PAGE]).unwrap();
+            wal.commit(2).unwrap();
+        }
+
+        // Chop the last commit record in half
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 10).unwrap();
+
+        let (wal, report) = Wal::open(&path, TEST_PAGE).unwrap();
+        assert_eq!(report.committed_txns, 1);
+        let mut buf = [0u8; TEST_PAGE];
+        assert!(wal.read_page(2, &mut buf, false).unwrap());
+        assert_eq!(buf, [1u8; TEST_PAGE]);
+    }
+
+    #[test]
+    fn test_uncommitted_images_are_only_read_by_their_transaction() {
+        let temp_dir = TempDir::new().unwrap();
+        let (wal, _) = Wal::open(&temp_dir.path().join("test.wal"), TEST_PAGE).unwrap();
+        wal.append_page(1, 2, &[1u8; TEST_PAGE]).unwrap();
+        wal.commit(1).unwrap();
+        wal.append_page(2, 2, &[2u8; TEST_PAGE]).unwrap();
+        wal.append_page(2, 3, &[3u8; TEST_PAGE]).unwrap();
+        assert!(wal.is_pending(2));
+
+        let mut buf = [0u8; TEST_PAGE];
+        assert!(wal.read_page(2, &mut buf, false).unwrap());
+        assert_eq!(buf, [1u8; TEST_PAGE]);
+        assert!(!wal.read_page(3, &mut buf, false).unwrap());
+        assert!(wal.read_page(2, &mut buf, true).unwrap());
+        assert_eq!(buf, [2u8; TEST_PAGE]);
+
+        wal.commit(2).unwrap();
+        assert!(!wal.is_pending(2));
+        assert!(wal.read_page(3, &mut buf, false).unwrap());
+        assert_eq!(buf, [3u8; TEST_PAGE]);
+    }
+}
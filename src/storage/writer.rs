@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::storage::buffer::BufferPool;
+
+pub struct WriterSettings {
+    /// How often dirty pages are trickled out (`None` = never)
+    pub flush_interval: Option<Duration>,
+    /// Most pages written per flush round
+    pub flush_batch: usize,
+    /// How often every database gets a full checkpoint (`None` = never)
+    pub checkpoint_interval: Option<Duration>,
+}
+
+/// Background thread that writes dirty pages out of the buffer pool a few at a
+/// time and checkpoints every database periodically, so little is left to do
+/// at eviction, commit or shutdown. Stops when dropped.
+pub struct BackgroundWriter {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    /// Starts the writer. Errors don't stop it; they are handed to `on_error`
+    /// and the work is retried on the next round.
+    pub fn start<F>(buffer_pool: Arc<BufferPool>, settings: WriterSettings, on_error: F) -> Self
+    where
+        F: Fn(anyhow::Error) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let tick = [settings.flush_interval, settings.checkpoint_interval]
+            .into_iter()
+            .flatten()
+            .min();
+
+        let handle = tick.map(|tick| std::thread::spawn(move || {
+            let mut last_checkpoint = Instant::now();
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(tick) {
+                if settings.flush_interval.is_some()
+                    && let Err(e) = buffer_pool.flush_dirty(settings.flush_batch) {
+                        on_error(e);
+                }
+                if let Some(interval) = settings.checkpoint_interval
+                    && last_checkpoint.elapsed() >= interval {
+                        if let Err(e) = buffer_pool.checkpoint_all() {
+                            on_error(e);
+                        }
+                        last_checkpoint = Instant::now();
+                }
+            }
+        }));
+
+        Self { stop: Some(stop), handle }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::buffer::GlobalPageId;
+    use crate::storage::header::DatabaseHeader;
+    use crate::query::test_util::create;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_background_writer_flushes_and_checkpoints() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("main.db");
+        let (pager, pool, _) = create(temp_dir.path());
+        let page_id = pager.allocate_page().unwrap();
+
+        {
+            let page = pool.fetch_page(GlobalPageId { db_id: 0, page_id }).unwrap();
+            let mut guard = page.write();
+            guard.data[0] = 42;
+            guard.dirty = true;
+        }
+
+        let settings = WriterSettings {
+            flush_interval: Some(Duration::from_millis(10)),
+            flush_batch: 16,
+            checkpoint_interval: Some(Duration::from_millis(50)),
+        };
+        let writer = BackgroundWriter::start(pool.clone(), settings, |e| panic!("{}", e));
+
+        // The page and the checkpoint marker reach the file itself, not just the log
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            // May race with the checkpoint writing page 0, so misreads are retried
+            if let Ok(header) = DatabaseHeader::read_from_file(&db_path)
+                && header.checkpoint_count > 0 {
+                assert!(header.last_checkpoint_at > 0);
+                break;
+            }
+            assert!(Instant::now() < deadline, "no checkpoint was recorded");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(writer);
+
+        let bytes = std::fs::read(&db_path).unwrap();
+        assert_eq!(bytes[page_id as usize * 4096], 42);
+        assert!(!pool.fetch_page(GlobalPageId { db_id: 0, page_id }).unwrap().read().dirty);
+    }
+}